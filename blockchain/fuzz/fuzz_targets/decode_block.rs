@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_blockchain::block::Block;
+use simple_blockchain::encoding::{Decode, Encode};
+
+fuzz_target!(|data: &[u8]| {
+    // Stored blocks and binary exports: whatever decodes must be the one
+    // canonical encoding of its value.
+    if let Ok(block) = Block::from_bytes(data) {
+        assert_eq!(block.to_bytes(), data);
+        block.hash();
+        block.compute_merkle_root();
+    }
+    // Blocks gossiped between nodes and JSON exports.
+    if let Ok(block) = serde_json::from_slice::<Block>(data) {
+        if block.has_known_versions() {
+            block.hash();
+            Block::from_bytes(&block.to_bytes()).unwrap();
+        }
+    }
+});
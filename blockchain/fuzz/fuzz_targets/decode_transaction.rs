@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_blockchain::encoding::{Decode, Encode};
+use simple_blockchain::transaction::Transaction;
+
+fn exercise(tx: &Transaction) {
+    tx.hash();
+    tx.size();
+    for input in &tx.inputs {
+        let owner = input.signer_address().unwrap_or_default();
+        tx.verify_input(input, &owner);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(tx) = Transaction::from_bytes(data) {
+        assert_eq!(tx.to_bytes(), data);
+        exercise(&tx);
+    }
+    // Transactions submitted through the API and gossiped between nodes.
+    if let Ok(tx) = serde_json::from_slice::<Transaction>(data) {
+        exercise(&tx);
+    }
+});
@@ -0,0 +1,45 @@
+/// Number of base units in one coin. All amounts on chain are whole base
+/// units; decimals only exist at the CLI and display layer.
+pub const COIN: u64 = 100_000_000;
+const DECIMALS: usize = 8;
+
+/// Parses a decimal coin amount such as `"1.5"` into base units.
+pub fn parse_amount(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let (whole, fraction) = input.split_once('.').unwrap_or((input, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(format!("Invalid amount {:?}", input));
+    }
+    if !whole.chars().all(|c| c.is_ascii_digit()) || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid amount {:?}", input));
+    }
+    if fraction.len() > DECIMALS {
+        return Err(format!("Amount {:?} has more than {} decimal places", input, DECIMALS));
+    }
+
+    let whole: u64 = if whole.is_empty() { 0 } else {
+        whole.parse().map_err(|_| format!("Amount {:?} is too large", input))?
+    };
+    let fraction: u64 = format!("{:0<width$}", fraction, width = DECIMALS).parse().unwrap();
+    whole.checked_mul(COIN)
+        .and_then(|units| units.checked_add(fraction))
+        .ok_or_else(|| format!("Amount {:?} is too large", input))
+}
+
+/// Formats base units as a decimal coin amount without trailing zeros.
+pub fn format_amount(units: u64) -> String {
+    let whole = units / COIN;
+    let fraction = units % COIN;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:0width$}", fraction, width = DECIMALS);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+/// Sums amounts, failing instead of wrapping on overflow.
+pub fn checked_sum(amounts: impl IntoIterator<Item = u64>) -> Result<u64, String> {
+    amounts.into_iter().try_fold(0u64, |total, amount| {
+        total.checked_add(amount).ok_or_else(|| String::from("Amount overflow"))
+    })
+}
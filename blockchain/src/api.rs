@@ -0,0 +1,224 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::amount;
+use crate::blockchain::Blockchain;
+use crate::miner::{CancelToken, Miner};
+use crate::mempool::MempoolEntry;
+use crate::transaction::Transaction;
+
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 500;
+
+#[derive(Deserialize)]
+struct CreateWalletRequest {
+    passphrase: String,
+}
+
+#[derive(Deserialize)]
+struct SendRequest {
+    from: String,
+    to: String,
+    /// Base units; see `amount::COIN`.
+    amount: u64,
+    #[serde(default)]
+    fee: u64,
+    passphrase: String,
+}
+
+#[derive(Deserialize)]
+struct MineRequest {
+    address: String,
+}
+
+/// HTTP status and JSON body returned for a request.
+type Reply = (u16, Value);
+
+/// JSON over HTTP front end to a single `Blockchain`. Requests are served by a
+/// pool of worker threads that share the chain behind a mutex, so sled state
+/// is only ever touched by one request at a time.
+pub struct ApiServer {
+    blockchain: Arc<Mutex<Blockchain>>,
+}
+
+impl ApiServer {
+    pub fn new(blockchain: Blockchain) -> Self {
+        ApiServer {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+        }
+    }
+
+    pub fn run(self, listen: &str, workers: usize) -> Result<(), String> {
+        let server = Arc::new(Server::http(listen).map_err(|e| e.to_string())?);
+        println!("API listening on http://{}", listen);
+
+        let api = Arc::new(self);
+        let handles: Vec<_> = (0..workers.max(1))
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let api = Arc::clone(&api);
+                thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        api.respond(request);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+        Ok(())
+    }
+
+    fn respond(&self, mut request: Request) {
+        let mut body = String::new();
+        let (status, value) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => self.route(request.method(), request.url(), &body),
+            Err(e) => (400, json!({ "error": e.to_string() })),
+        };
+
+        let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let response = Response::from_string(value.to_string())
+            .with_status_code(status)
+            .with_header(header);
+        let _ = request.respond(response);
+    }
+
+    fn route(&self, method: &Method, url: &str, body: &str) -> Reply {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            (Method::Get, ["wallets"]) => {
+                (200, json!({ "addresses": self.chain().wallet_addresses() }))
+            }
+            (Method::Post, ["wallets"]) => {
+                let request: CreateWalletRequest = match parse_body(body) {
+                    Ok(request) => request,
+                    Err(reply) => return reply,
+                };
+                match self.chain().create_wallet(&request.passphrase) {
+                    Ok(wallet) => (201, json!({ "address": wallet.address })),
+                    Err(e) => error(400, e),
+                }
+            }
+            (Method::Get, ["wallets", address, "balance"]) => {
+                match self.chain().get_wallet_balance(address) {
+                    Ok(balance) => (200, json!({
+                        "address": address,
+                        "balance": balance,
+                        "balance_coins": amount::format_amount(balance),
+                    })),
+                    Err(e) => error(404, e),
+                }
+            }
+            (Method::Get, ["transactions", "pending"]) => {
+                let pending: Vec<MempoolEntry> = self.chain()
+                    .mempool_entries()
+                    .into_iter()
+                    .map(|(_, entry)| entry)
+                    .collect();
+                (200, json!({ "transactions": pending }))
+            }
+            (Method::Post, ["transactions"]) => {
+                let request: SendRequest = match parse_body(body) {
+                    Ok(request) => request,
+                    Err(reply) => return reply,
+                };
+                let result = self.chain()
+                    .add_transaction(request.from, request.to, request.amount, request.fee, &request.passphrase);
+                match result {
+                    Ok(block_index) => (201, json!({ "block_index": block_index })),
+                    Err(e) => error(400, e),
+                }
+            }
+            (Method::Post, ["transactions", "signed"]) => {
+                let transaction: Transaction = match parse_body(body) {
+                    Ok(transaction) => transaction,
+                    Err(reply) => return reply,
+                };
+                match self.chain().submit_transaction(transaction) {
+                    Ok(block_index) => (201, json!({ "block_index": block_index })),
+                    Err(e) => error(400, e),
+                }
+            }
+            (Method::Post, ["mine"]) => {
+                let request: MineRequest = match parse_body(body) {
+                    Ok(request) => request,
+                    Err(reply) => return reply,
+                };
+                self.mine(&request.address)
+            }
+            (Method::Get, ["blocks"]) => {
+                let offset = query_param(query, "offset").unwrap_or(0);
+                let limit = query_param(query, "limit").unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+                let chain = self.chain();
+                let total = chain.get_last_block().map_or(0, |b| b.index + 1);
+                let blocks = chain.blocks_from(offset, limit as usize);
+                (200, json!({ "total": total, "offset": offset, "limit": limit, "blocks": blocks }))
+            }
+            (Method::Get, ["blocks", "hash", hash]) => {
+                match self.chain().get_block_by_hash(hash) {
+                    Some(block) => (200, json!(block)),
+                    None => error(404, format!("Block {} not found", hash)),
+                }
+            }
+            (Method::Get, ["blocks", index]) => {
+                let block = index.parse().ok().and_then(|i| self.chain().get_block(i));
+                match block {
+                    Some(block) => (200, json!(block)),
+                    None => error(404, format!("Block {} not found", index)),
+                }
+            }
+            _ => error(404, format!("No route for {} {}", method, path)),
+        }
+    }
+
+    /// Locks the chain, ignoring poisoning for the reason given on
+    /// `Node::chain`.
+    fn chain(&self) -> MutexGuard<'_, Blockchain> {
+        self.blockchain.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Mines without holding the lock during the nonce search, so other
+    /// requests are served meanwhile. If another block lands first the
+    /// candidate is rebuilt on the new tip.
+    fn mine(&self, miner_address: &str) -> Reply {
+        loop {
+            let block = match self.chain().create_new_block(miner_address) {
+                Ok(block) => block,
+                Err(e) => return error(400, e),
+            };
+            let block = match Miner::default().mine(block, &CancelToken::default(), |_| {}) {
+                Some((block, _)) => block,
+                None => return error(500, String::from("Nonce space exhausted")),
+            };
+
+            let mut chain = self.chain();
+            if chain.get_last_block().is_some_and(|tip| tip.hash() != block.previous_hash) {
+                continue;
+            }
+            return match chain.process_block(block.clone()) {
+                Ok(_) => (201, json!(block)),
+                Err(e) => error(500, e),
+            };
+        }
+    }
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &str) -> Result<T, Reply> {
+    serde_json::from_str(body).map_err(|e| error(400, format!("Invalid request body: {}", e)))
+}
+
+fn query_param(query: &str, name: &str) -> Option<u64> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+fn error(status: u16, message: String) -> Reply {
+    (status, json!({ "error": message }))
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use crate::block::Block;
+use crate::encoding::{Decode, Encode};
+
+/// Leads every binary chain file, followed by a format version byte.
+const BINARY_MAGIC: &[u8; 4] = b"SBCH";
+const BINARY_VERSION: u8 = 1;
+
+/// Layout of an exported chain file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// One JSON-encoded block per line.
+    Json,
+    /// A header, then each block's binary encoding prefixed by its length.
+    Binary,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "binary" => Ok(ExportFormat::Binary),
+            _ => Err(format!("Unknown format {:?}, expected json or binary", s)),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::Json => write!(f, "json"),
+            ExportFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Streams `blocks` to a new file at `path`, returning how many were written.
+pub fn write_blocks(path: &Path, format: ExportFormat, blocks: impl IntoIterator<Item = Block>) -> Result<u64, String> {
+    let file = File::create(path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    let mut out = BufWriter::new(file);
+    let io_error = |e: std::io::Error| format!("Cannot write {}: {}", path.display(), e);
+
+    if format == ExportFormat::Binary {
+        out.write_all(BINARY_MAGIC).map_err(io_error)?;
+        out.write_all(&[BINARY_VERSION]).map_err(io_error)?;
+    }
+    let mut written = 0;
+    for block in blocks {
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_writer(&mut out, &block).map_err(|e| e.to_string())?;
+                out.write_all(b"\n").map_err(io_error)?;
+            }
+            ExportFormat::Binary => {
+                let bytes = block.to_bytes();
+                out.write_all(&(bytes.len() as u32).to_be_bytes()).map_err(io_error)?;
+                out.write_all(&bytes).map_err(io_error)?;
+            }
+        }
+        written += 1;
+    }
+    out.flush().map_err(io_error)?;
+    Ok(written)
+}
+
+/// Blocks read back from a chain file one at a time, so an import never
+/// holds the whole chain in memory.
+pub struct BlockReader {
+    input: BufReader<File>,
+    format: ExportFormat,
+    /// Number of the next record, for error messages.
+    record: u64,
+}
+
+impl BlockReader {
+    /// Opens a chain file, telling the format from its first bytes.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let mut input = BufReader::new(file);
+        let head = input.fill_buf().map_err(|e| e.to_string())?;
+        let format = if head.starts_with(BINARY_MAGIC) {
+            let mut header = [0u8; 5];
+            input.read_exact(&mut header).map_err(|e| e.to_string())?;
+            if header[4] != BINARY_VERSION {
+                return Err(format!("Unsupported chain file version {}", header[4]));
+            }
+            ExportFormat::Binary
+        } else {
+            ExportFormat::Json
+        };
+        Ok(BlockReader { input, format, record: 0 })
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    fn read_json(&mut self) -> Result<Option<Block>, String> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                return serde_json::from_str(&line).map(Some).map_err(|e| e.to_string());
+            }
+        }
+    }
+
+    fn read_binary(&mut self) -> Result<Option<Block>, String> {
+        if self.input.fill_buf().map_err(|e| e.to_string())?.is_empty() {
+            return Ok(None);
+        }
+        let mut len = [0u8; 4];
+        self.input.read_exact(&mut len).map_err(|e| e.to_string())?;
+        let mut bytes = Vec::new();
+        let len = u32::from_be_bytes(len) as u64;
+        (&mut self.input).take(len).read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+        if bytes.len() as u64 != len {
+            return Err(String::from("file ends in the middle of a block"));
+        }
+        Block::from_bytes(&bytes).map(Some)
+    }
+}
+
+impl Iterator for BlockReader {
+    type Item = Result<Block, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record += 1;
+        let block = match self.format {
+            ExportFormat::Json => self.read_json(),
+            ExportFormat::Binary => self.read_binary(),
+        };
+        block.map_err(|e| format!("Record {}: {}", self.record, e)).transpose()
+    }
+}
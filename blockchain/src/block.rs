@@ -1,32 +1,118 @@
 use serde::{Serialize, Deserialize};
 use chrono::Utc;
+use std::collections::HashSet;
 use sha2::{Sha256, Digest};
-use crate::transaction::Transaction;
+use crate::encoding::Writer;
+use crate::merkle;
+use crate::transaction::{Transaction, TX_VERSION};
+
+/// Most transactions, coinbase included, a block may hold.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1_000;
+/// Most serialized transaction bytes a block may hold.
+pub const MAX_BLOCK_BYTES: usize = 1_000_000;
+/// Version of newly created blocks. Version 0 blocks predate the binary
+/// encoding and hash a JSON rendering of their header instead.
+pub const BLOCK_VERSION: u8 = 2;
+/// First version whose header names the validator that sealed the block.
+pub const SEAL_VERSION: u8 = 2;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
+    #[serde(default)]
+    pub version: u8,
     pub index: u64,
     pub timestamp: i64,
-    pub transactions: Vec<Transaction>,
-    pub proof: u64,
     pub previous_hash: String,
+    pub merkle_root: String,
+    pub difficulty: u32,
+    pub nonce: u64,
+    /// Hex public key of the proof-of-authority validator that signed the
+    /// block; empty on proof-of-work chains.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub validator: String,
+    /// The validator's signature over the header digest. Not part of the
+    /// header itself, so the block hash is known before signing.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature: String,
+    pub transactions: Vec<Transaction>,
 }
 
 impl Block {
-    pub fn new(index: u64, transactions: Vec<Transaction>, proof: u64, previous_hash: String) -> Self {
-        Block {
+    pub fn new(index: u64, transactions: Vec<Transaction>, previous_hash: String, difficulty: u32) -> Self {
+        let mut block = Block {
+            version: BLOCK_VERSION,
             index,
             timestamp: Utc::now().timestamp(),
-            transactions,
-            proof,
             previous_hash,
+            merkle_root: String::new(),
+            difficulty,
+            nonce: 0,
+            validator: String::new(),
+            signature: String::new(),
+            transactions,
+        };
+        block.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    pub fn compute_merkle_root(&self) -> String {
+        let txids: Vec<String> = self.transactions.iter().map(|tx| tx.id.clone()).collect();
+        merkle::merkle_root(&txids)
+    }
+
+    /// Whether this code knows how to hash the block and its transactions.
+    pub fn has_known_versions(&self) -> bool {
+        self.version <= BLOCK_VERSION && self.transactions.iter().all(|tx| tx.version <= TX_VERSION)
+    }
+
+    /// Whether two transactions share an id. Since odd merkle levels repeat
+    /// their last node, repeating the last transactions of a valid block
+    /// leaves its merkle root, and so its hash, unchanged. Such a block must
+    /// be refused before it is looked up or stored under that hash.
+    pub fn has_duplicate_transactions(&self) -> bool {
+        let mut seen = HashSet::new();
+        !self.transactions.iter().all(|tx| seen.insert(tx.id.as_str()))
+    }
+
+    pub fn exceeds_limits(&self) -> bool {
+        self.transactions.len() > MAX_BLOCK_TRANSACTIONS
+            || self.transactions.iter().map(|tx| tx.size()).sum::<usize>() > MAX_BLOCK_BYTES
+    }
+
+    /// Bytes covered by the proof of work or the validator's signature.
+    /// Transactions are committed to through `merkle_root`.
+    pub fn header_bytes(&self) -> Vec<u8> {
+        if self.version > 0 {
+            let mut writer = Writer::default();
+            writer
+                .u8(self.version)
+                .u64(self.index)
+                .str(&self.previous_hash)
+                .str(&self.merkle_root)
+                .i64(self.timestamp)
+                .u32(self.difficulty)
+                .u64(self.nonce);
+            if self.version >= SEAL_VERSION {
+                writer.str(&self.validator);
+            }
+            return writer.into_bytes();
         }
+        serde_json::to_vec(&(
+            self.index,
+            &self.previous_hash,
+            &self.merkle_root,
+            self.timestamp,
+            self.difficulty,
+            self.nonce,
+        ))
+        .unwrap()
+    }
+
+    pub fn header_digest(&self) -> Vec<u8> {
+        Sha256::digest(self.header_bytes()).to_vec()
     }
 
     pub fn hash(&self) -> String {
-        let encoded = serde_json::to_string(self).unwrap();
-        let mut hasher = Sha256::new();
-        hasher.update(encoded);
-        format!("{:x}", hasher.finalize())
+        hex::encode(self.header_digest())
     }
-}
\ No newline at end of file
+}
@@ -1,129 +1,1408 @@
-use sha2::Digest;
-use sled::{Db, IVec};
-use crate::block::Block;
-use crate::transaction::Transaction;
-use crate::wallet::Wallet;
-use std::collections::HashMap;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree, UnabortableTransactionError};
+use sled::{Db, IVec, Transactional, Tree};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use crate::amount;
+use crate::block::{Block, MAX_BLOCK_BYTES, MAX_BLOCK_TRANSACTIONS};
+use crate::config::{ChainConfig, Config};
+use crate::encoding::{Decode, Encode};
+use crate::events::{ChainEvent, EventFilter, Subscriber};
+use crate::explorer::HistoryEntry;
+use crate::keystore::Keystore;
+use crate::mempool::{MempoolEntry, MAX_MEMPOOL_TRANSACTIONS};
+use crate::merkle::{self, MerkleProof};
+use crate::pow;
+use crate::snapshot::{Snapshot, UnspentOutput};
+use crate::transaction::{outpoint_key, SpendingCondition, Transaction, TxInput, TxOutput, TX_VERSION};
+use crate::validation::{self, ApplyError, ChainReport, Replay, Spent, UtxoSet, Violation};
+use crate::wallet::{self, Wallet};
+
+const BLOCK_TREE: &str = "blocks";
+const BLOCK_WORK_TREE: &str = "block_work";
+const BEST_CHAIN_TREE: &str = "best_chain";
+const UNDO_TREE: &str = "undo";
+const ORPHAN_TREE: &str = "orphans";
+const MEMPOOL_TREE: &str = "mempool";
+const UTXO_TREE: &str = "utxo";
+const META_TREE: &str = "meta";
+const TX_INDEX_TREE: &str = "tx_index";
+const ADDRESS_INDEX_TREE: &str = "address_index";
+/// Meta key holding the hash of the best chain's tip.
+const TIP_KEY: &[u8] = b"tip";
+/// Meta key holding the encoding of stored blocks and mempool entries.
+/// Stores written before the binary encoding lack it and hold JSON.
+const FORMAT_KEY: &[u8] = b"format";
+const STORE_FORMAT: u8 = 1;
+/// Meta key holding the height of the snapshot a chain was bootstrapped
+/// from. Such a chain has no blocks between genesis and the snapshot's.
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+/// Copy of the bootstrap snapshot in the data directory, the starting point
+/// for validating the chain.
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// Blocks whose parent is unknown are kept up to this many at a time; the
+/// longest waiting is dropped to make room.
+const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// Outputs spent by each transaction of a block, in block order, recorded
+/// when the block joins the best chain so it can be rolled back.
+type BlockUndo = Vec<Spent>;
+
+/// What `migrate_store` rewrote.
+#[derive(Debug, Clone)]
+pub struct MigrationReport {
+    pub blocks: usize,
+    pub orphans: usize,
+    pub mempool: usize,
+    /// Report of validating the best chain as read back from the new format.
+    pub chain: ChainReport,
+}
+
+/// What `import_blocks` did with the blocks it read.
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// Blocks validated and added to the block tree.
+    pub imported: u64,
+    pub already_known: u64,
+}
+
+/// What `process_block` did with a block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockStatus {
+    /// The block extended the best chain.
+    Connected,
+    /// The block made its side chain the heaviest, so the best chain was
+    /// rolled back by `disconnected` blocks and `connected` were applied.
+    Reorganized { disconnected: usize, connected: usize },
+    /// The block was stored on a side chain with less work than the best one.
+    SideChain,
+    /// The block's parent is unknown; it is held until the parent arrives.
+    Orphan,
+    AlreadyKnown,
+}
 
 pub struct Blockchain {
     db: Db,
-    current_transactions: Vec<Transaction>,
-    wallets: HashMap<String, f64>,
+    /// Block hash → block, for every block on the best chain or a side chain.
+    blocks: Tree,
+    /// Block hash → total work of the chain ending in that block.
+    block_work: Tree,
+    /// Height → hash of the best chain's block at that height.
+    best_chain: Tree,
+    /// Block hash → `BlockUndo` of a block on the best chain.
+    undo: Tree,
+    /// Arrival sequence and block hash → block whose parent has not been
+    /// seen yet.
+    orphans: Tree,
+    mempool: Tree,
+    utxos: Tree,
+    meta: Tree,
+    /// Transaction id → index of the best-chain block containing it.
+    tx_index: Tree,
+    /// Address, block index and position → id of a transaction paying or
+    /// spending from the address.
+    address_index: Tree,
+    keystore: Keystore,
+    config: ChainConfig,
+    data_dir: PathBuf,
+    subscribers: Vec<Subscriber>,
 }
 
 impl Blockchain {
-    pub fn new() -> Self {
-        let db = sled::open("data/blockchain").unwrap();
+    /// Opens the chain in `config.data_dir`, creating it with the genesis
+    /// block described by `config.chain` if the directory is new. An existing
+    /// chain keeps the settings it was created with.
+    pub fn open(config: &Config) -> Result<Self, String> {
+        fs::create_dir_all(&config.data_dir).map_err(|e| e.to_string())?;
+        let chain_config = config.chain.load_or_store(&config.data_dir)?;
+        let db = sled::open(config.data_dir.join("blockchain")).map_err(|e| e.to_string())?;
+        let open_tree = |name: &str| db.open_tree(name).map_err(|e| e.to_string());
         let mut blockchain = Blockchain {
+            blocks: open_tree(BLOCK_TREE)?,
+            block_work: open_tree(BLOCK_WORK_TREE)?,
+            best_chain: open_tree(BEST_CHAIN_TREE)?,
+            undo: open_tree(UNDO_TREE)?,
+            orphans: open_tree(ORPHAN_TREE)?,
+            mempool: open_tree(MEMPOOL_TREE)?,
+            utxos: open_tree(UTXO_TREE)?,
+            meta: open_tree(META_TREE)?,
+            tx_index: open_tree(TX_INDEX_TREE)?,
+            address_index: open_tree(ADDRESS_INDEX_TREE)?,
+            keystore: Keystore::open(config.data_dir.join("keystore.json"))?,
+            config: chain_config,
+            data_dir: config.data_dir.clone(),
+            subscribers: Vec::new(),
             db,
-            current_transactions: Vec::new(),
-            wallets: HashMap::new(),
         };
 
+        blockchain.check_store_format()?;
+        if !blockchain.db.is_empty() {
+            blockchain.migrate_height_keyed_blocks()?;
+        }
         if blockchain.get_last_block().is_none() {
-            blockchain.create_genesis_block();
+            blockchain.create_genesis_block()?;
+        }
+        if blockchain.tx_index.is_empty() {
+            blockchain.reindex_explorer()?;
+        }
+        if blockchain.utxos.is_empty() && blockchain.snapshot_height().is_none() {
+            blockchain.reindex_utxos()?;
+        }
+
+        Ok(blockchain)
+    }
+
+    /// Marks a new store as binary-encoded and refuses JSON-encoded ones,
+    /// which have to go through `migrate_store` first.
+    fn check_store_format(&self) -> Result<(), String> {
+        match self.meta.get(FORMAT_KEY).map_err(|e| e.to_string())? {
+            Some(format) if format.as_ref() == [STORE_FORMAT] => Ok(()),
+            Some(format) => Err(format!("Unsupported store format {:?}", format.as_ref())),
+            None if self.blocks.is_empty() => {
+                self.meta.insert(FORMAT_KEY, vec![STORE_FORMAT]).map_err(|e| e.to_string())?;
+                Ok(())
+            }
+            None => Err(String::from("The block store is JSON-encoded; run migrate-store to convert it")),
+        }
+    }
+
+    /// Rewrites a store whose blocks, orphans and mempool entries are
+    /// JSON-encoded into the binary format. Every block must hash to its key
+    /// and the best chain must validate before anything is written, and the
+    /// rewrite is one sled transaction, so a failed migration leaves the JSON
+    /// store as it was.
+    pub fn migrate_store(config: &Config) -> Result<MigrationReport, String> {
+        let path = config.data_dir.join("blockchain");
+        if !path.exists() {
+            return Err(format!("No block store at {}", path.display()));
+        }
+        let chain_config = config.chain.load_or_store(&config.data_dir)?;
+        let db = sled::open(path).map_err(|e| e.to_string())?;
+        let open_tree = |name: &str| db.open_tree(name).map_err(|e| e.to_string());
+        let (blocks, orphans, mempool) = (open_tree(BLOCK_TREE)?, open_tree(ORPHAN_TREE)?, open_tree(MEMPOOL_TREE)?);
+        let (best_chain, meta) = (open_tree(BEST_CHAIN_TREE)?, open_tree(META_TREE)?);
+        if meta.contains_key(FORMAT_KEY).map_err(|e| e.to_string())? {
+            return Err(String::from("The block store is already binary-encoded"));
+        }
+
+        let stored = decode_json_blocks(&blocks)?;
+        let waiting = decode_json_blocks(&orphans)?;
+        let mut pending = Vec::new();
+        for entry in mempool.iter() {
+            let (key, value) = entry.map_err(|e| e.to_string())?;
+            let entry: MempoolEntry = serde_json::from_slice(&value)
+                .map_err(|e| format!("Corrupt mempool entry: {}", e))?;
+            pending.push((key, entry));
+        }
+
+        let by_hash: HashMap<&[u8], &Block> = stored.iter().map(|(k, b)| (k.as_ref(), b)).collect();
+        let best: Vec<(u64, Option<Block>)> = best_chain.iter()
+            .filter_map(|res| res.ok())
+            .map(|(height, hash)| (decode_index(&height), by_hash.get(hash.as_ref()).map(|&b| b.clone())))
+            .collect();
+        if let Some((index, violation)) = validation::validate_blocks(&chain_config, best).first_invalid {
+            return Err(format!("Block {} is invalid: {}; the store was left unchanged", index, violation));
+        }
+
+        let result: Result<(), TransactionError<String>> = (&blocks, &orphans, &mempool, &meta)
+            .transaction(|(blocks, orphans, mempool, meta)| {
+                for (key, block) in &stored {
+                    blocks.insert(key, block.to_bytes())?;
+                }
+                for (key, block) in &waiting {
+                    orphans.insert(key, block.to_bytes())?;
+                }
+                for (key, entry) in &pending {
+                    mempool.insert(key, entry.to_bytes())?;
+                }
+                meta.insert(FORMAT_KEY, vec![STORE_FORMAT])?;
+                Ok(())
+            });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.to_string(),
+        })?;
+        db.flush().map_err(|e| e.to_string())?;
+
+        let rewritten = best_chain.iter()
+            .filter_map(|res| res.ok())
+            .map(|(height, hash)| {
+                let block = blocks.get(hash).ok().flatten().and_then(|v| Block::from_bytes(&v).ok());
+                (decode_index(&height), block)
+            });
+        let chain = validation::validate_blocks(&chain_config, rewritten);
+        Ok(MigrationReport { blocks: stored.len(), orphans: waiting.len(), mempool: pending.len(), chain })
+    }
+
+    /// Consensus settings this chain was created with.
+    pub fn chain_config(&self) -> &ChainConfig {
+        &self.config
+    }
+
+    fn create_genesis_block(&mut self) -> Result<(), String> {
+        let genesis_block = self.config.genesis_block();
+        self.store_block(&genesis_block, pow::block_work(genesis_block.difficulty))?;
+        self.connect_branch(&[], &[genesis_block]).map_err(|(_, e)| e)
+    }
+
+    /// Earlier versions kept the chain in the default tree keyed by height.
+    /// Those blocks are replayed into the hash-keyed store and the old
+    /// entries dropped; an interrupted migration simply resumes.
+    fn migrate_height_keyed_blocks(&mut self) -> Result<(), String> {
+        let legacy: Vec<Block> = self.db.iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+            .collect();
+        let mut legacy = legacy.into_iter();
+        if let Some(genesis_block) = legacy.next() {
+            if self.get_last_block().is_none() {
+                self.utxos.clear().map_err(|e| e.to_string())?;
+                self.tx_index.clear().map_err(|e| e.to_string())?;
+                self.address_index.clear().map_err(|e| e.to_string())?;
+                self.store_block(&genesis_block, pow::block_work(genesis_block.difficulty))?;
+                self.connect_branch(&[], &[genesis_block]).map_err(|(_, e)| e)?;
+            }
+        }
+        for block in legacy {
+            self.process_block(block)?;
+        }
+        self.db.clear().map_err(|e| e.to_string())?;
+        self.db.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Builds an unmined block on top of the current tip from the highest
+    /// fee-rate pending transactions that fit the block limits, plus a
+    /// coinbase paying the subsidy and the collected fees to `miner_address`.
+    /// Pending transactions whose inputs are no longer unspent are skipped.
+    pub fn create_new_block(&self, miner_address: &str) -> Result<Block, String> {
+        if !wallet::is_valid_address(miner_address) {
+            return Err(format!("Miner address {} is invalid", miner_address));
+        }
+        let last_block = self.get_last_block().ok_or("Chain has no tip")?;
+        let index = last_block.index + 1;
+
+        // Reserve room for the coinbase at its largest possible size.
+        let mut size = Transaction::coinbase(index, miner_address.to_string(), u64::MAX)?.size();
+        let mut fees: u64 = 0;
+        let mut transactions = Vec::new();
+        let mut claimed = HashSet::new();
+        for (_, entry) in self.mempool_entries() {
+            if transactions.len() + 1 >= MAX_BLOCK_TRANSACTIONS {
+                break;
+            }
+            if size + entry.size as usize > MAX_BLOCK_BYTES {
+                continue;
+            }
+            let outpoints: Vec<String> = entry.transaction.inputs.iter().map(TxInput::outpoint).collect();
+            let spendable = outpoints.iter().all(|outpoint| {
+                !claimed.contains(outpoint) && self.utxos.contains_key(outpoint.as_bytes()).unwrap_or(false)
+            });
+            if !spendable {
+                continue;
+            }
+            claimed.extend(outpoints);
+            fees = fees.checked_add(entry.fee).ok_or("Block fees overflow")?;
+            size += entry.size as usize;
+            transactions.push(entry.transaction);
+        }
+
+        let reward = self.config.block_subsidy(index).checked_add(fees).ok_or("Block reward overflows")?;
+        transactions.push(Transaction::coinbase(index, miner_address.to_string(), reward)?);
+
+        Ok(Block::new(index, transactions, last_block.hash(), self.difficulty_after(&last_block)))
+    }
+
+    /// Validates `block` and adds it to the block tree. A block extending the
+    /// best chain is connected; one that makes a side chain heavier than the
+    /// best chain triggers a reorganisation; one whose parent is unknown is
+    /// held as an orphan. Orphans waiting on this block are processed next.
+    pub fn process_block(&mut self, block: Block) -> Result<BlockStatus, String> {
+        // Checked before the hash is looked up; see
+        // `Block::has_duplicate_transactions`.
+        if block.has_duplicate_transactions() {
+            return Err(format!("Block {} repeats a transaction", block.index));
+        }
+        let hash = block.hash();
+        let known = self.blocks.contains_key(hash.as_bytes()).map_err(|e| e.to_string())?
+            || self.has_orphan(&hash);
+        if known {
+            return Ok(BlockStatus::AlreadyKnown);
+        }
+        if block.index == 0 {
+            return Err(String::from("Cannot replace the genesis block"));
+        }
+        if !block.has_known_versions() {
+            return Err(format!("Block {} has an unsupported version", block.index));
+        }
+        self.config.consensus().check_seal(&block).map_err(|e| format!("Block {}: {}", block.index, e))?;
+        if block.merkle_root != block.compute_merkle_root() {
+            return Err(format!("Block {} has a mismatched merkle root", block.index));
+        }
+        if block.exceeds_limits() {
+            return Err(format!("Block {} exceeds the block size limits", block.index));
+        }
+
+        let parent = match self.get_block_by_hash(&block.previous_hash) {
+            Some(parent) => parent,
+            None => {
+                self.add_orphan(&block)?;
+                return Ok(BlockStatus::Orphan);
+            }
+        };
+        if block.version < parent.version {
+            return Err(format!("Block {} has an older version than its parent", block.index));
+        }
+        if block.index != parent.index + 1 {
+            return Err(format!("Block {} does not follow its parent {}", block.index, parent.index));
+        }
+        if block.timestamp < parent.timestamp {
+            return Err(format!("Block {} has a timestamp before its parent", block.index));
+        }
+        let expected = self.difficulty_after(&parent);
+        if block.difficulty != expected {
+            return Err(format!("Block {} has difficulty {}, expected {}", block.index, block.difficulty, expected));
+        }
+
+        let work = self.total_work(&parent.hash())
+            .and_then(|work| work.checked_add(pow::block_work(block.difficulty)))
+            .ok_or_else(|| format!("Block {} has no recorded parent work", block.index))?;
+        self.store_block(&block, work)?;
+        let status = if work > self.chain_work() {
+            self.switch_to(&block)?
+        } else {
+            BlockStatus::SideChain
+        };
+
+        self.process_orphans(&hash);
+        Ok(status)
+    }
+
+    /// Makes `tip` the best chain's tip. Blocks of the current best chain
+    /// above the fork point are rolled back and the new branch applied in one
+    /// sled transaction, so a branch with an invalid block leaves the best
+    /// chain untouched; the invalid block and its descendants are discarded.
+    fn switch_to(&mut self, tip: &Block) -> Result<BlockStatus, String> {
+        let mut connect = vec![tip.clone()];
+        loop {
+            let parent = connect.last().unwrap().previous_hash.clone();
+            let parent = self.get_block_by_hash(&parent)
+                .ok_or_else(|| format!("Block {} is missing from the block tree", parent))?;
+            if self.is_on_best_chain(&parent) {
+                break;
+            }
+            connect.push(parent);
+        }
+        connect.reverse();
+        let mut disconnect = self.blocks_from(connect[0].index, usize::MAX);
+        disconnect.reverse();
+
+        if let Err((failed, e)) = self.connect_branch(&disconnect, &connect) {
+            // Without a failing block the branch itself is not at fault, so
+            // the block tree keeps it.
+            let Some(position) = connect.iter().position(|b| Some(b.hash()) == failed) else {
+                return Err(e);
+            };
+            for block in &connect[position..] {
+                let hash = block.hash();
+                self.blocks.remove(hash.as_bytes()).map_err(|e| e.to_string())?;
+                self.block_work.remove(hash.as_bytes()).map_err(|e| e.to_string())?;
+            }
+            return Err(e);
+        }
+
+        self.publish(&disconnect, &connect);
+        if disconnect.is_empty() {
+            return Ok(BlockStatus::Connected);
+        }
+        let status = BlockStatus::Reorganized { disconnected: disconnect.len(), connected: connect.len() };
+        self.requeue_transactions(disconnect)?;
+        Ok(status)
+    }
+
+    /// Subscribes to changes of the best chain. The subscription ends when
+    /// the receiver is dropped.
+    pub fn subscribe(&mut self, filter: EventFilter) -> Receiver<ChainEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(Subscriber::channel(filter, sender));
+        receiver
+    }
+
+    /// Calls `callback` with each matching change of the best chain, on the
+    /// thread that processed the block, so it should return quickly.
+    pub fn subscribe_with(&mut self, filter: EventFilter, callback: impl FnMut(&ChainEvent) + Send + 'static) {
+        self.subscribers.push(Subscriber::callback(filter, callback));
+    }
+
+    /// Reports a switch of the best chain to every subscriber, dropping
+    /// those whose receiver has gone away.
+    fn publish(&mut self, disconnected: &[Block], connected: &[Block]) {
+        let mut subscribers = std::mem::take(&mut self.subscribers);
+        subscribers.retain_mut(|subscriber| {
+            self.chain_events(&subscriber.filter, disconnected, connected)
+                .iter()
+                .all(|event| subscriber.deliver(event))
+        });
+        self.subscribers = subscribers;
+    }
+
+    /// Events for disconnecting `disconnected` (tip first) and then
+    /// connecting `connected` (parent first), as seen through `filter`.
+    fn chain_events(&self, filter: &EventFilter, disconnected: &[Block], connected: &[Block]) -> Vec<ChainEvent> {
+        let mut events: Vec<ChainEvent> = disconnected.iter()
+            .map(|block| ChainEvent::BlockDisconnected { index: block.index, hash: block.hash() })
+            .collect();
+        for block in connected {
+            events.push(ChainEvent::BlockConnected {
+                index: block.index,
+                hash: block.hash(),
+                transactions: block.transactions.len(),
+            });
+            self.transaction_events(filter, block, 1, &mut events);
+            if filter.confirmations > 1 {
+                let buried = block.index.checked_sub(filter.confirmations - 1).and_then(|index| self.get_block(index));
+                if let Some(buried) = buried {
+                    self.transaction_events(filter, &buried, filter.confirmations, &mut events);
+                }
+            }
+        }
+        events
+    }
+
+    fn transaction_events(&self, filter: &EventFilter, block: &Block, confirmations: u64, events: &mut Vec<ChainEvent>) {
+        if filter.addresses.is_empty() {
+            return;
+        }
+        for tx in &block.transactions {
+            let mut involved: BTreeSet<String> = tx.outputs.iter().map(|o| o.address.clone()).collect();
+            involved.extend(tx.inputs.iter().filter_map(TxInput::signer_address));
+            for address in involved.into_iter().filter(|a| filter.addresses.contains(a)) {
+                // Outputs spent from below a bootstrap snapshot cannot be
+                // looked up; such a transaction is still reported.
+                let (received, sent) = self.address_flows(tx, &address).unwrap_or_default();
+                events.push(ChainEvent::TransactionConfirmed {
+                    txid: tx.id.clone(),
+                    address,
+                    received,
+                    sent,
+                    block_index: block.index,
+                    block_hash: block.hash(),
+                    confirmations,
+                });
+            }
+        }
+    }
+
+    /// Rolls back `disconnect` (best-chain blocks, tip first) and applies
+    /// `connect` (parent first) in one transaction, also dropping from the
+    /// mempool the newly mined transactions and those spending an output the
+    /// new blocks spend. On failure returns the hash of the block that could
+    /// not be applied, if any.
+    fn connect_branch(&self, disconnect: &[Block], connect: &[Block]) -> Result<(), (Option<String>, String)> {
+        let included: HashSet<&str> = connect.iter()
+            .flat_map(|block| &block.transactions)
+            .map(|tx| tx.id.as_str())
+            .collect();
+        let spent: HashSet<String> = connect.iter()
+            .flat_map(|block| &block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.inputs.iter().map(TxInput::outpoint))
+            .collect();
+        let stale: Vec<IVec> = self.pending_transactions()
+            .into_iter()
+            .filter(|(_, tx)| {
+                included.contains(tx.id.as_str()) || tx.inputs.iter().any(|input| spent.contains(&input.outpoint()))
+            })
+            .map(|(key, _)| key)
+            .collect();
+
+        let trees = (&self.best_chain, &self.undo, &self.utxos, &self.tx_index, &self.address_index, &self.meta, &self.mempool);
+        let result: Result<(), TransactionError<(Option<String>, String)>> = trees
+            .transaction(|(best_chain, undo, utxos, tx_index, address_index, meta, mempool)| {
+                let state = ChainState { best_chain, undo, utxos, tx_index, address_index, meta };
+                for block in disconnect {
+                    disconnect_block(&state, block).map_err(|e| tag_abort(e, None))?;
+                }
+                for block in connect {
+                    connect_block(&self.config, &state, block).map_err(|e| tag_abort(e, Some(block.hash())))?;
+                }
+                for key in &stale {
+                    mempool.remove(key)?;
+                }
+                Ok(())
+            });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => (None, e.to_string()),
+        })?;
+        self.db.flush().map_err(|e| (None, e.to_string()))?;
+        Ok(())
+    }
+
+    /// Records `block` in the block tree with the total work of its chain.
+    fn store_block(&self, block: &Block, work: u128) -> Result<(), String> {
+        let hash = block.hash();
+        let result: Result<(), TransactionError<String>> = (&self.blocks, &self.block_work)
+            .transaction(|(blocks, block_work)| {
+                blocks.insert(hash.as_bytes(), block.to_bytes())?;
+                block_work.insert(hash.as_bytes(), &work.to_be_bytes())?;
+                Ok(())
+            });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.to_string(),
+        })
+    }
+
+    /// Holds `block` until its parent arrives, keyed by arrival so that a
+    /// full pool drops its oldest entry, whatever the hashes.
+    fn add_orphan(&self, block: &Block) -> Result<(), String> {
+        if self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            if let Some((oldest, _)) = self.orphans.first().map_err(|e| e.to_string())? {
+                self.orphans.remove(oldest).map_err(|e| e.to_string())?;
+            }
         }
+        let mut key = self.db.generate_id().map_err(|e| e.to_string())?.to_be_bytes().to_vec();
+        key.extend_from_slice(block.hash().as_bytes());
+        self.orphans.insert(key, block.to_bytes()).map_err(|e| e.to_string())?;
+        Ok(())
+    }
 
-        blockchain
+    fn has_orphan(&self, hash: &str) -> bool {
+        self.orphans.iter().keys().filter_map(|res| res.ok()).any(|key| key.ends_with(hash.as_bytes()))
     }
 
-    fn create_genesis_block(&mut self) {
-        let genesis_block = Block::new(0, Vec::new(), 100, String::from("0"));
-        self.save_block(&genesis_block);
+    /// Processes the orphans whose parent is `parent_hash`, which just
+    /// arrived. Orphans that turn out invalid are dropped.
+    fn process_orphans(&mut self, parent_hash: &str) {
+        let children: Vec<(IVec, Block)> = self.orphans.iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(k, v)| Block::from_bytes(&v).ok().map(|block| (k, block)))
+            .filter(|(_, block)| block.previous_hash == parent_hash)
+            .collect();
+        for (key, block) in children {
+            let _ = self.orphans.remove(key);
+            let _ = self.process_block(block);
+        }
     }
 
-    pub fn create_new_block(&mut self, proof: u64) -> Block {
-        let last_block = self.get_last_block().unwrap();
-        let new_block = Block::new(
-            last_block.index + 1,
-            self.current_transactions.clone(),
-            proof,
-            last_block.hash(),
-        );
+    /// Difficulty a child of `parent` must be mined at, following the
+    /// retargeting rules along `parent`'s own branch.
+    pub fn difficulty_after(&self, parent: &Block) -> u32 {
+        self.config.consensus().next_difficulty(parent.index + 1, parent.difficulty, &|height| {
+            self.ancestor(parent, height).map(|b| b.timestamp)
+        })
+    }
 
-        self.save_block(&new_block);
-        self.current_transactions.clear();
-        new_block
+    /// The block at `height` on the branch ending in `block`.
+    fn ancestor(&self, block: &Block, height: u64) -> Option<Block> {
+        let mut block = block.clone();
+        while block.index > height {
+            if self.is_on_best_chain(&block) {
+                return self.get_block(height);
+            }
+            block = self.get_block_by_hash(&block.previous_hash)?;
+        }
+        (block.index == height).then_some(block)
+    }
+
+    fn is_on_best_chain(&self, block: &Block) -> bool {
+        self.best_chain.get(block.index.to_be_bytes())
+            .ok()
+            .flatten()
+            .is_some_and(|hash| hash == block.hash().as_bytes())
+    }
+
+    /// Total work of the chain ending in the stored block `hash`.
+    fn total_work(&self, hash: &str) -> Option<u128> {
+        let bytes = self.block_work.get(hash.as_bytes()).ok().flatten()?;
+        Some(u128::from_be_bytes(bytes.as_ref().try_into().ok()?))
     }
 
-    pub fn add_transaction(&mut self, sender: String, recipient: String, amount: f64) -> Result<u64, String> {
-        if !self.wallets.contains_key(&sender) {
+    /// Builds a transaction spending `sender`'s unspent outputs to pay
+    /// `amount` to `recipient` plus `fee` to the miner, returning any change
+    /// to `sender`, signs it with the sender's key from the keystore and
+    /// queues it in the mempool. Outputs already claimed by pending
+    /// transactions are not selected again.
+    pub fn add_transaction(&mut self, sender: String, recipient: String, amount: u64, fee: u64, passphrase: &str) -> Result<u64, String> {
+        if !self.keystore.contains(&sender) {
             return Err(format!("Sender wallet {} does not exist", sender));
         }
-        if !self.wallets.contains_key(&recipient) {
-            return Err(format!("Recipient wallet {} does not exist", recipient));
+        let signer = self.keystore.unlock(&sender, passphrase)?;
+        self.send(&signer, recipient, amount, fee)
+    }
+
+    /// Like `add_transaction`, but signed by an already unlocked wallet.
+    pub fn send(&mut self, signer: &Wallet, recipient: String, amount: u64, fee: u64) -> Result<u64, String> {
+        if amount == 0 {
+            return Err(String::from("Amount must be positive"));
+        }
+        if !wallet::is_valid_address(&recipient) {
+            return Err(format!("Recipient address {} is invalid", recipient));
         }
-        if self.wallets.get(&sender).unwrap() < &amount {
+        let sender = &signer.address;
+        let needed = amount.checked_add(fee).ok_or("Amount plus fee overflows")?;
+
+        let (inputs, gathered) = self.select_inputs(sender, needed, |_| true)?;
+        if gathered < needed {
             return Err(format!("Insufficient funds in sender wallet {}", sender));
         }
 
-        *self.wallets.get_mut(&sender).unwrap() -= amount;
-        *self.wallets.get_mut(&recipient).unwrap() += amount;
+        let mut outputs = vec![TxOutput { amount, address: recipient }];
+        if gathered > needed {
+            outputs.push(TxOutput { amount: gathered - needed, address: sender.clone() });
+        }
+        let mut transaction = Transaction::new(inputs, outputs);
+        transaction.sign(signer);
 
-        let transaction = Transaction {
-            sender,
-            recipient,
-            amount,
-        };
-        self.current_transactions.push(transaction);
-        Ok(self.get_last_block().unwrap().index + 1)
+        self.submit_transaction(transaction)
+    }
+
+    /// Builds an unsigned transaction spending outputs paid to `condition`'s
+    /// address, with change returned to it. Only outputs whose timelock
+    /// allows spending in the next block are selected. The keys of the
+    /// condition then sign it in turn before it is submitted.
+    pub fn propose_spend(&self, condition: &SpendingCondition, recipient: String, amount: u64, fee: u64) -> Result<Transaction, String> {
+        condition.validate()?;
+        if amount == 0 {
+            return Err(String::from("Amount must be positive"));
+        }
+        if !wallet::is_valid_address(&recipient) {
+            return Err(format!("Recipient address {} is invalid", recipient));
+        }
+        let address = condition.address();
+        let needed = amount.checked_add(fee).ok_or("Amount plus fee overflows")?;
+        let height = self.get_last_block().ok_or("Chain has no tip")?.index + 1;
+
+        let (inputs, gathered) = self.select_inputs(&address, needed, |input| {
+            let created = self.mined_height(&input.txid).unwrap_or(height);
+            condition.timelock.is_none_or(|timelock| timelock.is_unlocked(created, height))
+        })?;
+        if gathered < needed {
+            return Err(format!("Insufficient unlocked funds at {}", address));
+        }
+
+        let mut outputs = vec![TxOutput { amount, address: recipient }];
+        if gathered > needed {
+            outputs.push(TxOutput { amount: gathered - needed, address });
+        }
+        let inputs = inputs.into_iter()
+            .map(|input| TxInput::with_condition(input.txid, input.vout, condition.clone()))
+            .collect();
+        Ok(Transaction::new(inputs, outputs))
+    }
+
+    /// Picks unspent outputs of `address` that no pending transaction claims
+    /// and `spendable` accepts until they cover `needed`. Returns the inputs
+    /// and their total, which falls short if the funds do not suffice.
+    fn select_inputs(&self, address: &str, needed: u64, spendable: impl Fn(&TxInput) -> bool) -> Result<(Vec<TxInput>, u64), String> {
+        let reserved = self.reserved_outpoints();
+        let mut inputs = Vec::new();
+        let mut gathered: u64 = 0;
+        for (input, output) in self.unspent_outputs(address) {
+            if gathered >= needed {
+                break;
+            }
+            if reserved.contains(&input.outpoint()) || !spendable(&input) {
+                continue;
+            }
+            gathered = gathered.checked_add(output.amount)
+                .ok_or_else(|| format!("Balance of wallet {} overflows", address))?;
+            inputs.push(input);
+        }
+        Ok((inputs, gathered))
+    }
+
+    /// Height of the best-chain block containing transaction `txid`.
+    fn mined_height(&self, txid: &str) -> Option<u64> {
+        self.tx_index.get(txid.as_bytes()).ok().flatten().map(|v| decode_index(&v))
     }
 
+    /// Validates a signed transaction against the UTXO set and the mempool and
+    /// queues it by fee rate. When the mempool is full the lowest fee-rate
+    /// entry is evicted, or the new transaction refused if it pays less.
+    /// Returns the index of the next block.
+    pub fn submit_transaction(&mut self, transaction: Transaction) -> Result<u64, String> {
+        let height = self.get_last_block().ok_or("Chain has no tip")?.index + 1;
+        let entry = self.check_pending(transaction, &self.reserved_outpoints(), height)??;
+
+        if self.mempool.len() >= MAX_MEMPOOL_TRANSACTIONS {
+            // Entries that cannot be decoded leave nothing to compare with,
+            // so the pool is treated as having room.
+            if let Some((lowest_key, lowest)) = self.mempool_entries().pop() {
+                if entry.fee_rate() <= lowest.fee_rate() {
+                    return Err(String::from("Mempool is full and the fee rate is too low"));
+                }
+                self.mempool.remove(lowest_key).map_err(|e| e.to_string())?;
+            }
+        }
+
+        let sequence = self.db.generate_id().map_err(|e| e.to_string())?;
+        self.mempool.insert(entry.key(sequence), entry.to_bytes()).map_err(|e| e.to_string())?;
+        self.db.flush().map_err(|e| e.to_string())?;
+
+        Ok(height)
+    }
+
+    /// Validates `transaction` for the mempool against the UTXO set as of
+    /// block `height`, refusing to spend the `reserved` outputs. The outer
+    /// error is a storage failure, the inner one the reason the transaction
+    /// is rejected.
+    fn check_pending(&self, transaction: Transaction, reserved: &HashSet<String>, height: u64) -> Result<Result<MempoolEntry, String>, String> {
+        if transaction.is_coinbase() || transaction.inputs.is_empty() {
+            return Ok(Err(String::from("Only the miner may create coinbase transactions")));
+        }
+        if transaction.version > TX_VERSION {
+            return Ok(Err(format!("Transaction {} has an unsupported version", transaction.id)));
+        }
+        if transaction.id != transaction.hash() {
+            return Ok(Err(format!("Transaction {} has a mismatched id", transaction.id)));
+        }
+        if let Some(input) = transaction.inputs.iter().find(|input| reserved.contains(&input.outpoint())) {
+            return Ok(Err(format!("Output {} is already being spent", input.outpoint())));
+        }
+        let mut utxos = PendingUtxos { chain: self, spent: HashSet::new() };
+        match validation::apply_transaction(&transaction, height, &mut utxos) {
+            Ok((fee, _)) => Ok(Ok(MempoolEntry::new(transaction, fee))),
+            Err(ApplyError::Invalid(violation)) => Ok(Err(format!("Transaction rejected: {}", violation))),
+            Err(ApplyError::Storage(e)) => Err(e),
+        }
+    }
+
+    /// Mempool entries from the highest to the lowest fee rate.
+    pub fn mempool_entries(&self) -> Vec<(IVec, MempoolEntry)> {
+        self.mempool.iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(k, v)| MempoolEntry::from_bytes(&v).ok().map(|entry| (k, entry)))
+            .collect()
+    }
+
+    /// Transactions waiting to be mined, in mining priority order.
+    pub fn pending_transactions(&self) -> Vec<(IVec, Transaction)> {
+        self.mempool_entries()
+            .into_iter()
+            .map(|(k, entry)| (k, entry.transaction))
+            .collect()
+    }
+
+    /// Outputs already spent by a transaction that is waiting in the mempool.
+    fn reserved_outpoints(&self) -> HashSet<String> {
+        self.pending_transactions()
+            .into_iter()
+            .flat_map(|(_, tx)| tx.inputs)
+            .map(|input| input.outpoint())
+            .collect()
+    }
+
+    /// Unspent outputs paying `address`, keyed by the input that would spend them.
+    pub fn unspent_outputs(&self, address: &str) -> Vec<(TxInput, TxOutput)> {
+        self.utxos.iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(k, v)| {
+                let output: TxOutput = serde_json::from_slice(&v).ok()?;
+                if output.address != address {
+                    return None;
+                }
+                let key = String::from_utf8(k.to_vec()).ok()?;
+                let (txid, vout) = key.rsplit_once(':')?;
+                Some((TxInput::new(txid.to_string(), vout.parse().ok()?), output))
+            })
+            .collect()
+    }
+
+    /// Drops the UTXO set and the undo records and rebuilds them by replaying
+    /// the best chain from genesis, so any balance can be audited against the
+    /// stored blocks.
+    pub fn reindex_utxos(&mut self) -> Result<(), String> {
+        if let Some(height) = self.snapshot_height() {
+            return Err(format!("Chain was bootstrapped from a snapshot at height {} and cannot be replayed", height));
+        }
+        self.utxos.clear().map_err(|e| e.to_string())?;
+        self.undo.clear().map_err(|e| e.to_string())?;
+        for block in self.get_chain() {
+            let result: Result<(), TransactionError<String>> = (&self.utxos, &self.undo, &self.tx_index)
+                .transaction(|(utxos, undo, tx_index)| {
+                    let spent = apply_block(&self.config, utxos, tx_index, &block)?;
+                    undo.insert(block.hash().as_bytes(), serde_json::to_vec(&spent).unwrap())?;
+                    Ok(())
+                });
+            result.map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.to_string(),
+            })?;
+        }
+        self.utxos.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Tip of the best chain.
     pub fn get_last_block(&self) -> Option<Block> {
-        self.db.last()
+        let hash = self.meta.get(TIP_KEY).ok().flatten()?;
+        self.get_block_by_hash(std::str::from_utf8(&hash).ok()?)
+    }
+
+    /// Block at `index` on the best chain.
+    pub fn get_block(&self, index: u64) -> Option<Block> {
+        let hash = self.best_chain.get(index.to_be_bytes()).ok().flatten()?;
+        self.get_block_by_hash(std::str::from_utf8(&hash).ok()?)
+    }
+
+    /// Any stored block, on the best chain or a side chain.
+    pub fn get_block_by_hash(&self, hash: &str) -> Option<Block> {
+        self.blocks.get(hash.as_bytes())
             .ok()
             .flatten()
-            .and_then(|(_, v)| serde_json::from_slice(&v).ok())
+            .and_then(|v| Block::from_bytes(&v).ok())
     }
 
-    fn save_block(&mut self, block: &Block) {
-        let key = block.index.to_be_bytes();
-        let value = serde_json::to_vec(block).unwrap();
-        self.db.insert(key, value).unwrap();
+    /// The mined transaction `txid` together with the block containing it.
+    pub fn find_transaction(&self, txid: &str) -> Option<(Block, Transaction)> {
+        let index = self.tx_index.get(txid.as_bytes()).ok().flatten()?;
+        let block = self.get_block(decode_index(&index))?;
+        let transaction = block.transactions.iter().find(|tx| tx.id == txid)?.clone();
+        Some((block, transaction))
     }
 
-    pub fn proof_of_work(&self, last_proof: u64) -> u64 {
-        let mut proof = 0;
-        while !Blockchain::valid_proof(last_proof, proof) {
-            proof += 1;
+    /// Every mined transaction paying or spending from `address`, oldest
+    /// first, with the amounts it moved in and out of the address.
+    pub fn address_history(&self, address: &str) -> Result<Vec<HistoryEntry>, String> {
+        if !wallet::is_valid_address(address) {
+            return Err(format!("Address {} is invalid", address));
         }
-        proof
+        let mut history = Vec::new();
+        for entry in self.address_index.scan_prefix(address.as_bytes()) {
+            let (key, txid) = entry.map_err(|e| e.to_string())?;
+            let txid = String::from_utf8(txid.to_vec()).map_err(|e| e.to_string())?;
+            let (block, tx) = self.find_transaction(&txid)
+                .ok_or_else(|| format!("Indexed transaction {} is missing", txid))?;
+
+            let (received, sent) = self.address_flows(&tx, address)?;
+            history.push(HistoryEntry {
+                block_index: decode_index(&key[address.len()..]),
+                block_hash: block.hash(),
+                timestamp: block.timestamp,
+                txid,
+                received,
+                sent,
+            });
+        }
+        Ok(history)
     }
 
-    fn valid_proof(last_proof: u64, proof: u64) -> bool {
-        let guess = format!("{}{}", last_proof, proof);
-        let guess_hash = format!("{:x}", sha2::Sha256::digest(guess.as_bytes()));
-        guess_hash.starts_with("0000")
+    /// Base units `tx` pays to and spends from `address`.
+    fn address_flows(&self, tx: &Transaction, address: &str) -> Result<(u64, u64), String> {
+        let received = amount::checked_sum(
+            tx.outputs.iter().filter(|o| o.address == address).map(|o| o.amount),
+        )?;
+        let mut spent = Vec::new();
+        for input in tx.inputs.iter().filter(|i| i.signer_address().as_deref() == Some(address)) {
+            let (_, previous) = self.find_transaction(&input.txid)
+                .ok_or_else(|| format!("Spent transaction {} is missing", input.txid))?;
+            let output = previous.outputs.get(input.vout as usize)
+                .ok_or_else(|| format!("Spent output {} is missing", input.outpoint()))?;
+            spent.push(output.amount);
+        }
+        Ok((received, amount::checked_sum(spent)?))
     }
 
-    pub fn create_wallet(&mut self) -> Wallet {
+    /// Drops the explorer indexes and rebuilds them from the best chain.
+    pub fn reindex_explorer(&mut self) -> Result<(), String> {
+        self.tx_index.clear().map_err(|e| e.to_string())?;
+        self.address_index.clear().map_err(|e| e.to_string())?;
+        for block in self.get_chain() {
+            let result: Result<(), TransactionError<String>> = (&self.tx_index, &self.address_index)
+                .transaction(|(txs, addresses)| index_block(txs, addresses, &block));
+            result.map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.to_string(),
+            })?;
+        }
+        self.db.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Searches for a nonce that gives `block`'s header hash at least
+    /// `block.difficulty` leading zero bits.
+    pub fn proof_of_work(block: &mut Block) {
+        while !pow::valid_proof(block) {
+            block.nonce += 1;
+        }
+    }
+
+    /// Generates a new key pair and stores it in the keystore encrypted
+    /// under `passphrase`.
+    pub fn create_wallet(&mut self, passphrase: &str) -> Result<Wallet, String> {
         let wallet = Wallet::new();
-        self.wallets.insert(wallet.address.clone(), 0.0);
-        wallet
+        self.keystore.add(&wallet, passphrase)?;
+        Ok(wallet)
+    }
+
+    /// Hex-encoded public key of a wallet in the local keystore.
+    pub fn public_key(&self, address: &str) -> Option<String> {
+        self.keystore.public_key(address)
+    }
+
+    /// Decrypts the key of a wallet in the local keystore.
+    pub fn unlock_wallet(&self, address: &str, passphrase: &str) -> Result<Wallet, String> {
+        self.keystore.unlock(address, passphrase)
+    }
+
+    /// Addresses of the wallets whose keys are held in the local keystore.
+    pub fn wallet_addresses(&self) -> Vec<String> {
+        self.keystore.addresses()
     }
 
-    pub fn get_wallet_balance(&self, address: &str) -> Result<f64, String> {
-        self.wallets.get(address)
-            .cloned()
-            .ok_or_else(|| format!("Wallet {} not found", address))
+    /// Sum of the confirmed unspent outputs paying `address`.
+    pub fn get_wallet_balance(&self, address: &str) -> Result<u64, String> {
+        if !wallet::is_valid_address(address) {
+            return Err(format!("Wallet {} not found", address));
+        }
+        amount::checked_sum(self.unspent_outputs(address).iter().map(|(_, o)| o.amount))
+            .map_err(|_| format!("Balance of wallet {} overflows", address))
+    }
+
+    pub fn mine(&mut self, miner_address: &str) -> Result<Block, String> {
+        let mut block = self.create_new_block(miner_address)?;
+        Blockchain::proof_of_work(&mut block);
+        self.process_block(block.clone())?;
+        Ok(block)
+    }
+
+    /// Builds the next block of a proof-of-authority chain, paying the
+    /// reward to `validator`, and signs and connects it. Fails unless it is
+    /// `validator`'s turn.
+    pub fn sign_block(&mut self, validator: &Wallet) -> Result<Block, String> {
+        let authority = self.config.authority().ok_or("Chain does not use proof of authority")?;
+        let mut block = self.create_new_block(&validator.address)?;
+        authority.sign(&mut block, validator)?;
+        self.process_block(block.clone())?;
+        Ok(block)
+    }
+
+    /// Whether `validator` is due to sign the next block of a
+    /// proof-of-authority chain.
+    pub fn is_turn_of(&self, validator: &Wallet) -> bool {
+        match (self.config.authority(), self.get_last_block()) {
+            (Some(authority), Some(tip)) => authority.in_turn(tip.index + 1) == validator.public_key_hex(),
+            _ => false,
+        }
+    }
+
+    /// Builds a merkle inclusion proof for the mined transaction `tx_id`.
+    pub fn transaction_proof(&self, tx_id: &str) -> Result<MerkleProof, String> {
+        let (block, _) = self.find_transaction(tx_id)
+            .ok_or_else(|| format!("Transaction {} is not in any block", tx_id))?;
+        let txids: Vec<String> = block.transactions.iter().map(|tx| tx.id.clone()).collect();
+        let position = txids.iter().position(|id| id == tx_id).unwrap();
+        Ok(MerkleProof {
+            txid: tx_id.to_string(),
+            block_index: block.index,
+            block_hash: block.hash(),
+            merkle_root: block.merkle_root.clone(),
+            path: merkle::merkle_path(&txids, position).unwrap(),
+        })
+    }
+
+    /// Replays the best chain from genesis, checking hash links, proofs of
+    /// work, timestamps, signatures and spends, and reports the first block
+    /// that fails.
+    pub fn validate_chain(&self) -> ChainReport {
+        let (mut replay, from) = match self.replay_base() {
+            Ok(base) => base,
+            Err(_) => return ChainReport { blocks_checked: 0, first_invalid: Some((0, Violation::Undecodable)) },
+        };
+        validation::replay_blocks(&self.config, &mut replay, self.stored_blocks(from))
+    }
+
+    /// Best-chain blocks from height `from` up, `None` where one is missing
+    /// or cannot be decoded.
+    fn stored_blocks(&self, from: u64) -> impl Iterator<Item = (u64, Option<Block>)> + '_ {
+        self.best_chain.range(from.to_be_bytes()..)
+            .filter_map(|res| res.ok())
+            .map(|(k, hash)| {
+                let block = std::str::from_utf8(&hash).ok().and_then(|hash| self.get_block_by_hash(hash));
+                (decode_index(&k), block)
+            })
+    }
+
+    /// Where replaying the chain starts: genesis, or for a bootstrapped
+    /// chain the state of its snapshot. Returns the first height to replay.
+    fn replay_base(&self) -> Result<(Replay, u64), String> {
+        match self.snapshot_height() {
+            None => Ok((Replay::default(), 0)),
+            Some(height) => {
+                let snapshot = Snapshot::load(&self.data_dir.join(SNAPSHOT_FILE))?;
+                Ok((Replay::from_snapshot(&snapshot), height + 1))
+            }
+        }
     }
 
-    pub fn mine(&mut self) -> Block {
-        let last_block = self.get_last_block().unwrap();
-        let last_proof = last_block.proof;
-        let proof = self.proof_of_work(last_proof);
+    /// Height of the snapshot this chain was bootstrapped from, if any.
+    pub fn snapshot_height(&self) -> Option<u64> {
+        self.meta.get(SNAPSHOT_KEY).ok().flatten().map(|v| decode_index(&v))
+    }
 
-        // Reward the miner
-        let miner_address = self.create_wallet().address;
-        self.add_transaction(String::from("0"), miner_address, 10.0).unwrap();
+    /// Every best-chain block from genesis, read one at a time for export.
+    pub fn export_blocks(&self) -> Result<impl Iterator<Item = Block> + '_, String> {
+        if let Some(height) = self.snapshot_height() {
+            return Err(format!("Chain was bootstrapped from a snapshot at height {} and lacks earlier blocks", height));
+        }
+        let tip = self.get_last_block().map_or(0, |tip| tip.index);
+        Ok((0..=tip).map_while(|index| self.get_block(index)))
+    }
 
-        self.create_new_block(proof)
+    /// Validates and adds blocks read from an export in chain order. The
+    /// file's genesis block must be this chain's; every other block goes
+    /// through `process_block`, and the import stops at the first failure.
+    pub fn import_blocks(&mut self, blocks: impl IntoIterator<Item = Result<Block, String>>) -> Result<ImportReport, String> {
+        let mut report = ImportReport::default();
+        for block in blocks {
+            let block = block?;
+            if block.index == 0 {
+                if self.get_block(0).is_none_or(|genesis| genesis.hash() != block.hash()) {
+                    return Err(String::from("The file holds a different chain: genesis blocks differ"));
+                }
+                report.already_known += 1;
+                continue;
+            }
+            let index = block.index;
+            match self.process_block(block).map_err(|e| format!("Block {}: {}", index, e))? {
+                BlockStatus::AlreadyKnown => report.already_known += 1,
+                BlockStatus::Orphan => return Err(format!("Block {} does not follow the blocks before it", index)),
+                _ => report.imported += 1,
+            }
+        }
+        Ok(report)
     }
 
+    /// The UTXO set after the best-chain block at `height`, built by
+    /// replaying and validating the chain up to it.
+    pub fn snapshot(&self, height: u64) -> Result<Snapshot, String> {
+        let tip = self.get_last_block().map_or(0, |tip| tip.index);
+        if height > tip {
+            return Err(format!("Height {} is above the tip at {}", height, tip));
+        }
+        let (mut replay, from) = self.replay_base()?;
+        if height + 1 < from {
+            return Err(format!("Chain was bootstrapped at height {}; cannot snapshot below it", from - 1));
+        }
+        let blocks = self.stored_blocks(from).take_while(|(index, _)| *index <= height);
+        let report = validation::replay_blocks(&self.config, &mut replay, blocks);
+        if let Some((index, violation)) = report.first_invalid {
+            return Err(format!("Chain is invalid at block {}: {}", index, violation));
+        }
+
+        // Retargeting looks back at most one interval.
+        let start = height.saturating_sub(self.config.pow.retarget_interval);
+        let blocks: Vec<Block> = (start..=height).filter_map(|index| self.get_block(index)).collect();
+        let tip = blocks.last().ok_or("Snapshot block is missing")?;
+        let work = self.total_work(&tip.hash()).ok_or("Snapshot block has no recorded work")?;
+        let utxos = replay.utxos().iter().map(|(outpoint, (output, height))| UnspentOutput {
+            outpoint: outpoint.clone(),
+            height: *height,
+            output: output.clone(),
+        });
+        Ok(Snapshot::new(self.config.chain_id.clone(), work, blocks, utxos))
+    }
+
+    /// Replaces a new chain's state with `snapshot`'s: its blocks join the
+    /// best chain, its outputs become the UTXO set and its block becomes the
+    /// tip, without replaying the blocks before it. Blocks below the
+    /// snapshot are never stored, so reorganisations cannot reach past it.
+    pub fn bootstrap(&mut self, snapshot: Snapshot) -> Result<(), String> {
+        if self.get_last_block().is_some_and(|tip| tip.index > 0) || self.snapshot_height().is_some() {
+            return Err(String::from("Only a chain holding just its genesis block can be bootstrapped"));
+        }
+        snapshot.verify(&self.config)?;
+        snapshot.save(&self.data_dir.join(SNAPSHOT_FILE))?;
+
+        let mut work = snapshot.chain_work;
+        let mut block_work = Vec::new();
+        for block in snapshot.blocks.iter().rev() {
+            block_work.push((block.hash(), work));
+            work = work.saturating_sub(pow::block_work(block.difficulty));
+        }
+        let stale_keys = |tree: &Tree| -> Vec<IVec> { tree.iter().keys().filter_map(|k| k.ok()).collect() };
+        let stale = [&self.utxos, &self.undo, &self.tx_index, &self.address_index, &self.mempool].map(stale_keys);
+
+        let result: Result<(), TransactionError<String>> = (
+            &self.blocks, &self.block_work, &self.best_chain, &self.meta,
+            &self.utxos, &self.undo, &self.tx_index, &self.address_index, &self.mempool,
+        )
+            .transaction(|(blocks, works, best_chain, meta, utxos, undo, tx_index, address_index, mempool)| {
+                for (tree, keys) in [utxos, undo, tx_index, address_index, mempool].into_iter().zip(&stale) {
+                    for key in keys {
+                        tree.remove(key)?;
+                    }
+                }
+                for (block, (hash, work)) in snapshot.blocks.iter().zip(block_work.iter().rev()) {
+                    blocks.insert(hash.as_bytes(), block.to_bytes())?;
+                    works.insert(hash.as_bytes(), &work.to_be_bytes())?;
+                    best_chain.insert(&block.index.to_be_bytes(), hash.as_bytes())?;
+                    index_block(tx_index, address_index, block)?;
+                }
+                // Relative timelocks look up when the spent output was mined.
+                for utxo in &snapshot.utxos {
+                    utxos.insert(utxo.outpoint.as_bytes(), serde_json::to_vec(&utxo.output).unwrap())?;
+                    if let Some((txid, _)) = utxo.outpoint.rsplit_once(':') {
+                        tx_index.insert(txid.as_bytes(), &utxo.height.to_be_bytes())?;
+                    }
+                }
+                meta.insert(TIP_KEY, snapshot.block_hash.as_bytes())?;
+                meta.insert(SNAPSHOT_KEY, &snapshot.height.to_be_bytes())?;
+                Ok(())
+            });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.to_string(),
+        })?;
+        self.db.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Every block of the best chain, from genesis.
     pub fn get_chain(&self) -> Vec<Block> {
-        self.db.iter()
+        self.blocks_from(0, usize::MAX)
+    }
+
+    /// Up to `limit` consecutive best-chain blocks starting at index `from`.
+    pub fn blocks_from(&self, from: u64, limit: usize) -> Vec<Block> {
+        self.best_chain.range(from.to_be_bytes()..)
+            .take(limit)
             .filter_map(|res| res.ok())
-            .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+            .filter_map(|(_, hash)| self.get_block_by_hash(std::str::from_utf8(&hash).ok()?))
             .collect()
     }
-}
\ No newline at end of file
+
+    /// Total proof of work behind the best chain.
+    pub fn chain_work(&self) -> u128 {
+        self.get_last_block().and_then(|tip| self.total_work(&tip.hash())).unwrap_or(0)
+    }
+
+    /// Drops pending transactions that no longer apply on top of the tip.
+    pub fn revalidate_mempool(&mut self) -> Result<(), String> {
+        self.requeue_transactions(Vec::new())
+    }
+
+    /// Rebuilds the mempool from its current contents plus the transactions
+    /// of `abandoned` blocks, dropping anything no longer spendable and, past
+    /// the size limit, the lowest fee rates. The new contents replace the old
+    /// in one batch.
+    fn requeue_transactions(&mut self, abandoned: Vec<Block>) -> Result<(), String> {
+        let mut candidates: Vec<Transaction> = abandoned.into_iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .collect();
+        candidates.extend(self.pending_transactions().into_iter().map(|(_, tx)| tx));
+
+        let height = self.get_last_block().ok_or("Chain has no tip")?.index + 1;
+        let mut reserved = HashSet::new();
+        let mut entries = Vec::new();
+        for tx in candidates {
+            if let Ok(entry) = self.check_pending(tx, &reserved, height)? {
+                reserved.extend(entry.transaction.inputs.iter().map(TxInput::outpoint));
+                entries.push(entry);
+            }
+        }
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.fee_rate()));
+        entries.truncate(MAX_MEMPOOL_TRANSACTIONS);
+
+        let mut batch = sled::Batch::default();
+        for key in self.mempool.iter().keys() {
+            batch.remove(key.map_err(|e| e.to_string())?);
+        }
+        for entry in entries {
+            let sequence = self.db.generate_id().map_err(|e| e.to_string())?;
+            batch.insert(entry.key(sequence), entry.to_bytes());
+        }
+        self.mempool.apply_batch(batch).map_err(|e| e.to_string())?;
+        self.db.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Trees that change when blocks join or leave the best chain, as seen from
+/// inside one sled transaction.
+struct ChainState<'a> {
+    best_chain: &'a TransactionalTree,
+    undo: &'a TransactionalTree,
+    utxos: &'a TransactionalTree,
+    tx_index: &'a TransactionalTree,
+    address_index: &'a TransactionalTree,
+    meta: &'a TransactionalTree,
+}
+
+/// Applies `block` on top of the best chain: updates the UTXO set, records
+/// the undo data and explorer indexes, and moves the tip pointer to it.
+fn connect_block(config: &ChainConfig, state: &ChainState, block: &Block) -> Result<(), ConflictableTransactionError<String>> {
+    let hash = block.hash();
+    let spent = apply_block(config, state.utxos, state.tx_index, block)?;
+    state.undo.insert(hash.as_bytes(), serde_json::to_vec(&spent).unwrap())?;
+    state.best_chain.insert(&block.index.to_be_bytes(), hash.as_bytes())?;
+    state.meta.insert(TIP_KEY, hash.as_bytes())?;
+    index_block(state.tx_index, state.address_index, block)
+}
+
+/// Rolls the best chain's tip `block` back: removes the outputs it created,
+/// restores the ones it spent and moves the tip pointer to its parent.
+fn disconnect_block(state: &ChainState, block: &Block) -> Result<(), ConflictableTransactionError<String>> {
+    let hash = block.hash();
+    let spent: BlockUndo = match state.undo.remove(hash.as_bytes())? {
+        Some(v) => serde_json::from_slice(&v).unwrap(),
+        None => return abort(format!("Block {} has no undo data", block.index)),
+    };
+    for (tx, spent) in block.transactions.iter().zip(spent).rev() {
+        for vout in 0..tx.outputs.len() {
+            state.utxos.remove(outpoint_key(&tx.id, vout as u32).as_bytes())?;
+        }
+        for (outpoint, output) in spent {
+            state.utxos.insert(outpoint.as_bytes(), serde_json::to_vec(&output).unwrap())?;
+        }
+    }
+    state.best_chain.remove(&block.index.to_be_bytes())?;
+    state.meta.insert(TIP_KEY, block.previous_hash.as_bytes())?;
+
+    for (position, tx) in block.transactions.iter().enumerate() {
+        state.tx_index.remove(tx.id.as_bytes())?;
+        for address in involved_addresses(tx) {
+            state.address_index.remove(address_key(&address, block.index, position))?;
+        }
+    }
+    Ok(())
+}
+
+fn index_block(
+    txs: &TransactionalTree,
+    addresses: &TransactionalTree,
+    block: &Block,
+) -> Result<(), ConflictableTransactionError<String>> {
+    let index = IVec::from(&block.index.to_be_bytes()[..]);
+    for (position, tx) in block.transactions.iter().enumerate() {
+        txs.insert(tx.id.as_bytes(), index.clone())?;
+        for address in involved_addresses(tx) {
+            addresses.insert(address_key(&address, block.index, position), tx.id.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Addresses `tx` pays or spends from.
+fn involved_addresses(tx: &Transaction) -> BTreeSet<String> {
+    let spenders = tx.inputs.iter().filter_map(TxInput::signer_address);
+    tx.outputs.iter().map(|o| o.address.clone()).chain(spenders).collect()
+}
+
+/// Address index key: the address followed by the block index and the
+/// transaction's position in it, so a prefix scan yields history in order.
+fn address_key(address: &str, block_index: u64, position: usize) -> Vec<u8> {
+    let mut key = address.as_bytes().to_vec();
+    key.extend_from_slice(&block_index.to_be_bytes());
+    key.extend_from_slice(&(position as u32).to_be_bytes());
+    key
+}
+
+/// Decodes every JSON block in `tree`, checking each key ends with the hash
+/// of its block; orphan keys are prefixed with their arrival sequence.
+fn decode_json_blocks(tree: &Tree) -> Result<Vec<(IVec, Block)>, String> {
+    let mut blocks = Vec::new();
+    for entry in tree.iter() {
+        let (key, value) = entry.map_err(|e| e.to_string())?;
+        let key_text = String::from_utf8_lossy(&key).into_owned();
+        let block: Block = serde_json::from_slice(&value)
+            .map_err(|e| format!("Corrupt block {}: {}", key_text, e))?;
+        if !key.ends_with(block.hash().as_bytes()) {
+            return Err(format!("Block stored under {} hashes to {}", key_text, block.hash()));
+        }
+        blocks.push((key, block));
+    }
+    Ok(blocks)
+}
+
+fn decode_index(bytes: &[u8]) -> u64 {
+    let mut index = [0u8; 8];
+    index.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(index)
+}
+
+/// The stored UTXO set inside a sled transaction. Outputs do not record the
+/// height they were mined at, so it is looked up in the transaction index;
+/// outputs created earlier in the block being applied are not indexed yet.
+struct StoredUtxos<'a> {
+    utxos: &'a TransactionalTree,
+    tx_index: &'a TransactionalTree,
+}
+
+impl UtxoSet for StoredUtxos<'_> {
+    type Error = UnabortableTransactionError;
+
+    fn spend(&mut self, input: &TxInput, height: u64) -> Result<Option<(TxOutput, u64)>, Self::Error> {
+        let Some(v) = self.utxos.remove(input.outpoint().as_bytes())? else {
+            return Ok(None);
+        };
+        let created = self.tx_index.get(input.txid.as_bytes())?.map_or(height, |v| decode_index(&v));
+        Ok(Some((serde_json::from_slice(&v).unwrap(), created)))
+    }
+
+    fn create(&mut self, outpoint: String, output: &TxOutput, _height: u64) -> Result<bool, Self::Error> {
+        Ok(self.utxos.insert(outpoint.as_bytes(), serde_json::to_vec(output).unwrap())?.is_none())
+    }
+}
+
+/// The stored UTXO set as seen by a transaction waiting for the next block:
+/// its spends and outputs are checked but never written.
+struct PendingUtxos<'a> {
+    chain: &'a Blockchain,
+    spent: HashSet<String>,
+}
+
+impl UtxoSet for PendingUtxos<'_> {
+    type Error = String;
+
+    fn spend(&mut self, input: &TxInput, height: u64) -> Result<Option<(TxOutput, u64)>, String> {
+        let outpoint = input.outpoint();
+        if !self.spent.insert(outpoint.clone()) {
+            return Ok(None);
+        }
+        let output = self.chain.utxos.get(outpoint.as_bytes())
+            .map_err(|e| e.to_string())?
+            .and_then(|v| serde_json::from_slice::<TxOutput>(&v).ok());
+        Ok(output.map(|output| (output, self.chain.mined_height(&input.txid).unwrap_or(height))))
+    }
+
+    fn create(&mut self, outpoint: String, _output: &TxOutput, _height: u64) -> Result<bool, String> {
+        Ok(!self.chain.utxos.contains_key(outpoint.as_bytes()).map_err(|e| e.to_string())?)
+    }
+}
+
+/// Applies the transactions of `block` to the UTXO set, aborting if they
+/// break a rule of `validation::apply_transactions`. Returns the outputs
+/// each transaction spent.
+fn apply_block(
+    config: &ChainConfig,
+    utxos: &TransactionalTree,
+    tx_index: &TransactionalTree,
+    block: &Block,
+) -> Result<BlockUndo, ConflictableTransactionError<String>> {
+    validation::apply_transactions(config, block, &mut StoredUtxos { utxos, tx_index }).map_err(|e| match e {
+        ApplyError::Invalid(violation) => ConflictableTransactionError::Abort(format!("Block {}: {}", block.index, violation)),
+        ApplyError::Storage(e) => e.into(),
+    })
+}
+
+fn abort<T>(reason: String) -> Result<T, ConflictableTransactionError<String>> {
+    Err(ConflictableTransactionError::Abort(reason))
+}
+
+/// Attaches the hash of the block being applied to an abort reason.
+fn tag_abort(
+    error: ConflictableTransactionError<String>,
+    block_hash: Option<String>,
+) -> ConflictableTransactionError<(Option<String>, String)> {
+    match error {
+        ConflictableTransactionError::Abort(reason) => ConflictableTransactionError::Abort((block_hash, reason)),
+        ConflictableTransactionError::Storage(e) => ConflictableTransactionError::Storage(e),
+        ConflictableTransactionError::Conflict => ConflictableTransactionError::Conflict,
+    }
+}
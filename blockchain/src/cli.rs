@@ -1,9 +1,78 @@
+use std::path::PathBuf;
 use structopt::StructOpt;
+use crate::amount::parse_amount;
+use crate::archive::ExportFormat;
+use crate::config::{parse_allocation, Config};
+use crate::consensus::ConsensusMode;
+use crate::transaction::TxOutput;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "simple_blockchain")]
-pub enum Cli {
-    CreateWallet,
+pub struct Cli {
+    /// JSON file with the data directory and chain settings
+    #[structopt(long, global = true, env = "BLOCKCHAIN_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Directory holding the chain and keystore [default: data]
+    #[structopt(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    /// Chain to create or open [default: main]
+    #[structopt(long, global = true)]
+    pub chain_id: Option<String>,
+    /// Initial difficulty in leading zero bits; only used for a new chain
+    #[structopt(long, global = true)]
+    pub difficulty: Option<u32>,
+    /// Block reward in coins before halvings; only used for a new chain
+    #[structopt(long, global = true, parse(try_from_str = parse_amount))]
+    pub block_reward: Option<u64>,
+    /// Pre-fund ADDRESS=AMOUNT in the genesis block of a new chain; may be repeated
+    #[structopt(long = "allocate", global = true, number_of_values = 1, parse(try_from_str = parse_allocation))]
+    pub allocations: Vec<TxOutput>,
+    /// Run a new chain under proof of authority, with this hex public key among
+    /// the validators taking turns to sign blocks; may be repeated
+    #[structopt(long = "validator", global = true, number_of_values = 1)]
+    pub validators: Vec<String>,
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// Settings from `--config` (or the defaults) overridden by any flags.
+    pub fn config(&self) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(chain_id) = &self.chain_id {
+            config.chain.chain_id = chain_id.clone();
+        }
+        if let Some(difficulty) = self.difficulty {
+            config.chain.pow.initial_difficulty = difficulty;
+        }
+        if let Some(block_reward) = self.block_reward {
+            config.chain.block_reward = block_reward;
+        }
+        if !self.allocations.is_empty() {
+            config.chain.genesis_allocations = self.allocations.clone();
+        }
+        if !self.validators.is_empty() {
+            config.chain.consensus = ConsensusMode::ProofOfAuthority { validators: self.validators.clone() };
+        }
+        Ok(config)
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    CreateWallet {
+        /// Passphrase used to encrypt the new wallet's key in the keystore
+        #[structopt(short, long, env = "WALLET_PASSPHRASE", hide_env_values = true)]
+        passphrase: String,
+    },
+    /// List local wallets with their public keys
+    ListWallets,
     GetBalance {
         #[structopt(short, long)]
         address: String,
@@ -13,9 +82,180 @@ pub enum Cli {
         from: String,
         #[structopt(short, long)]
         to: String,
+        /// Amount in coins, up to 8 decimal places
+        #[structopt(short, long, parse(try_from_str = parse_amount))]
+        amount: u64,
+        /// Fee paid to the miner, in coins
+        #[structopt(long, default_value = "0.0001", parse(try_from_str = parse_amount))]
+        fee: u64,
+        /// Passphrase unlocking the `--from` wallet in the keystore
+        #[structopt(short, long, env = "WALLET_PASSPHRASE", hide_env_values = true)]
+        passphrase: String,
+    },
+    /// Create an m-of-n multisig address, optionally timelocked, and save its spending condition
+    CreateMultisig {
+        /// Signatures needed to spend
+        #[structopt(short = "m", long)]
+        threshold: u32,
+        /// Hex public key, or the address of a wallet in the local keystore; repeat for each key
+        #[structopt(long = "key", required = true, number_of_values = 1)]
+        keys: Vec<String>,
+        /// Spendable only in blocks at or above this height
+        #[structopt(long, conflicts_with = "after-blocks")]
+        after_height: Option<u64>,
+        /// Spendable only once this many blocks are mined on top of the funds
+        #[structopt(long)]
+        after_blocks: Option<u64>,
+        /// File to save the spending condition to
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+    /// Start a spend from a multisig address, writing an unsigned transaction for its keys to sign
+    ProposeSpend {
+        /// File holding the spending condition
+        #[structopt(short, long)]
+        condition: PathBuf,
+        #[structopt(short, long)]
+        to: String,
+        /// Amount in coins, up to 8 decimal places
+        #[structopt(short, long, parse(try_from_str = parse_amount))]
+        amount: u64,
+        /// Fee paid to the miner, in coins
+        #[structopt(long, default_value = "0.0001", parse(try_from_str = parse_amount))]
+        fee: u64,
+        /// File to write the transaction to
         #[structopt(short, long)]
-        amount: f64,
+        output: PathBuf,
+    },
+    /// Add a local wallet's signature to a transaction file
+    SignSpend {
+        #[structopt(short, long)]
+        file: PathBuf,
+        /// Wallet in the local keystore whose key is one of the condition's
+        #[structopt(short, long)]
+        address: String,
+        #[structopt(short, long, env = "WALLET_PASSPHRASE", hide_env_values = true)]
+        passphrase: String,
+    },
+    /// Submit a transaction file once it has collected enough signatures
+    BroadcastSpend {
+        #[structopt(short, long)]
+        file: PathBuf,
+    },
+    Mine {
+        /// Address receiving the block subsidy and fees
+        #[structopt(short, long)]
+        address: String,
+        /// Number of blocks to mine in a row
+        #[structopt(long, default_value = "1")]
+        blocks: u64,
+        /// Worker threads searching for a nonce [default: one per CPU]
+        #[structopt(long)]
+        threads: Option<usize>,
+        /// Passphrase unlocking the `--address` wallet, which signs blocks on a proof-of-authority chain
+        #[structopt(short, long, env = "WALLET_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
     },
-    Mine,
     PrintChain,
-}
\ No newline at end of file
+    /// Show a block by index or hash
+    ShowBlock {
+        #[structopt(short, long, required_unless = "hash", conflicts_with = "hash")]
+        index: Option<u64>,
+        #[structopt(long)]
+        hash: Option<String>,
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Show a mined transaction and the block containing it
+    ShowTx {
+        #[structopt(short, long)]
+        txid: String,
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// List every mined transaction paying or spending from an address
+    AddressHistory {
+        #[structopt(short, long)]
+        address: String,
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Print the merkle path proving a transaction is in a block
+    ProveTx {
+        #[structopt(short, long)]
+        txid: String,
+    },
+    /// Check every stored block for tampering
+    ValidateChain,
+    /// Convert a JSON-encoded block store to the binary format, verifying the chain
+    MigrateStore,
+    /// Write the best chain to a portable file
+    ExportChain {
+        #[structopt(short, long)]
+        output: PathBuf,
+        /// json (one block per line) or binary
+        #[structopt(long, default_value = "json")]
+        format: ExportFormat,
+    },
+    /// Validate and add the blocks of an exported chain file
+    ImportChain {
+        #[structopt(short, long)]
+        input: PathBuf,
+    },
+    /// Write the UTXO set at a height so a new node can bootstrap from it
+    ExportSnapshot {
+        #[structopt(short, long)]
+        output: PathBuf,
+        /// Height of the snapshot [default: the tip]
+        #[structopt(long)]
+        height: Option<u64>,
+    },
+    /// Bootstrap a new chain from a snapshot instead of replaying every block
+    ImportSnapshot {
+        #[structopt(short, long)]
+        input: PathBuf,
+    },
+    /// Run a peer-to-peer node that syncs and gossips blocks and transactions
+    Node {
+        #[structopt(short, long, default_value = "127.0.0.1:7000")]
+        listen: String,
+        /// Address of a peer node; may be repeated
+        #[structopt(long = "peer")]
+        peers: Vec<String>,
+        /// Mine continuously, paying rewards to this address; on a proof-of-authority
+        /// chain, sign blocks in turn with this validator wallet instead
+        #[structopt(long = "mine", name = "ADDRESS")]
+        miner_address: Option<String>,
+        /// Passphrase unlocking the `--mine` wallet on a proof-of-authority chain
+        #[structopt(short, long, env = "WALLET_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+    },
+    /// Run a node and report new blocks and transactions of watched addresses as they confirm
+    Watch {
+        /// Address whose transactions are reported; may be repeated
+        #[structopt(short, long = "address", number_of_values = 1)]
+        addresses: Vec<String>,
+        /// Report transactions again once buried this many blocks deep
+        #[structopt(long, default_value = "6")]
+        confirmations: u64,
+        /// URL to POST each event to as JSON
+        #[structopt(long)]
+        webhook: Option<String>,
+        #[structopt(short, long, default_value = "127.0.0.1:7000")]
+        listen: String,
+        /// Address of a peer node; may be repeated
+        #[structopt(long = "peer")]
+        peers: Vec<String>,
+    },
+    /// Serve wallets, transactions and chain queries as a JSON HTTP API
+    Serve {
+        #[structopt(short, long, default_value = "127.0.0.1:8000")]
+        listen: String,
+        /// Number of worker threads handling requests
+        #[structopt(long, default_value = "4")]
+        workers: usize,
+    },
+}
@@ -0,0 +1,179 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::amount::{self, COIN};
+use crate::block::{Block, BLOCK_VERSION};
+use crate::consensus::{Consensus, ConsensusMode, ProofOfAuthority, ProofOfWork};
+use crate::pow::PowParams;
+use crate::transaction::{Transaction, TxInput, TxOutput};
+use crate::wallet;
+
+/// File inside the data directory recording the settings the chain was
+/// created with.
+const CHAIN_CONFIG_FILE: &str = "chain.json";
+
+/// Consensus settings of one chain. They are fixed when the genesis block is
+/// created and stored next to the chain, so every later run uses the same
+/// values regardless of flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainConfig {
+    /// Name distinguishing e.g. a test chain from staging; nodes only sync
+    /// with peers on the same chain.
+    pub chain_id: String,
+    pub genesis_timestamp: i64,
+    /// Outputs minted by the genesis block to pre-fund addresses.
+    pub genesis_allocations: Vec<TxOutput>,
+    /// Base units paid to the miner of each block before any halving.
+    pub block_reward: u64,
+    /// The reward halves every this many blocks.
+    pub halving_interval: u64,
+    #[serde(flatten)]
+    pub pow: PowParams,
+    pub consensus: ConsensusMode,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            chain_id: String::from("main"),
+            genesis_timestamp: 1_700_000_000,
+            genesis_allocations: Vec::new(),
+            block_reward: 10 * COIN,
+            halving_interval: 100_000,
+            pow: PowParams::default(),
+            consensus: ConsensusMode::default(),
+        }
+    }
+}
+
+impl ChainConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chain_id.is_empty() {
+            return Err(String::from("Chain id must not be empty"));
+        }
+        if self.halving_interval == 0 {
+            return Err(String::from("Halving interval must be positive"));
+        }
+        for allocation in &self.genesis_allocations {
+            if allocation.amount == 0 || !wallet::is_valid_address(&allocation.address) {
+                return Err(format!("Invalid genesis allocation to {}", allocation.address));
+            }
+        }
+        amount::checked_sum(self.genesis_allocations.iter().map(|a| a.amount))
+            .map_err(|_| String::from("Genesis allocations overflow"))?;
+        self.consensus.validate()
+    }
+
+    /// The rules blocks of this chain are produced under.
+    pub fn consensus(&self) -> Box<dyn Consensus + '_> {
+        match &self.consensus {
+            ConsensusMode::ProofOfWork => Box::new(ProofOfWork { params: &self.pow }),
+            ConsensusMode::ProofOfAuthority { validators } => Box::new(ProofOfAuthority { validators }),
+        }
+    }
+
+    /// The validator set, if this is a proof-of-authority chain.
+    pub fn authority(&self) -> Option<ProofOfAuthority<'_>> {
+        match &self.consensus {
+            ConsensusMode::ProofOfWork => None,
+            ConsensusMode::ProofOfAuthority { validators } => Some(ProofOfAuthority { validators }),
+        }
+    }
+
+    /// Block reward at `height`, halving every `halving_interval` blocks.
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+        self.block_reward.checked_shr(halvings.try_into().unwrap_or(u32::MAX)).unwrap_or(0)
+    }
+
+    /// The genesis block is fully deterministic so that independently started
+    /// nodes of the same chain agree on it. Allocations are minted by a
+    /// coinbase, and the chain id takes the place of the previous hash so
+    /// different chains never share a genesis block.
+    pub fn genesis_block(&self) -> Block {
+        self.genesis_block_at(BLOCK_VERSION)
+    }
+
+    /// The genesis block as created under encoding `version`, so chains
+    /// started before the binary encoding keep their original genesis.
+    pub fn genesis_block_at(&self, version: u8) -> Block {
+        let mut transactions = Vec::new();
+        if !self.genesis_allocations.is_empty() {
+            let mut allocation = Transaction::new(
+                vec![TxInput::new(String::new(), 0)],
+                self.genesis_allocations.clone(),
+            );
+            allocation.version = version;
+            allocation.id = allocation.hash();
+            transactions.push(allocation);
+        }
+        let difficulty = self.consensus().next_difficulty(0, 0, &|_| None);
+        let mut block = Block::new(0, transactions, self.chain_id.clone(), difficulty);
+        block.version = version;
+        block.timestamp = self.genesis_timestamp;
+        block
+    }
+
+    /// Loads the settings stored in `data_dir`, or stores `self` there if the
+    /// directory holds no chain yet. Opening another chain's directory is an
+    /// error.
+    pub fn load_or_store(&self, data_dir: &Path) -> Result<ChainConfig, String> {
+        let path = data_dir.join(CHAIN_CONFIG_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let stored: ChainConfig = serde_json::from_slice(&bytes)
+                    .map_err(|e| format!("Corrupt chain config {}: {}", path.display(), e))?;
+                if stored.chain_id != self.chain_id {
+                    return Err(format!(
+                        "{} holds chain {:?}, not {:?}",
+                        data_dir.display(), stored.chain_id, self.chain_id,
+                    ));
+                }
+                Ok(stored)
+            }
+            Err(_) => {
+                self.validate()?;
+                let encoded = serde_json::to_vec_pretty(self).unwrap();
+                fs::write(&path, encoded).map_err(|e| e.to_string())?;
+                Ok(self.clone())
+            }
+        }
+    }
+}
+
+/// Node settings: where data lives plus the chain to create there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    #[serde(flatten)]
+    pub chain: ChainConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from("data"),
+            chain: ChainConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads a JSON config file; missing fields take their defaults.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+}
+
+/// Parses a genesis allocation given as `ADDRESS=AMOUNT`, the amount in coins.
+pub fn parse_allocation(input: &str) -> Result<TxOutput, String> {
+    let (address, amount) = input.split_once('=')
+        .ok_or_else(|| format!("Allocation {:?} is not ADDRESS=AMOUNT", input))?;
+    if !wallet::is_valid_address(address) {
+        return Err(format!("Allocation address {} is invalid", address));
+    }
+    Ok(TxOutput { amount: amount::parse_amount(amount)?, address: address.to_string() })
+}
@@ -0,0 +1,123 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use crate::block::{Block, SEAL_VERSION};
+use crate::pow::{self, PowParams};
+use crate::wallet::{self, Wallet};
+
+/// How a chain decides who may add the next block. Chosen when the genesis
+/// block is created and stored with the rest of the chain config.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ConsensusMode {
+    /// Anyone may mine a block by finding a nonce meeting the difficulty.
+    #[default]
+    ProofOfWork,
+    /// The listed validators, by hex public key, take turns signing blocks:
+    /// the block at height `h` must be signed by validator `(h - 1) % n`.
+    ProofOfAuthority { validators: Vec<String> },
+}
+
+impl ConsensusMode {
+    pub fn validate(&self) -> Result<(), String> {
+        if let ConsensusMode::ProofOfAuthority { validators } = self {
+            if validators.is_empty() {
+                return Err(String::from("Proof of authority needs at least one validator"));
+            }
+            let mut seen = HashSet::new();
+            for key in validators {
+                if hex::decode(key).map_or(true, |bytes| bytes.len() != 32) || key.to_lowercase() != *key {
+                    return Err(format!("Validator key {} is not 32 lowercase hex bytes", key));
+                }
+                if !seen.insert(key) {
+                    return Err(format!("Validator key {} is listed twice", key));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The rules a block's producer must follow, checked on top of the
+/// consensus-independent rules in `validation`.
+pub trait Consensus {
+    /// Difficulty the block at `height` must carry, given its parent's and a
+    /// lookup of earlier timestamps on the same branch.
+    fn next_difficulty(&self, height: u64, last_difficulty: u32, timestamp_at: &dyn Fn(u64) -> Option<i64>) -> u32;
+
+    /// Checks that `block` was produced by someone entitled to: enough work
+    /// for proof of work, the in-turn validator's signature for proof of
+    /// authority. The genesis block is not sealed and is never checked.
+    /// Errors describe the problem without naming the block.
+    fn check_seal(&self, block: &Block) -> Result<(), String>;
+}
+
+pub struct ProofOfWork<'a> {
+    pub params: &'a PowParams,
+}
+
+impl Consensus for ProofOfWork<'_> {
+    fn next_difficulty(&self, height: u64, last_difficulty: u32, timestamp_at: &dyn Fn(u64) -> Option<i64>) -> u32 {
+        pow::next_difficulty(self.params, height, last_difficulty, timestamp_at)
+    }
+
+    fn check_seal(&self, block: &Block) -> Result<(), String> {
+        if !block.validator.is_empty() || !block.signature.is_empty() {
+            return Err(String::from("a validator seal is not allowed on a proof-of-work chain"));
+        }
+        if !pow::valid_proof(block) {
+            return Err(String::from("proof of work is not valid"));
+        }
+        Ok(())
+    }
+}
+
+/// Blocks carry difficulty 0, so each adds one unit of work and the longest
+/// branch is the best one.
+pub struct ProofOfAuthority<'a> {
+    pub validators: &'a [String],
+}
+
+impl ProofOfAuthority<'_> {
+    /// Public key of the validator whose turn it is to sign the block at
+    /// `height`.
+    pub fn in_turn(&self, height: u64) -> &str {
+        let turn = height.saturating_sub(1) % self.validators.len() as u64;
+        &self.validators[turn as usize]
+    }
+
+    /// Seals `block` with `validator`'s key, which must be the one in turn.
+    pub fn sign(&self, block: &mut Block, validator: &Wallet) -> Result<(), String> {
+        let key = validator.public_key_hex();
+        if !self.validators.contains(&key) {
+            return Err(format!("Wallet {} is not a validator", validator.address));
+        }
+        if key != self.in_turn(block.index) {
+            return Err(format!("Block {} is not wallet {}'s turn to sign", block.index, validator.address));
+        }
+        block.validator = key;
+        block.signature = validator.sign(&block.header_digest());
+        Ok(())
+    }
+}
+
+impl Consensus for ProofOfAuthority<'_> {
+    fn next_difficulty(&self, _height: u64, _last_difficulty: u32, _timestamp_at: &dyn Fn(u64) -> Option<i64>) -> u32 {
+        0
+    }
+
+    fn check_seal(&self, block: &Block) -> Result<(), String> {
+        if block.version < SEAL_VERSION {
+            return Err(String::from("block version predates validator seals"));
+        }
+        if !self.validators.contains(&block.validator) {
+            return Err(String::from("block is not signed by a validator"));
+        }
+        if block.validator != self.in_turn(block.index) {
+            return Err(format!("block is signed out of turn; validator {} was due", self.in_turn(block.index)));
+        }
+        if !wallet::verify_signature(&block.validator, &block.header_digest(), &block.signature) {
+            return Err(String::from("validator signature is not valid"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use crate::block::{Block, SEAL_VERSION};
+use crate::mempool::MempoolEntry;
+use crate::transaction::{KeySignature, SpendingCondition, Timelock, Transaction, TxInput, TxOutput, CONDITION_VERSION};
+
+/// Builds the canonical binary form of a value: fields in declaration order,
+/// integers as fixed-width big-endian, strings and sequences prefixed by
+/// their length as a `u32`. Every value has exactly one encoding, so the
+/// bytes can be hashed as well as stored.
+#[derive(Debug, Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn i64(&mut self, value: i64) -> &mut Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.len(value.len()).bytes.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    pub fn seq<T: Encode>(&mut self, items: &[T]) -> &mut Self {
+        self.seq_with(items, |writer, item| item.encode(writer))
+    }
+
+    /// Writes a sequence whose items are encoded by `encode`.
+    pub fn seq_with<T>(&mut self, items: &[T], mut encode: impl FnMut(&mut Writer, &T)) -> &mut Self {
+        self.len(items.len());
+        for item in items {
+            encode(self, item);
+        }
+        self
+    }
+
+    /// A tag byte, 0 for `None` and 1 for `Some`, followed by the value.
+    pub fn option<T: Encode>(&mut self, value: Option<&T>) -> &mut Self {
+        match value {
+            None => self.u8(0),
+            Some(value) => {
+                self.u8(1);
+                value.encode(self);
+                self
+            }
+        }
+    }
+
+    fn len(&mut self, len: usize) -> &mut Self {
+        self.u32(u32::try_from(len).expect("encoded length exceeds u32"))
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads values written by `Writer`, failing on truncated input rather than
+/// panicking so corrupt or hostile bytes are safe to decode.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if n > self.bytes.len() {
+            return Err(format!("Unexpected end of input: need {} bytes, {} left", n, self.bytes.len()));
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        self.array().map(u64::from_be_bytes)
+    }
+
+    pub fn i64(&mut self) -> Result<i64, String> {
+        self.array().map(i64::from_be_bytes)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("String is not valid UTF-8"))
+    }
+
+    pub fn seq<T: Decode>(&mut self) -> Result<Vec<T>, String> {
+        self.seq_with(T::decode)
+    }
+
+    /// Reads a sequence whose items are decoded by `decode`.
+    pub fn seq_with<T>(&mut self, mut decode: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let len = self.u32()? as usize;
+        // Every item takes at least one byte, which bounds the allocation by
+        // the input size whatever the length prefix claims.
+        let mut items = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            items.push(decode(self)?);
+        }
+        Ok(items)
+    }
+
+    pub fn option<T: Decode>(&mut self) -> Result<Option<T>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => T::decode(self).map(Some),
+            tag => Err(format!("Invalid option tag {}", tag)),
+        }
+    }
+
+    /// Fails if input remains, so each value has only one accepted encoding.
+    pub fn finish(self) -> Result<(), String> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            n => Err(format!("{} trailing bytes after value", n)),
+        }
+    }
+}
+
+pub trait Encode {
+    fn encode(&self, writer: &mut Writer);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        self.encode(&mut writer);
+        writer.into_bytes()
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, String>;
+
+    /// Decodes a value that must span all of `bytes`.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(value)
+    }
+}
+
+impl Encode for TxInput {
+    fn encode(&self, writer: &mut Writer) {
+        writer.str(&self.txid).u32(self.vout).str(&self.public_key).str(&self.signature);
+    }
+}
+
+impl Decode for TxInput {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        Ok(TxInput {
+            txid: reader.string()?,
+            vout: reader.u32()?,
+            public_key: reader.string()?,
+            signature: reader.string()?,
+            condition: None,
+            signatures: Vec::new(),
+        })
+    }
+}
+
+/// Inputs of transactions from `CONDITION_VERSION` on are followed by the
+/// condition they reveal and its signatures.
+fn encode_input(writer: &mut Writer, input: &TxInput, version: u8) {
+    input.encode(writer);
+    if version >= CONDITION_VERSION {
+        writer.option(input.condition.as_ref()).seq(&input.signatures);
+    }
+}
+
+fn decode_input(reader: &mut Reader, version: u8) -> Result<TxInput, String> {
+    let mut input = TxInput::decode(reader)?;
+    if version >= CONDITION_VERSION {
+        input.condition = reader.option()?;
+        input.signatures = reader.seq()?;
+    }
+    Ok(input)
+}
+
+impl Encode for Timelock {
+    fn encode(&self, writer: &mut Writer) {
+        match *self {
+            Timelock::AfterHeight(height) => writer.u8(0).u64(height),
+            Timelock::AfterBlocks(blocks) => writer.u8(1).u64(blocks),
+        };
+    }
+}
+
+impl Decode for Timelock {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        match reader.u8()? {
+            0 => Ok(Timelock::AfterHeight(reader.u64()?)),
+            1 => Ok(Timelock::AfterBlocks(reader.u64()?)),
+            tag => Err(format!("Invalid timelock tag {}", tag)),
+        }
+    }
+}
+
+impl Encode for SpendingCondition {
+    fn encode(&self, writer: &mut Writer) {
+        writer
+            .u32(self.threshold)
+            .seq_with(&self.public_keys, |writer, key| { writer.str(key); })
+            .option(self.timelock.as_ref());
+    }
+}
+
+impl Decode for SpendingCondition {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        Ok(SpendingCondition {
+            threshold: reader.u32()?,
+            public_keys: reader.seq_with(Reader::string)?,
+            timelock: reader.option()?,
+        })
+    }
+}
+
+impl Encode for KeySignature {
+    fn encode(&self, writer: &mut Writer) {
+        writer.str(&self.public_key).str(&self.signature);
+    }
+}
+
+impl Decode for KeySignature {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        Ok(KeySignature { public_key: reader.string()?, signature: reader.string()? })
+    }
+}
+
+impl Encode for TxOutput {
+    fn encode(&self, writer: &mut Writer) {
+        writer.u64(self.amount).str(&self.address);
+    }
+}
+
+impl Decode for TxOutput {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        Ok(TxOutput { amount: reader.u64()?, address: reader.string()? })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, writer: &mut Writer) {
+        writer
+            .u8(self.version)
+            .str(&self.id)
+            .seq_with(&self.inputs, |writer, input| encode_input(writer, input, self.version))
+            .seq(&self.outputs);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        let version = reader.u8()?;
+        Ok(Transaction {
+            version,
+            id: reader.string()?,
+            inputs: reader.seq_with(|reader| decode_input(reader, version))?,
+            outputs: reader.seq()?,
+        })
+    }
+}
+
+/// The version byte leads, followed by the header fields, the seal from
+/// `SEAL_VERSION` on, and then the transactions.
+impl Encode for Block {
+    fn encode(&self, writer: &mut Writer) {
+        writer
+            .u8(self.version)
+            .u64(self.index)
+            .i64(self.timestamp)
+            .str(&self.previous_hash)
+            .str(&self.merkle_root)
+            .u32(self.difficulty)
+            .u64(self.nonce);
+        if self.version >= SEAL_VERSION {
+            writer.str(&self.validator).str(&self.signature);
+        }
+        writer.seq(&self.transactions);
+    }
+}
+
+impl Decode for Block {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        let version = reader.u8()?;
+        let index = reader.u64()?;
+        let timestamp = reader.i64()?;
+        let previous_hash = reader.string()?;
+        let merkle_root = reader.string()?;
+        let difficulty = reader.u32()?;
+        let nonce = reader.u64()?;
+        let (validator, signature) = if version >= SEAL_VERSION {
+            (reader.string()?, reader.string()?)
+        } else {
+            (String::new(), String::new())
+        };
+        Ok(Block {
+            version,
+            index,
+            timestamp,
+            previous_hash,
+            merkle_root,
+            difficulty,
+            nonce,
+            validator,
+            signature,
+            transactions: reader.seq()?,
+        })
+    }
+}
+
+impl Encode for MempoolEntry {
+    fn encode(&self, writer: &mut Writer) {
+        self.transaction.encode(writer);
+        writer.u64(self.fee).u64(self.size);
+    }
+}
+
+impl Decode for MempoolEntry {
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        Ok(MempoolEntry {
+            transaction: Transaction::decode(reader)?,
+            fee: reader.u64()?,
+            size: reader.u64()?,
+        })
+    }
+}
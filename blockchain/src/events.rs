@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// A change to the best chain, as reported to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChainEvent {
+    /// A block became part of the best chain.
+    BlockConnected { index: u64, hash: String, transactions: usize },
+    /// A block left the best chain in a reorg; its transactions are
+    /// unconfirmed again until they are mined on the new branch.
+    BlockDisconnected { index: u64, hash: String },
+    /// A transaction paying or spending from a watched address reached
+    /// `confirmations`: reported once when it is mined and once more when
+    /// it is buried under the subscriber's required depth.
+    TransactionConfirmed {
+        txid: String,
+        address: String,
+        /// Base units paid to the address.
+        received: u64,
+        /// Base units spent from the address, including change paid back to it.
+        sent: u64,
+        block_index: u64,
+        block_hash: String,
+        confirmations: u64,
+    },
+}
+
+/// Which transactions a subscriber hears about. Block events are always
+/// delivered.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    /// Addresses whose transactions are reported; none if empty.
+    pub addresses: HashSet<String>,
+    /// Report transactions again once this many blocks include or follow
+    /// them; 0 or 1 reports them only when mined.
+    pub confirmations: u64,
+}
+
+impl EventFilter {
+    pub fn new(addresses: impl IntoIterator<Item = String>, confirmations: u64) -> Self {
+        EventFilter { addresses: addresses.into_iter().collect(), confirmations }
+    }
+}
+
+enum Sink {
+    Channel(Sender<ChainEvent>),
+    Callback(Box<dyn FnMut(&ChainEvent) + Send>),
+}
+
+/// A filter and where its matching events go.
+pub(crate) struct Subscriber {
+    pub(crate) filter: EventFilter,
+    sink: Sink,
+}
+
+impl Subscriber {
+    pub(crate) fn channel(filter: EventFilter, sender: Sender<ChainEvent>) -> Self {
+        Subscriber { filter, sink: Sink::Channel(sender) }
+    }
+
+    pub(crate) fn callback(filter: EventFilter, callback: impl FnMut(&ChainEvent) + Send + 'static) -> Self {
+        Subscriber { filter, sink: Sink::Callback(Box::new(callback)) }
+    }
+
+    /// Hands `event` to the subscriber, returning false once its receiver
+    /// has been dropped so it can be removed.
+    pub(crate) fn deliver(&mut self, event: &ChainEvent) -> bool {
+        match &mut self.sink {
+            Sink::Channel(sender) => sender.send(event.clone()).is_ok(),
+            Sink::Callback(callback) => {
+                callback(event);
+                true
+            }
+        }
+    }
+}
+
+/// POSTs events as JSON to a webhook URL.
+pub struct Webhook {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build();
+        Webhook { url, agent }
+    }
+
+    pub fn notify(&self, event: &ChainEvent) -> Result<(), String> {
+        self.agent
+            .post(&self.url)
+            .send_json(event)
+            .map(|_| ())
+            .map_err(|e| format!("Webhook {} failed: {}", self.url, e))
+    }
+}
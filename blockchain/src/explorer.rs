@@ -0,0 +1,66 @@
+use serde::Serialize;
+use crate::amount::{checked_sum, format_amount};
+use crate::block::Block;
+use crate::transaction::Transaction;
+
+/// One transaction in an address's history.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub block_index: u64,
+    pub block_hash: String,
+    pub timestamp: i64,
+    pub txid: String,
+    /// Base units paid to the address.
+    pub received: u64,
+    /// Base units spent from the address, including change paid back to it.
+    pub sent: u64,
+}
+
+pub fn print_block(block: &Block) {
+    println!("Block {}", block.index);
+    println!("  Hash:          {}", block.hash());
+    println!("  Previous hash: {}", block.previous_hash);
+    println!("  Merkle root:   {}", block.merkle_root);
+    println!("  Timestamp:     {}", block.timestamp);
+    println!("  Difficulty:    {}", block.difficulty);
+    println!("  Nonce:         {}", block.nonce);
+    println!();
+    println!("{:<64}  {:>6}  {:>7}  {:>20}", "TXID", "INPUTS", "OUTPUTS", "TOTAL OUT");
+    for tx in &block.transactions {
+        let total = checked_sum(tx.outputs.iter().map(|o| o.amount)).map_or_else(|e| e, format_amount);
+        println!("{:<64}  {:>6}  {:>7}  {:>20}", tx.id, tx.inputs.len(), tx.outputs.len(), total);
+    }
+}
+
+pub fn print_transaction(block: &Block, tx: &Transaction) {
+    println!("Transaction {}", tx.id);
+    println!("  Block: {} ({})", block.index, block.hash());
+    println!();
+    if tx.is_coinbase() {
+        println!("INPUTS: coinbase");
+    } else {
+        println!("{:<70}  SPENDER", "INPUT");
+        for input in &tx.inputs {
+            println!("{:<70}  {}", input.outpoint(), input.signer_address().unwrap_or_default());
+        }
+    }
+    println!();
+    println!("{:>4}  {:<40}  {:>20}", "VOUT", "ADDRESS", "AMOUNT");
+    for (vout, output) in tx.outputs.iter().enumerate() {
+        println!("{:>4}  {:<40}  {:>20}", vout, output.address, format_amount(output.amount));
+    }
+}
+
+pub fn print_history(address: &str, history: &[HistoryEntry]) {
+    println!("History of {} ({} transactions)", address, history.len());
+    println!("{:>8}  {:<64}  {:>20}  {:>20}", "BLOCK", "TXID", "RECEIVED", "SENT");
+    for entry in history {
+        println!(
+            "{:>8}  {:<64}  {:>20}  {:>20}",
+            entry.block_index,
+            entry.txid,
+            format_amount(entry.received),
+            format_amount(entry.sent),
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::wallet::Wallet;
+
+const KDF_ROUNDS: u32 = 100_000;
+
+/// Secret key of one wallet, encrypted with AES-256-GCM under a key derived
+/// from the owner's passphrase with PBKDF2-HMAC-SHA256.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedKey {
+    public_key: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Local file holding the encrypted keys of the wallets this node owns.
+pub struct Keystore {
+    path: PathBuf,
+    keys: BTreeMap<String, EncryptedKey>,
+}
+
+impl Keystore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let keys = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| format!("Corrupt keystore: {}", e))?,
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(Keystore { path, keys })
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.keys.contains_key(address)
+    }
+
+    pub fn public_key(&self, address: &str) -> Option<String> {
+        self.keys.get(address).map(|key| key.public_key.clone())
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.keys.keys().cloned().collect()
+    }
+
+    pub fn add(&mut self, wallet: &Wallet, passphrase: &str) -> Result<(), String> {
+        let mut rng = rand::thread_rng();
+        let salt: [u8; 16] = rng.gen();
+        let nonce: [u8; 12] = rng.gen();
+
+        let cipher = cipher_for(passphrase, &salt);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), wallet.secret_bytes().as_ref())
+            .map_err(|_| String::from("Failed to encrypt wallet key"))?;
+
+        self.keys.insert(wallet.address.clone(), EncryptedKey {
+            public_key: wallet.public_key_hex(),
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        });
+        self.save()
+    }
+
+    /// Decrypts the wallet for `address`, failing on an unknown address or a
+    /// wrong passphrase.
+    pub fn unlock(&self, address: &str, passphrase: &str) -> Result<Wallet, String> {
+        let entry = self.keys.get(address)
+            .ok_or_else(|| format!("Wallet {} is not in the keystore", address))?;
+        let salt = hex::decode(&entry.salt).map_err(|e| e.to_string())?;
+        let nonce = hex::decode(&entry.nonce).map_err(|e| e.to_string())?;
+        let ciphertext = hex::decode(&entry.ciphertext).map_err(|e| e.to_string())?;
+
+        let secret = cipher_for(passphrase, &salt)
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| format!("Wrong passphrase for wallet {}", address))?;
+        let secret: [u8; 32] = secret.try_into()
+            .map_err(|_| format!("Corrupt key for wallet {}", address))?;
+
+        let wallet = Wallet::from_secret(&secret);
+        if wallet.public_key_hex() != entry.public_key {
+            return Err(format!("Corrupt key for wallet {}", address));
+        }
+        Ok(wallet)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let encoded = serde_json::to_vec_pretty(&self.keys).unwrap();
+        fs::write(&self.path, encoded).map_err(|e| e.to_string())
+    }
+}
+
+fn cipher_for(passphrase: &str, salt: &[u8]) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, KDF_ROUNDS, &mut key);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
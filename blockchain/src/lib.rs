@@ -0,0 +1,22 @@
+pub mod blockchain;
+pub mod block;
+pub mod transaction;
+pub mod wallet;
+pub mod keystore;
+pub mod validation;
+pub mod merkle;
+pub mod pow;
+pub mod node;
+pub mod api;
+pub mod amount;
+pub mod mempool;
+pub mod miner;
+pub mod explorer;
+pub mod config;
+pub mod cli;
+pub mod encoding;
+pub mod archive;
+pub mod snapshot;
+pub mod multisig;
+pub mod events;
+pub mod consensus;
@@ -1,41 +1,339 @@
-mod blockchain;
-mod block;
-mod transaction;
-mod wallet;
-mod cli;
-
+use serde_json::json;
+use std::thread;
 use structopt::StructOpt;
-use crate::blockchain::Blockchain;
-use crate::cli::Cli;
+use simple_blockchain::amount;
+use simple_blockchain::api::ApiServer;
+use simple_blockchain::archive::{self, BlockReader};
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::cli::{Cli, Command};
+use simple_blockchain::events::{ChainEvent, EventFilter, Webhook};
+use simple_blockchain::explorer;
+use simple_blockchain::miner::{CancelToken, Miner, MiningProgress};
+use simple_blockchain::multisig;
+use simple_blockchain::node::{Node, Producer};
+use simple_blockchain::snapshot::Snapshot;
+use simple_blockchain::transaction::{SpendingCondition, Timelock, Transaction};
+use simple_blockchain::wallet;
 
 fn main() {
-    let mut blockchain = Blockchain::new();
     let cli = Cli::from_args();
+    let config = match cli.config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    // The store cannot be opened normally until it has been migrated.
+    if let Command::MigrateStore = cli.command {
+        match Blockchain::migrate_store(&config) {
+            Ok(report) => {
+                println!(
+                    "Migrated {} blocks, {} orphans and {} mempool transactions",
+                    report.blocks, report.orphans, report.mempool,
+                );
+                match report.chain.first_invalid {
+                    None => println!("Chain is valid ({} blocks checked)", report.chain.blocks_checked),
+                    Some((index, violation)) => println!("Chain is invalid at block {}: {}", index, violation),
+                }
+            }
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    let mut blockchain = match Blockchain::open(&config) {
+        Ok(blockchain) => blockchain,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
 
-    match cli {
-        Cli::CreateWallet => {
-            let wallet = blockchain.create_wallet();
-            println!("New wallet created: {}", wallet.address);
+    match cli.command {
+        Command::CreateWallet { passphrase } => {
+            match blockchain.create_wallet(&passphrase) {
+                Ok(wallet) => println!("New wallet created: {}", wallet.address),
+                Err(e) => println!("Error: {}", e),
+            }
         }
-        Cli::GetBalance { address } => {
+        Command::ListWallets => {
+            for address in blockchain.wallet_addresses() {
+                println!("{}  {}", address, blockchain.public_key(&address).unwrap_or_default());
+            }
+        }
+        Command::GetBalance { address } => {
             match blockchain.get_wallet_balance(&address) {
-                Ok(balance) => println!("Balance of wallet {}: {}", address, balance),
+                Ok(balance) => println!("Balance of wallet {}: {}", address, amount::format_amount(balance)),
                 Err(e) => println!("Error: {}", e),
             }
         }
-        Cli::SendCoins { from, to, amount } => {
-            match blockchain.add_transaction(from, to, amount) {
+        Command::SendCoins { from, to, amount, fee, passphrase } => {
+            match blockchain.add_transaction(from, to, amount, fee, &passphrase) {
                 Ok(_) => println!("Transaction added successfully"),
                 Err(e) => println!("Error: {}", e),
             }
         }
-        Cli::Mine => {
-            let block = blockchain.mine();
-            println!("New block mined: {:#?}", block);
+        Command::CreateMultisig { threshold, keys, after_height, after_blocks, output } => {
+            let keys: Result<Vec<String>, String> = keys.into_iter()
+                .map(|key| if wallet::is_valid_address(&key) {
+                    blockchain.public_key(&key).ok_or_else(|| format!("Wallet {} is not in the keystore", key))
+                } else {
+                    Ok(key)
+                })
+                .collect();
+            let timelock = after_height.map(Timelock::AfterHeight).or(after_blocks.map(Timelock::AfterBlocks));
+            let condition = keys.and_then(|keys| SpendingCondition::new(threshold, keys, timelock));
+            match condition.and_then(|condition| multisig::write_json(&output, &condition).map(|_| condition)) {
+                Ok(condition) => println!(
+                    "Multisig address {} ({} of {}) saved to {}",
+                    condition.address(), condition.threshold, condition.public_keys.len(), output.display(),
+                ),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::ProposeSpend { condition, to, amount, fee, output } => {
+            let proposal = multisig::read_json(&condition)
+                .and_then(|condition| blockchain.propose_spend(&condition, to, amount, fee))
+                .and_then(|tx| multisig::write_json(&output, &tx).map(|_| tx));
+            match proposal {
+                Ok(tx) => println!("Transaction {} written to {}; it needs signing", tx.id, output.display()),
+                Err(e) => println!("Error: {}", e),
+            }
         }
-        Cli::PrintChain => {
+        Command::SignSpend { file, address, passphrase } => {
+            let signed = multisig::read_json::<Transaction>(&file).and_then(|mut tx| {
+                let wallet = blockchain.unlock_wallet(&address, &passphrase)?;
+                match tx.sign(&wallet) {
+                    0 => Err(format!("Wallet {} is not a key of any input", address)),
+                    _ => multisig::write_json(&file, &tx).map(|_| tx),
+                }
+            });
+            match signed {
+                Ok(tx) => {
+                    for (input, (collected, needed)) in multisig::signature_status(&tx).into_iter().enumerate() {
+                        println!("Input {}: {} of {} signatures", input, collected, needed);
+                    }
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::BroadcastSpend { file } => {
+            match multisig::read_json(&file).and_then(|tx| blockchain.submit_transaction(tx)) {
+                Ok(index) => println!("Transaction will be added to block {}", index),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::Mine { address, blocks, passphrase, .. } if blockchain.chain_config().authority().is_some() => {
+            let validator = match unlock_validator(&blockchain, &address, passphrase) {
+                Ok(validator) => validator,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    return;
+                }
+            };
+            for _ in 0..blocks {
+                match blockchain.sign_block(&validator) {
+                    Ok(block) => println!("Signed block {} ({})", block.index, block.hash()),
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        Command::Mine { address, blocks, threads, .. } => {
+            let miner = threads.map_or_else(Miner::default, Miner::new);
+            for _ in 0..blocks {
+                let block = match blockchain.create_new_block(&address) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        break;
+                    }
+                };
+                let report = |progress: &MiningProgress| eprint!("\rMining block {}: {}\x1b[K", block.index, progress);
+                let (block, summary) = match miner.mine(block.clone(), &CancelToken::default(), report) {
+                    Some(found) => found,
+                    None => break,
+                };
+                eprint!("\r\x1b[K");
+                match blockchain.process_block(block.clone()) {
+                    Ok(_) => println!("Mined block {} ({}): {}", block.index, block.hash(), summary),
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        Command::PrintChain => {
             let chain = blockchain.get_chain();
             println!("{:#?}", chain);
         }
+        Command::ShowBlock { index, hash, json } => {
+            let block = match (index, hash) {
+                (Some(index), _) => blockchain.get_block(index),
+                (None, Some(hash)) => blockchain.get_block_by_hash(&hash),
+                (None, None) => None,
+            };
+            match block {
+                Some(block) if json => println!("{}", serde_json::to_string_pretty(&block).unwrap()),
+                Some(block) => explorer::print_block(&block),
+                None => println!("Error: block not found"),
+            }
+        }
+        Command::ShowTx { txid, json } => {
+            match blockchain.find_transaction(&txid) {
+                Some((block, tx)) if json => {
+                    let value = json!({ "block_index": block.index, "block_hash": block.hash(), "transaction": tx });
+                    println!("{}", serde_json::to_string_pretty(&value).unwrap());
+                }
+                Some((block, tx)) => explorer::print_transaction(&block, &tx),
+                None => println!("Error: transaction {} is not in any block", txid),
+            }
+        }
+        Command::AddressHistory { address, json } => {
+            match blockchain.address_history(&address) {
+                Ok(history) if json => println!("{}", serde_json::to_string_pretty(&history).unwrap()),
+                Ok(history) => explorer::print_history(&address, &history),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::ProveTx { txid } => {
+            match blockchain.transaction_proof(&txid) {
+                Ok(proof) => {
+                    println!("Transaction {} is in block {} ({})", proof.txid, proof.block_index, proof.block_hash);
+                    println!("Merkle root: {}", proof.merkle_root);
+                    for (depth, step) in proof.path.iter().enumerate() {
+                        println!("  {}: {:?} {}", depth, step.side, step.hash);
+                    }
+                    println!("Proof verifies: {}", proof.verify());
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::ValidateChain => {
+            let report = blockchain.validate_chain();
+            match report.first_invalid {
+                None => println!("Chain is valid ({} blocks checked)", report.blocks_checked),
+                Some((index, violation)) => println!("Chain is invalid at block {}: {}", index, violation),
+            }
+        }
+        Command::MigrateStore => unreachable!("handled before the chain is opened"),
+        Command::ExportChain { output, format } => {
+            match blockchain.export_blocks().and_then(|blocks| archive::write_blocks(&output, format, blocks)) {
+                Ok(count) => println!("Exported {} blocks to {} ({})", count, output.display(), format),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::ImportChain { input } => {
+            match BlockReader::open(&input).and_then(|blocks| blockchain.import_blocks(blocks)) {
+                Ok(report) => println!(
+                    "Imported {} blocks ({} already known); tip is now block {}",
+                    report.imported,
+                    report.already_known,
+                    blockchain.get_last_block().unwrap().index,
+                ),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::ExportSnapshot { output, height } => {
+            let height = height.unwrap_or_else(|| blockchain.get_last_block().unwrap().index);
+            match blockchain.snapshot(height).and_then(|snapshot| snapshot.save(&output).map(|_| snapshot)) {
+                Ok(snapshot) => print_snapshot(&snapshot),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::ImportSnapshot { input } => {
+            match Snapshot::load(&input).and_then(|snapshot| blockchain.bootstrap(snapshot.clone()).map(|_| snapshot)) {
+                Ok(snapshot) => print_snapshot(&snapshot),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Command::Node { listen, peers, miner_address, passphrase } => {
+            let producer = match miner_address {
+                Some(address) if blockchain.chain_config().authority().is_some() => {
+                    match unlock_validator(&blockchain, &address, passphrase) {
+                        Ok(validator) => Some(Producer::Validator(Box::new(validator))),
+                        Err(e) => {
+                            println!("Error: {}", e);
+                            return;
+                        }
+                    }
+                }
+                address => address.map(Producer::Miner),
+            };
+            if let Err(e) = Node::new(blockchain, listen, peers).run(producer) {
+                println!("Error: {}", e);
+            }
+        }
+        Command::Watch { addresses, confirmations, webhook, listen, peers } => {
+            if let Some(address) = addresses.iter().find(|a| !wallet::is_valid_address(a)) {
+                println!("Error: Address {} is invalid", address);
+                return;
+            }
+            let events = blockchain.subscribe(EventFilter::new(addresses, confirmations));
+            let webhook = webhook.map(Webhook::new);
+            thread::spawn(move || {
+                for event in events {
+                    print_event(&event);
+                    if let Some(webhook) = &webhook {
+                        if let Err(e) = webhook.notify(&event) {
+                            println!("Error: {}", e);
+                        }
+                    }
+                }
+            });
+            if let Err(e) = Node::new(blockchain, listen, peers).run(None) {
+                println!("Error: {}", e);
+            }
+        }
+        Command::Serve { listen, workers } => {
+            if let Err(e) = ApiServer::new(blockchain).run(&listen, workers) {
+                println!("Error: {}", e);
+            }
+        }
+    }
+}
+
+/// Unlocks the keystore wallet that signs blocks on a proof-of-authority chain.
+fn unlock_validator(blockchain: &Blockchain, address: &str, passphrase: Option<String>) -> Result<wallet::Wallet, String> {
+    let passphrase = passphrase.ok_or("A passphrase is needed to sign blocks with a validator wallet")?;
+    blockchain.unlock_wallet(address, &passphrase)
+}
+
+fn print_snapshot(snapshot: &Snapshot) {
+    println!("Snapshot at block {} ({})", snapshot.height, snapshot.block_hash);
+    match snapshot.balances() {
+        Ok(balances) => {
+            let supply = amount::checked_sum(balances.values().copied()).map_or_else(|e| e, amount::format_amount);
+            println!("  Unspent outputs: {}", snapshot.utxos.len());
+            println!("  Addresses:       {}", balances.len());
+            println!("  Total supply:    {}", supply);
+        }
+        Err(e) => println!("  Balances: {}", e),
+    }
+    println!("  UTXO hash:       {}", snapshot.utxo_hash);
+}
+
+fn print_event(event: &ChainEvent) {
+    match event {
+        ChainEvent::BlockConnected { index, hash, transactions } => {
+            println!("Block {} connected: {} ({} transactions)", index, hash, transactions);
+        }
+        ChainEvent::BlockDisconnected { index, hash } => {
+            println!("Block {} disconnected: {}", index, hash);
+        }
+        ChainEvent::TransactionConfirmed { txid, address, received, sent, block_index, confirmations, .. } => {
+            println!(
+                "Transaction {} for {}: received {}, sent {} in block {} ({} confirmations)",
+                txid,
+                address,
+                amount::format_amount(*received),
+                amount::format_amount(*sent),
+                block_index,
+                confirmations,
+            );
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,33 @@
+use serde::{Serialize, Deserialize};
+use crate::transaction::Transaction;
+
+/// Most transactions kept waiting; beyond this the lowest fee rate is evicted.
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 5_000;
+
+/// A pending transaction with the fee it pays, as stored in the mempool tree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    pub fee: u64,
+    pub size: u64,
+}
+
+impl MempoolEntry {
+    pub fn new(transaction: Transaction, fee: u64) -> Self {
+        let size = transaction.size() as u64;
+        MempoolEntry { transaction, fee, size }
+    }
+
+    /// Fee in base units per 1000 bytes of transaction.
+    pub fn fee_rate(&self) -> u64 {
+        self.fee.saturating_mul(1000) / self.size.max(1)
+    }
+
+    /// Tree key that sorts entries by descending fee rate and then by arrival,
+    /// so iterating the tree yields transactions in mining priority.
+    pub fn key(&self, sequence: u64) -> Vec<u8> {
+        let mut key = (u64::MAX - self.fee_rate()).to_be_bytes().to_vec();
+        key.extend_from_slice(&sequence.to_be_bytes());
+        key
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+/// Which side of the running hash a sibling sits on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub side: Side,
+    pub hash: String,
+}
+
+/// Evidence that a transaction is committed to by a block's merkle root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub txid: String,
+    pub block_index: u64,
+    pub block_hash: String,
+    pub merkle_root: String,
+    pub path: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    pub fn verify(&self) -> bool {
+        verify_proof(&self.txid, &self.path, &self.merkle_root)
+    }
+}
+
+/// Merkle root over transaction ids. Odd levels duplicate their last node
+/// (see `Block::has_duplicate_transactions`). A block without transactions
+/// has an all-zero root.
+pub fn merkle_root(txids: &[String]) -> String {
+    if txids.is_empty() {
+        return hex::encode([0u8; 32]);
+    }
+
+    let mut level: Vec<Vec<u8>> = txids.iter().map(|id| leaf_hash(id)).collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    hex::encode(&level[0])
+}
+
+fn leaf_hash(txid: &str) -> Vec<u8> {
+    Sha256::digest(txid.as_bytes()).to_vec()
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+/// Sibling hashes from the leaf at `position` up to the root, or `None` if
+/// `position` is out of range.
+pub fn merkle_path(txids: &[String], position: usize) -> Option<Vec<ProofStep>> {
+    if position >= txids.len() {
+        return None;
+    }
+
+    let mut path = Vec::new();
+    let mut index = position;
+    let mut level: Vec<Vec<u8>> = txids.iter().map(|id| leaf_hash(id)).collect();
+    while level.len() > 1 {
+        let sibling = if index.is_multiple_of(2) {
+            ProofStep { side: Side::Right, hash: hex::encode(level.get(index + 1).unwrap_or(&level[index])) }
+        } else {
+            ProofStep { side: Side::Left, hash: hex::encode(&level[index - 1]) }
+        };
+        path.push(sibling);
+
+        level = level
+            .chunks(2)
+            .map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        index /= 2;
+    }
+    Some(path)
+}
+
+/// Recomputes the root from `txid` and its sibling path and compares it to
+/// `merkle_root`.
+pub fn verify_proof(txid: &str, path: &[ProofStep], merkle_root: &str) -> bool {
+    let mut hash = leaf_hash(txid);
+    for step in path {
+        let sibling = match hex::decode(&step.hash) {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        hash = match step.side {
+            Side::Left => node_hash(&sibling, &hash),
+            Side::Right => node_hash(&hash, &sibling),
+        };
+    }
+    hex::encode(hash) == merkle_root
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::block::Block;
+use crate::pow;
+
+/// Nonces a worker tries between checks for cancellation.
+const BATCH: u64 = 10_000;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shared flag that stops a running search, e.g. because a new block arrived
+/// and the candidate is stale.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Hashes tried so far in a search and how long it has been running.
+#[derive(Debug, Clone, Copy)]
+pub struct MiningProgress {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningProgress {
+    pub fn hashrate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for MiningProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = self.hashrate();
+        let (rate, unit) = if rate >= 1e6 {
+            (rate / 1e6, "MH/s")
+        } else if rate >= 1e3 {
+            (rate / 1e3, "kH/s")
+        } else {
+            (rate, "H/s")
+        };
+        write!(f, "{} hashes in {:.1}s ({:.1} {})", self.hashes, self.elapsed.as_secs_f64(), rate, unit)
+    }
+}
+
+/// Proof-of-work search spread over several threads. Worker `i` of `n` tries
+/// the nonces `i, i + n, i + 2n, ...` so the partitions never overlap.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Default for Miner {
+    /// One worker per available CPU.
+    fn default() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner { threads: threads.max(1) }
+    }
+
+    /// Searches for a nonce satisfying `block`'s difficulty, calling
+    /// `progress` about once a second. Returns `None` if `cancel` fires first.
+    pub fn mine(
+        &self,
+        block: Block,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(&MiningProgress),
+    ) -> Option<(Block, MiningProgress)> {
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let done = AtomicBool::new(false);
+        let stride = self.threads as u64;
+        let (sender, receiver) = mpsc::channel();
+
+        let found = thread::scope(|scope| {
+            for worker in 0..stride {
+                let sender = sender.clone();
+                let (hashes, done) = (&hashes, &done);
+                let mut candidate = block.clone();
+                candidate.nonce = worker;
+                scope.spawn(move || {
+                    let mut tried = 0;
+                    loop {
+                        if pow::valid_proof(&candidate) {
+                            done.store(true, Ordering::Relaxed);
+                            let _ = sender.send(candidate);
+                            break;
+                        }
+                        tried += 1;
+                        if tried == BATCH {
+                            hashes.fetch_add(tried, Ordering::Relaxed);
+                            tried = 0;
+                            if done.load(Ordering::Relaxed) || cancel.is_cancelled() {
+                                break;
+                            }
+                        }
+                        candidate.nonce = match candidate.nonce.checked_add(stride) {
+                            Some(nonce) => nonce,
+                            None => break,
+                        };
+                    }
+                    hashes.fetch_add(tried, Ordering::Relaxed);
+                });
+            }
+            drop(sender);
+
+            loop {
+                match receiver.recv_timeout(PROGRESS_INTERVAL) {
+                    Ok(found) => {
+                        done.store(true, Ordering::Relaxed);
+                        return Some(found);
+                    }
+                    Err(RecvTimeoutError::Timeout) => progress(&MiningProgress {
+                        hashes: hashes.load(Ordering::Relaxed),
+                        elapsed: start.elapsed(),
+                    }),
+                    // Every worker stopped without a solution: cancelled, or
+                    // the nonce space is exhausted.
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                }
+            }
+        });
+
+        // The scope has joined the workers, so every hash is counted.
+        let summary = MiningProgress { hashes: hashes.into_inner(), elapsed: start.elapsed() };
+        found.map(|block| (block, summary))
+    }
+}
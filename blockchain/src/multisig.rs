@@ -0,0 +1,29 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use crate::transaction::Transaction;
+
+/// Reads a spending condition or partially signed transaction passed
+/// between the keys of a multisig address.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+}
+
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let encoded = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    fs::write(path, encoded).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// For each input spending a condition, the valid signatures collected so
+/// far and the number required.
+pub fn signature_status(tx: &Transaction) -> Vec<(usize, usize)> {
+    let message = tx.signing_bytes();
+    tx.inputs.iter()
+        .filter_map(|input| {
+            let condition = input.condition.as_ref()?;
+            Some((condition.valid_signatures(&message, &input.signatures), condition.threshold as usize))
+        })
+        .collect()
+}
@@ -0,0 +1,369 @@
+use chrono::Utc;
+use serde::{Serialize, Deserialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, BlockStatus};
+use crate::miner::{CancelToken, Miner};
+use crate::transaction::Transaction;
+use crate::wallet::Wallet;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const BLOCK_BATCH: usize = 500;
+const VALIDATOR_POLL: Duration = Duration::from_secs(1);
+const MINING_RETRY: Duration = Duration::from_secs(1);
+
+/// Wire protocol between nodes: one JSON message per line, each request
+/// answered by exactly one response line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Message {
+    GetStatus,
+    Status { chain_id: String, height: u64, work: u128 },
+    GetBlocks { from: u64 },
+    Blocks(Vec<Block>),
+    NewBlock(Block),
+    NewTransaction(Transaction),
+    Ack,
+}
+
+/// How a node adds blocks of its own.
+pub enum Producer {
+    /// Mine proof-of-work blocks, paying rewards to this address.
+    Miner(String),
+    /// Sign a proof-of-authority block whenever it is this validator's turn.
+    Validator(Box<Wallet>),
+}
+
+/// A TCP peer that serves its chain, gossips new blocks and transactions to
+/// the configured peers and periodically syncs to the peer with the most
+/// work.
+pub struct Node {
+    blockchain: Arc<Mutex<Blockchain>>,
+    listen: String,
+    peers: Vec<String>,
+    /// Cancels the current mining attempt once its candidate block is stale.
+    mining: Mutex<CancelToken>,
+}
+
+impl Node {
+    pub fn new(blockchain: Blockchain, listen: String, peers: Vec<String>) -> Self {
+        Node {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            listen,
+            peers,
+            mining: Mutex::new(CancelToken::default()),
+        }
+    }
+
+    /// Serves peers forever, also producing blocks if `producer` is set.
+    pub fn run(self, producer: Option<Producer>) -> Result<(), String> {
+        let listener = TcpListener::bind(&self.listen).map_err(|e| e.to_string())?;
+        println!("Node listening on {}", self.listen);
+
+        let node = Arc::new(self);
+        node.sync_with_peers();
+
+        let server = Arc::clone(&node);
+        thread::spawn(move || server.serve(listener));
+
+        let producing = Arc::clone(&node);
+        match producer {
+            Some(Producer::Miner(address)) => {
+                thread::spawn(move || producing.mine_forever(&address));
+            }
+            Some(Producer::Validator(validator)) => {
+                thread::spawn(move || producing.sign_forever(&validator));
+            }
+            None => {}
+        }
+
+        loop {
+            thread::sleep(SYNC_INTERVAL);
+            node.sync_with_peers();
+        }
+    }
+
+    fn serve(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let node = Arc::clone(&self);
+                    thread::spawn(move || node.handle_connection(stream));
+                }
+                Err(e) => println!("Error: failed to accept connection: {}", e),
+            }
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let response = match serde_json::from_str(&line) {
+                Ok(message) => self.handle_message(message),
+                Err(_) => Message::Ack,
+            };
+            let mut encoded = serde_json::to_string(&response).unwrap();
+            encoded.push('\n');
+            if writer.write_all(encoded.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle_message(&self, message: Message) -> Message {
+        match message {
+            Message::GetStatus => {
+                let chain = self.chain();
+                // A chain without a tip has no status to give.
+                let Some(tip) = chain.get_last_block() else {
+                    return Message::Ack;
+                };
+                Message::Status {
+                    chain_id: chain.chain_config().chain_id.clone(),
+                    height: tip.index,
+                    work: chain.chain_work(),
+                }
+            }
+            Message::GetBlocks { from } => {
+                Message::Blocks(self.chain().blocks_from(from, BLOCK_BATCH))
+            }
+            Message::NewBlock(block) => {
+                let status = self.chain().process_block(block.clone());
+                match status {
+                    Ok(BlockStatus::Connected) | Ok(BlockStatus::Reorganized { .. }) => {
+                        println!("Accepted block {} from peer", block.index);
+                        self.interrupt_mining();
+                        self.broadcast(Message::NewBlock(block));
+                    }
+                    // We are missing its ancestors; fetch them from whoever has them.
+                    Ok(BlockStatus::Orphan) => self.sync_with_peers(),
+                    _ => {}
+                }
+                Message::Ack
+            }
+            Message::NewTransaction(tx) => {
+                let accepted = self.chain().submit_transaction(tx.clone()).is_ok();
+                if accepted {
+                    self.interrupt_mining();
+                    self.broadcast(Message::NewTransaction(tx));
+                }
+                Message::Ack
+            }
+            Message::Status { .. } | Message::Blocks(_) | Message::Ack => Message::Ack,
+        }
+    }
+
+    /// Mines on all CPUs. Whenever the tip or the mempool changes the search
+    /// is cancelled and restarted on a fresh candidate block. If no candidate
+    /// can be built, or the chain refuses the block found, mining pauses
+    /// briefly and resumes on a new candidate; a refused block also drops
+    /// the pending transactions that no longer apply to the tip, so the next
+    /// candidate does not repeat them.
+    fn mine_forever(&self, miner_address: &str) {
+        let miner = Miner::default();
+        loop {
+            let cancel = CancelToken::default();
+            *self.mining.lock().unwrap_or_else(PoisonError::into_inner) = cancel.clone();
+            let block = match self.chain().create_new_block(miner_address) {
+                Ok(block) => block,
+                Err(e) => {
+                    println!("Error: cannot build a block to mine: {}", e);
+                    thread::sleep(MINING_RETRY);
+                    continue;
+                }
+            };
+            let block = match miner.mine(block, &cancel, |_| {}) {
+                Some((block, _)) => block,
+                None => continue,
+            };
+
+            // A block may still have landed just before we locked the chain;
+            // ours then goes to a side chain and we start over on the new tip.
+            let mut blockchain = self.chain();
+            match blockchain.process_block(block.clone()) {
+                Ok(BlockStatus::Connected) => {
+                    drop(blockchain);
+                    println!("Mined block {} ({})", block.index, block.hash());
+                    self.broadcast(Message::NewBlock(block));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    println!("Error: mined block {} was rejected: {}", block.index, e);
+                    if let Err(e) = blockchain.revalidate_mempool() {
+                        println!("Error: {}", e);
+                    }
+                    drop(blockchain);
+                    thread::sleep(MINING_RETRY);
+                }
+            }
+        }
+    }
+
+    /// Signs the next block whenever it is `validator`'s turn and the target
+    /// block time has passed since the tip. A validator that is offline
+    /// stalls the chain until it returns.
+    fn sign_forever(&self, validator: &Wallet) {
+        loop {
+            thread::sleep(VALIDATOR_POLL);
+            let mut blockchain = self.chain();
+            let spacing = blockchain.chain_config().pow.target_block_time;
+            let due = blockchain.get_last_block().is_some_and(|tip| Utc::now().timestamp() >= tip.timestamp + spacing);
+            if !due || !blockchain.is_turn_of(validator) {
+                continue;
+            }
+            let signed = blockchain.sign_block(validator);
+            drop(blockchain);
+            match signed {
+                Ok(block) => {
+                    println!("Signed block {} ({})", block.index, block.hash());
+                    self.broadcast(Message::NewBlock(block));
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+    }
+
+    /// Locks the chain. A thread that panicked while holding the lock cannot
+    /// have left the chain half-updated, since its changes are committed in
+    /// sled transactions, so the poisoning is ignored.
+    fn chain(&self) -> MutexGuard<'_, Blockchain> {
+        self.blockchain.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn interrupt_mining(&self) {
+        self.mining.lock().unwrap_or_else(PoisonError::into_inner).cancel();
+    }
+
+    /// Pushes `message` to every peer in the background.
+    fn broadcast(&self, message: Message) {
+        let encoded = serde_json::to_string(&message).unwrap();
+        for peer in &self.peers {
+            let peer = peer.clone();
+            let encoded = encoded.clone();
+            thread::spawn(move || {
+                let _ = exchange(&peer, &encoded);
+            });
+        }
+    }
+
+    fn sync_with_peers(&self) {
+        for peer in &self.peers {
+            if let Err(e) = self.sync_with_peer(peer) {
+                println!("Sync with {} failed: {}", peer, e);
+            }
+        }
+    }
+
+    /// Downloads the peer's chain if it carries more work than ours; the
+    /// blocks are processed like any others, so the best chain reorganises
+    /// onto the peer's once its branch is heavier. The fork point is found by
+    /// stepping back exponentially from our tip until the peer's block links
+    /// onto one of ours.
+    fn sync_with_peer(&self, peer: &str) -> Result<(), String> {
+        let (chain_id, our_height, our_work) = {
+            let chain = self.chain();
+            let tip = chain.get_last_block().ok_or("our chain has no tip")?;
+            (chain.chain_config().chain_id.clone(), tip.index, chain.chain_work())
+        };
+        let (peer_height, peer_work) = match request(peer, &Message::GetStatus)? {
+            Message::Status { chain_id: peer_chain, .. } if peer_chain != chain_id => {
+                return Err(format!("peer is on chain {:?}, not {:?}", peer_chain, chain_id));
+            }
+            Message::Status { height, work, .. } => (height, work),
+            other => return Err(format!("unexpected response {:?}", other)),
+        };
+        if peer_work <= our_work {
+            return Ok(());
+        }
+
+        let mut from = our_height.min(peer_height) + 1;
+        let mut step = 1;
+        let mut branch = loop {
+            let blocks = fetch_blocks(peer, from)?;
+            let links = blocks.first().is_some_and(|first| {
+                let chain = self.chain();
+                chain.get_block(from - 1).is_some_and(|b| b.hash() == first.previous_hash)
+            });
+            if links {
+                break blocks;
+            }
+            if from == 1 {
+                return Err(String::from("peer chain does not share our genesis block"));
+            }
+            from = from.saturating_sub(step).max(1);
+            step *= 2;
+        };
+
+        while let Some(last) = branch.last() {
+            if last.index >= peer_height {
+                break;
+            }
+            let more = fetch_blocks(peer, last.index + 1)?;
+            if more.is_empty() {
+                break;
+            }
+            branch.extend(more);
+        }
+
+        let mut chain = self.chain();
+        let (mut reorganised, mut extended) = (false, false);
+        for block in branch {
+            match chain.process_block(block)? {
+                BlockStatus::Reorganized { .. } => reorganised = true,
+                BlockStatus::Connected => extended = true,
+                _ => {}
+            }
+        }
+        if reorganised || extended {
+            self.interrupt_mining();
+        }
+        let height = chain.get_last_block().ok_or("our chain has no tip")?.index;
+        if reorganised {
+            println!("Reorganised onto {}'s chain at height {}", peer, height);
+        } else {
+            println!("Synced to height {} from {}", height, peer);
+        }
+        Ok(())
+    }
+}
+
+fn fetch_blocks(peer: &str, from: u64) -> Result<Vec<Block>, String> {
+    match request(peer, &Message::GetBlocks { from })? {
+        Message::Blocks(blocks) => Ok(blocks),
+        other => Err(format!("unexpected response {:?}", other)),
+    }
+}
+
+fn request(peer: &str, message: &Message) -> Result<Message, String> {
+    let response = exchange(peer, &serde_json::to_string(message).unwrap())?;
+    serde_json::from_str(&response).map_err(|e| e.to_string())
+}
+
+/// Sends one encoded message to `peer` and returns its response line.
+fn exchange(peer: &str, encoded: &str) -> Result<String, String> {
+    let address = peer.to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", peer))?;
+    let mut stream = TcpStream::connect_timeout(&address, IO_TIMEOUT).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(|e| e.to_string())?;
+
+    stream.write_all(encoded.as_bytes()).map_err(|e| e.to_string())?;
+    stream.write_all(b"\n").map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).map_err(|e| e.to_string())?;
+    Ok(response)
+}
@@ -0,0 +1,87 @@
+use serde::{Serialize, Deserialize};
+use crate::block::Block;
+
+/// Proof-of-work settings. Difficulty is the number of leading zero bits the
+/// block header hash must have.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowParams {
+    pub initial_difficulty: u32,
+    /// Seconds the network aims to spend on each block.
+    pub target_block_time: i64,
+    /// Difficulty is re-evaluated every this many blocks.
+    pub retarget_interval: u64,
+}
+
+impl Default for PowParams {
+    fn default() -> Self {
+        PowParams {
+            initial_difficulty: 16,
+            target_block_time: 10,
+            retarget_interval: 10,
+        }
+    }
+}
+
+/// Expected number of hashes needed to mine a block at `difficulty`.
+pub fn block_work(difficulty: u32) -> u128 {
+    1u128.checked_shl(difficulty).unwrap_or(u128::MAX)
+}
+
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+/// Whether `block`'s header hash has at least `block.difficulty` leading
+/// zero bits.
+pub fn valid_proof(block: &Block) -> bool {
+    leading_zero_bits(&block.header_digest()) >= block.difficulty
+}
+
+/// Difficulty required for the block at `height`. Every `retarget_interval`
+/// blocks it steps up by one bit when the last window was mined in under half
+/// the target time and down by one when it took more than twice as long.
+/// `timestamp_at` looks up the timestamp of an earlier block by height.
+pub fn next_difficulty(
+    params: &PowParams,
+    height: u64,
+    last_difficulty: u32,
+    timestamp_at: impl Fn(u64) -> Option<i64>,
+) -> u32 {
+    if height <= 1 {
+        return params.initial_difficulty;
+    }
+    if params.retarget_interval == 0 || !(height - 1).is_multiple_of(params.retarget_interval) {
+        return last_difficulty;
+    }
+
+    // The genesis timestamp is fixed rather than mined, so it never counts.
+    let window_end = height - 1;
+    let window_start = window_end.saturating_sub(params.retarget_interval).max(1);
+    if window_start >= window_end {
+        return last_difficulty;
+    }
+    let (start, end) = match (timestamp_at(window_start), timestamp_at(window_end)) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return last_difficulty,
+    };
+
+    let actual = (end - start).max(0);
+    let expected = params.target_block_time * (window_end - window_start) as i64;
+    if actual * 2 < expected {
+        last_difficulty.saturating_add(1).min(255)
+    } else if actual > expected * 2 {
+        last_difficulty.saturating_sub(1)
+    } else {
+        last_difficulty
+    }
+}
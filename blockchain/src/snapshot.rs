@@ -0,0 +1,122 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use crate::amount;
+use crate::block::Block;
+use crate::config::ChainConfig;
+use crate::encoding::{Encode, Writer};
+use crate::transaction::TxOutput;
+
+/// An unspent output and the height of the block it was mined in, which
+/// relative timelocks count from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnspentOutput {
+    pub outpoint: String,
+    pub height: u64,
+    pub output: TxOutput,
+}
+
+/// The UTXO set of a chain at one height, plus the blocks a node needs to
+/// carry on from there, so a new node can bootstrap without replaying every
+/// block since genesis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub chain_id: String,
+    pub height: u64,
+    pub block_hash: String,
+    /// Total proof of work of the chain ending in the snapshot block.
+    pub chain_work: u128,
+    /// The snapshot block preceded by enough ancestors to check the
+    /// difficulty of the blocks that follow it.
+    pub blocks: Vec<Block>,
+    /// Unspent outputs, sorted by outpoint.
+    pub utxos: Vec<UnspentOutput>,
+    /// Digest of `utxos`, to compare against a node one trusts.
+    pub utxo_hash: String,
+}
+
+impl Snapshot {
+    /// Builds a snapshot, sorting `utxos` and computing their digest.
+    pub fn new(chain_id: String, chain_work: u128, blocks: Vec<Block>, utxos: impl IntoIterator<Item = UnspentOutput>) -> Self {
+        let mut utxos: Vec<UnspentOutput> = utxos.into_iter().collect();
+        utxos.sort_by(|a, b| a.outpoint.cmp(&b.outpoint));
+        let tip = blocks.last().expect("a snapshot needs its block");
+        Snapshot {
+            chain_id,
+            height: tip.index,
+            block_hash: tip.hash(),
+            chain_work,
+            utxo_hash: utxo_hash(&utxos),
+            blocks,
+            utxos,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("Invalid snapshot {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let encoded = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        fs::write(path, encoded).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+    }
+
+    /// Balance of every address holding unspent outputs.
+    pub fn balances(&self) -> Result<BTreeMap<String, u64>, String> {
+        let mut balances: BTreeMap<String, u64> = BTreeMap::new();
+        for utxo in &self.utxos {
+            let balance = balances.entry(utxo.output.address.clone()).or_default();
+            *balance = amount::checked_sum([*balance, utxo.output.amount])?;
+        }
+        Ok(balances)
+    }
+
+    /// Checks that the snapshot belongs to `config`'s chain and is internally
+    /// consistent: its blocks are linked, mined and end in the snapshot
+    /// block, and the UTXO set matches its digest. The UTXO set itself
+    /// cannot be checked without the full chain, so the digest should be
+    /// compared with one reported by a trusted node.
+    pub fn verify(&self, config: &ChainConfig) -> Result<(), String> {
+        if self.chain_id != config.chain_id {
+            return Err(format!("Snapshot is of chain {:?}, not {:?}", self.chain_id, config.chain_id));
+        }
+        let tip = self.blocks.last().ok_or("Snapshot holds no blocks")?;
+        if tip.index != self.height || tip.hash() != self.block_hash {
+            return Err(String::from("Snapshot blocks do not end in the snapshot block"));
+        }
+        for pair in self.blocks.windows(2) {
+            if pair[1].index != pair[0].index + 1 || pair[1].previous_hash != pair[0].hash() {
+                return Err(format!("Snapshot block {} does not follow its parent", pair[1].index));
+            }
+        }
+        for block in &self.blocks {
+            let valid = if block.index == 0 {
+                block.hash() == config.genesis_block_at(block.version).hash()
+            } else {
+                block.has_known_versions() && config.consensus().check_seal(block).is_ok()
+            };
+            if !valid || block.merkle_root != block.compute_merkle_root() {
+                return Err(format!("Snapshot block {} is invalid", block.index));
+            }
+        }
+        if self.utxos.windows(2).any(|pair| pair[0].outpoint >= pair[1].outpoint) {
+            return Err(String::from("Snapshot UTXO set is not sorted by outpoint"));
+        }
+        if utxo_hash(&self.utxos) != self.utxo_hash {
+            return Err(String::from("Snapshot UTXO set does not match its hash"));
+        }
+        Ok(())
+    }
+}
+
+fn utxo_hash(utxos: &[UnspentOutput]) -> String {
+    let mut writer = Writer::default();
+    for utxo in utxos {
+        writer.str(&utxo.outpoint).u64(utxo.height);
+        utxo.output.encode(&mut writer);
+    }
+    hex::encode(Sha256::digest(writer.into_bytes()))
+}
@@ -1,8 +1,287 @@
 use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashSet;
+use crate::encoding::{Encode, Writer};
+use crate::wallet::{self, Wallet};
+
+/// Version of newly created transactions. Version 0 transactions predate the
+/// binary encoding and sign a JSON rendering instead.
+pub const TX_VERSION: u8 = 2;
+/// First version whose inputs may spend outputs paid to a spending condition.
+pub const CONDITION_VERSION: u8 = 2;
+/// Most public keys a spending condition may list.
+pub const MAX_CONDITION_KEYS: usize = 16;
+
+/// Earliest block an output paid to a spending condition can be spent in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Timelock {
+    /// Spendable in blocks at or above this height.
+    AfterHeight(u64),
+    /// Spendable once this many blocks have been mined on top of the block
+    /// containing the output.
+    AfterBlocks(u64),
+}
+
+impl Timelock {
+    /// Whether an output mined at height `created` may be spent in a block
+    /// at `height`.
+    pub fn is_unlocked(&self, created: u64, height: u64) -> bool {
+        match *self {
+            Timelock::AfterHeight(after) => height >= after,
+            Timelock::AfterBlocks(blocks) => height >= created.saturating_add(blocks),
+        }
+    }
+}
+
+/// Rules for spending the outputs paid to its address: valid signatures by
+/// `threshold` of `public_keys`, and optionally a timelock. Outputs commit
+/// to the condition only through the address; the input spending them
+/// reveals it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpendingCondition {
+    pub threshold: u32,
+    /// Hex-encoded public keys, in the order they were given.
+    pub public_keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timelock: Option<Timelock>,
+}
+
+/// One key's signature over a transaction spending a condition's output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl SpendingCondition {
+    pub fn new(threshold: u32, public_keys: Vec<String>, timelock: Option<Timelock>) -> Result<Self, String> {
+        let condition = SpendingCondition { threshold, public_keys, timelock };
+        condition.validate()?;
+        Ok(condition)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let keys = self.public_keys.len();
+        if keys == 0 || keys > MAX_CONDITION_KEYS {
+            return Err(format!("A condition needs between 1 and {} keys", MAX_CONDITION_KEYS));
+        }
+        if self.threshold == 0 || self.threshold as usize > keys {
+            return Err(format!("Threshold {} is not between 1 and {}", self.threshold, keys));
+        }
+        let mut seen = HashSet::new();
+        for key in &self.public_keys {
+            if hex::decode(key).map_or(true, |bytes| bytes.len() != 32) || key.to_lowercase() != *key {
+                return Err(format!("Public key {} is not 32 lowercase hex bytes", key));
+            }
+            if !seen.insert(key) {
+                return Err(format!("Public key {} is listed twice", key));
+            }
+        }
+        Ok(())
+    }
+
+    /// The address outputs pay to be spendable under this condition: the
+    /// first 20 bytes of the SHA-256 of its encoding, domain-separated from
+    /// single-key addresses.
+    pub fn address(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"condition");
+        hasher.update(self.to_bytes());
+        hex::encode(&hasher.finalize()[..20])
+    }
+
+    /// Number of listed keys with a valid signature over `message` among
+    /// `signatures`; each key counts once.
+    pub fn valid_signatures(&self, message: &[u8], signatures: &[KeySignature]) -> usize {
+        self.public_keys.iter()
+            .filter(|key| signatures.iter().any(|s| {
+                s.public_key == **key && wallet::verify_signature(key, message, &s.signature)
+            }))
+            .count()
+    }
+}
+
+/// Reference to an output of an earlier transaction that is being spent,
+/// together with the owner's public key and signature authorising the spend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxInput {
+    pub txid: String,
+    pub vout: u32,
+    #[serde(default)]
+    pub public_key: String,
+    #[serde(default)]
+    pub signature: String,
+    /// Condition of the output being spent, when it pays a condition
+    /// address rather than a single key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<SpendingCondition>,
+    /// Signatures collected from the condition's keys.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<KeySignature>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxOutput {
+    /// Value in base units, see `amount::COIN`.
+    pub amount: u64,
+    pub address: String,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    pub sender: String,
-    pub recipient: String,
-    pub amount: f64,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub version: u8,
+    pub id: String,
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+}
+
+impl TxInput {
+    pub fn new(txid: String, vout: u32) -> Self {
+        TxInput {
+            txid,
+            vout,
+            public_key: String::new(),
+            signature: String::new(),
+            condition: None,
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Whether the timelock of the condition this input reveals, if any,
+    /// lets it spend an output mined at height `created` in a block at `height`.
+    pub fn is_unlocked(&self, created: u64, height: u64) -> bool {
+        self.condition.as_ref()
+            .and_then(|condition| condition.timelock)
+            .is_none_or(|timelock| timelock.is_unlocked(created, height))
+    }
+
+    /// Spends an output paid to `condition`'s address.
+    pub fn with_condition(txid: String, vout: u32, condition: SpendingCondition) -> Self {
+        TxInput { condition: Some(condition), ..TxInput::new(txid, vout) }
+    }
+
+    pub fn outpoint(&self) -> String {
+        outpoint_key(&self.txid, self.vout)
+    }
+
+    /// Address derived from the public key or condition this input was
+    /// signed with, i.e. the owner of the output it claims to spend.
+    pub fn signer_address(&self) -> Option<String> {
+        if let Some(condition) = &self.condition {
+            return Some(condition.address());
+        }
+        let public_key = hex::decode(&self.public_key).ok().filter(|key| !key.is_empty())?;
+        Some(wallet::address_from_public_key(&public_key))
+    }
+}
+
+impl Transaction {
+    pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
+        let mut transaction = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            inputs,
+            outputs,
+        };
+        transaction.id = transaction.hash();
+        transaction
+    }
+
+    /// Mints `amount` to `address`. The single input carries no previous
+    /// transaction and uses the block height as `vout`, so coinbases paying
+    /// the same address at different heights still get distinct ids, which
+    /// rules out heights beyond `u32::MAX`.
+    pub fn coinbase(height: u64, address: String, amount: u64) -> Result<Self, String> {
+        let vout = u32::try_from(height).map_err(|_| format!("Height {} is too large for a coinbase", height))?;
+        Ok(Transaction::new(
+            vec![TxInput::new(String::new(), vout)],
+            vec![TxOutput { amount, address }],
+        ))
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].txid.is_empty()
+    }
+
+    /// Canonical bytes covered by the id and by every input signature. Public
+    /// keys and signatures are left out so signing does not change the id.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        if self.version > 0 {
+            let mut writer = Writer::default();
+            writer.u8(self.version).u32(self.inputs.len() as u32);
+            for input in &self.inputs {
+                writer.str(&input.txid).u32(input.vout);
+            }
+            writer.seq(&self.outputs);
+            return writer.into_bytes();
+        }
+        let inputs: Vec<(&str, u32)> = self.inputs.iter()
+            .map(|input| (input.txid.as_str(), input.vout))
+            .collect();
+        serde_json::to_vec(&(inputs, &self.outputs)).unwrap()
+    }
+
+    /// Signs every input with `wallet`'s key. Inputs spending a condition
+    /// get the signature added to those collected so far, and only if the
+    /// condition lists the key. Returns the number of inputs signed.
+    pub fn sign(&mut self, wallet: &Wallet) -> usize {
+        let signature = wallet.sign(&self.signing_bytes());
+        let public_key = wallet.public_key_hex();
+        let mut signed = 0;
+        for input in &mut self.inputs {
+            match &input.condition {
+                Some(condition) if !condition.public_keys.contains(&public_key) => continue,
+                Some(_) => {
+                    input.signatures.retain(|s| s.public_key != public_key);
+                    input.signatures.push(KeySignature { public_key: public_key.clone(), signature: signature.clone() });
+                }
+                None => {
+                    input.public_key = public_key.clone();
+                    input.signature = signature.clone();
+                }
+            }
+            signed += 1;
+        }
+        signed
+    }
+
+    /// Checks that `input` is authorised by `owner`, the address of the
+    /// output it spends: signed by the key behind it, or by enough keys of
+    /// the condition it commits to. Timelocks are checked separately since
+    /// they depend on where the transaction is mined.
+    pub fn verify_input(&self, input: &TxInput, owner: &str) -> bool {
+        if let Some(condition) = &input.condition {
+            return self.version >= CONDITION_VERSION
+                && condition.validate().is_ok()
+                && condition.address() == owner
+                && condition.valid_signatures(&self.signing_bytes(), &input.signatures) >= condition.threshold as usize;
+        }
+        if self.version < CONDITION_VERSION && !input.signatures.is_empty() {
+            return false;
+        }
+        let public_key = match hex::decode(&input.public_key) {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        wallet::address_from_public_key(&public_key) == owner
+            && wallet::verify_signature(&input.public_key, &self.signing_bytes(), &input.signature)
+    }
+
+    /// Encoded size in bytes, used for fee rates and block limits.
+    pub fn size(&self) -> usize {
+        self.to_bytes().len()
+    }
+
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.signing_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// Key of an unspent output in the UTXO tree.
+pub fn outpoint_key(txid: &str, vout: u32) -> String {
+    format!("{}:{}", txid, vout)
+}
@@ -0,0 +1,355 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt;
+use crate::amount;
+use crate::block::Block;
+use crate::config::ChainConfig;
+use crate::snapshot::Snapshot;
+use crate::transaction::{outpoint_key, Transaction, TxInput, TxOutput};
+use crate::wallet;
+
+/// Why a stored block failed validation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    Undecodable,
+    UnknownVersion,
+    VersionRegression { previous: u8, found: u8 },
+    Genesis,
+    IndexGap { expected: u64 },
+    HashLink { expected: String, found: String },
+    Difficulty { expected: u32, found: u32 },
+    /// Missing or invalid proof of work or validator signature.
+    Seal(String),
+    MerkleRoot,
+    DuplicateTransaction,
+    BlockTooLarge,
+    TimestampRegression { previous: i64, found: i64 },
+    InvalidCoinbase { txid: String },
+    /// The id is not the transaction's hash or is shared with another
+    /// transaction of the block.
+    MismatchedId { txid: String },
+    /// No inputs, an invalid output or outputs that would replace unspent
+    /// ones.
+    InvalidTransaction { txid: String },
+    TransactionSignature { txid: String, outpoint: String },
+    Timelocked { txid: String, outpoint: String },
+    DoubleSpend { txid: String, outpoint: String },
+    Overspend { txid: String },
+    AmountOverflow { txid: String },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Undecodable => write!(f, "block could not be decoded"),
+            Violation::UnknownVersion => write!(f, "block or transaction version is not supported"),
+            Violation::VersionRegression { previous, found } => {
+                write!(f, "version {} is older than the prior block's {}", found, previous)
+            }
+            Violation::Genesis => write!(f, "genesis block does not match the chain config"),
+            Violation::IndexGap { expected } => write!(f, "expected block index {}", expected),
+            Violation::HashLink { expected, found } => {
+                write!(f, "previous hash is {} but the prior block hashes to {}", found, expected)
+            }
+            Violation::Difficulty { expected, found } => {
+                write!(f, "difficulty is {} but {} was required", found, expected)
+            }
+            Violation::Seal(reason) => write!(f, "{}", reason),
+            Violation::MerkleRoot => write!(f, "merkle root does not match the transactions"),
+            Violation::DuplicateTransaction => write!(f, "block repeats a transaction"),
+            Violation::BlockTooLarge => write!(f, "block exceeds the size limits"),
+            Violation::TimestampRegression { previous, found } => {
+                write!(f, "timestamp {} is earlier than the prior block's {}", found, previous)
+            }
+            Violation::InvalidCoinbase { txid } => write!(f, "coinbase {} is malformed or overpays", txid),
+            Violation::MismatchedId { txid } => write!(f, "transaction {} has a mismatched or repeated id", txid),
+            Violation::InvalidTransaction { txid } => write!(f, "transaction {} is malformed", txid),
+            Violation::TransactionSignature { txid, outpoint } => {
+                write!(f, "transaction {} has an invalid signature spending {}", txid, outpoint)
+            }
+            Violation::Timelocked { txid, outpoint } => {
+                write!(f, "transaction {} spends {} before its timelock expires", txid, outpoint)
+            }
+            Violation::DoubleSpend { txid, outpoint } => {
+                write!(f, "transaction {} spends {} which is not unspent", txid, outpoint)
+            }
+            Violation::Overspend { txid } => write!(f, "transaction {} spends more than its inputs", txid),
+            Violation::AmountOverflow { txid } => write!(f, "transaction {} amounts overflow", txid),
+        }
+    }
+}
+
+/// Outcome of replaying the stored chain from genesis.
+#[derive(Debug, Clone)]
+pub struct ChainReport {
+    pub blocks_checked: u64,
+    pub first_invalid: Option<(u64, Violation)>,
+}
+
+/// Chain state built up by replaying blocks in order: the unspent outputs
+/// with the height each was mined at, the timestamps retargeting looks back
+/// on and the last block applied.
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    utxos: HashMap<String, (TxOutput, u64)>,
+    timestamps: HashMap<u64, i64>,
+    previous: Option<Block>,
+}
+
+impl Replay {
+    /// State after the last block of `snapshot`, for chains bootstrapped
+    /// from one instead of replayed from genesis.
+    pub fn from_snapshot(snapshot: &Snapshot) -> Self {
+        Replay {
+            utxos: snapshot.utxos.iter()
+                .map(|utxo| (utxo.outpoint.clone(), (utxo.output.clone(), utxo.height)))
+                .collect(),
+            timestamps: snapshot.blocks.iter().map(|b| (b.index, b.timestamp)).collect(),
+            previous: snapshot.blocks.last().cloned(),
+        }
+    }
+
+    pub fn utxos(&self) -> &HashMap<String, (TxOutput, u64)> {
+        &self.utxos
+    }
+
+    /// The last block applied.
+    pub fn tip(&self) -> Option<&Block> {
+        self.previous.as_ref()
+    }
+
+    /// Checks `block` against the consensus rules and, if it passes, applies it.
+    pub fn apply(&mut self, config: &ChainConfig, block: Block) -> Result<(), Violation> {
+        check_block(config, &block, self.previous.as_ref(), &self.timestamps, &mut self.utxos)?;
+        self.timestamps.insert(block.index, block.timestamp);
+        self.previous = Some(block);
+        Ok(())
+    }
+}
+
+/// Replays `blocks` (in storage order, `None` for entries that failed to
+/// decode) and stops at the first block that breaks a consensus rule.
+pub fn validate_blocks(config: &ChainConfig, blocks: impl IntoIterator<Item = (u64, Option<Block>)>) -> ChainReport {
+    replay_blocks(config, &mut Replay::default(), blocks)
+}
+
+/// Like `validate_blocks`, but continuing from `replay`, which is left at
+/// the last valid block.
+pub fn replay_blocks(
+    config: &ChainConfig,
+    replay: &mut Replay,
+    blocks: impl IntoIterator<Item = (u64, Option<Block>)>,
+) -> ChainReport {
+    let mut blocks_checked = 0;
+    for (index, block) in blocks {
+        let block = match block {
+            Some(block) => block,
+            None => return ChainReport { blocks_checked, first_invalid: Some((index, Violation::Undecodable)) },
+        };
+        let index = block.index;
+        if let Err(violation) = replay.apply(config, block) {
+            return ChainReport { blocks_checked, first_invalid: Some((index, violation)) };
+        }
+        blocks_checked += 1;
+    }
+
+    ChainReport { blocks_checked, first_invalid: None }
+}
+
+fn check_block(
+    config: &ChainConfig,
+    block: &Block,
+    previous: Option<&Block>,
+    timestamps: &HashMap<u64, i64>,
+    utxos: &mut HashMap<String, (TxOutput, u64)>,
+) -> Result<(), Violation> {
+    let expected_index = previous.map_or(0, |p| p.index + 1);
+    if block.index != expected_index {
+        return Err(Violation::IndexGap { expected: expected_index });
+    }
+
+    if !block.has_known_versions() {
+        return Err(Violation::UnknownVersion);
+    }
+    if block.index == 0 && block.hash() != config.genesis_block_at(block.version).hash() {
+        return Err(Violation::Genesis);
+    }
+
+    if let Some(previous) = previous {
+        if block.version < previous.version {
+            return Err(Violation::VersionRegression { previous: previous.version, found: block.version });
+        }
+        let expected = previous.hash();
+        if block.previous_hash != expected {
+            return Err(Violation::HashLink { expected, found: block.previous_hash.clone() });
+        }
+        let consensus = config.consensus();
+        let expected = consensus.next_difficulty(block.index, previous.difficulty, &|i| timestamps.get(&i).copied());
+        if block.difficulty != expected {
+            return Err(Violation::Difficulty { expected, found: block.difficulty });
+        }
+        consensus.check_seal(block).map_err(Violation::Seal)?;
+        if block.timestamp < previous.timestamp {
+            return Err(Violation::TimestampRegression { previous: previous.timestamp, found: block.timestamp });
+        }
+    }
+
+    if block.merkle_root != block.compute_merkle_root() {
+        return Err(Violation::MerkleRoot);
+    }
+    if block.has_duplicate_transactions() {
+        return Err(Violation::DuplicateTransaction);
+    }
+    if block.exceeds_limits() {
+        return Err(Violation::BlockTooLarge);
+    }
+
+    apply_transactions(config, block, utxos).map_err(|e| match e {
+        ApplyError::Invalid(violation) => violation,
+        ApplyError::Storage(never) => match never {},
+    })?;
+    Ok(())
+}
+
+/// Outputs spent by one transaction, by outpoint, in input order.
+pub type Spent = Vec<(String, TxOutput)>;
+
+/// Unspent outputs that blocks are checked against and applied to, whether
+/// replayed in memory or stored.
+pub trait UtxoSet {
+    type Error;
+
+    /// Removes the output `input` spends, returning it with the height it
+    /// was mined at, or `None` if it is not unspent.
+    fn spend(&mut self, input: &TxInput, height: u64) -> Result<Option<(TxOutput, u64)>, Self::Error>;
+
+    /// Adds `output` mined at `height`. Returns false if `outpoint` was
+    /// already unspent.
+    fn create(&mut self, outpoint: String, output: &TxOutput, height: u64) -> Result<bool, Self::Error>;
+}
+
+impl UtxoSet for HashMap<String, (TxOutput, u64)> {
+    type Error = Infallible;
+
+    fn spend(&mut self, input: &TxInput, _height: u64) -> Result<Option<(TxOutput, u64)>, Infallible> {
+        Ok(self.remove(&input.outpoint()))
+    }
+
+    fn create(&mut self, outpoint: String, output: &TxOutput, height: u64) -> Result<bool, Infallible> {
+        Ok(self.insert(outpoint, (output.clone(), height)).is_none())
+    }
+}
+
+/// Why transactions could not be applied.
+#[derive(Debug)]
+pub enum ApplyError<E> {
+    /// They break a consensus rule.
+    Invalid(Violation),
+    /// The UTXO set could not be read or written.
+    Storage(E),
+}
+
+impl<E> From<Violation> for ApplyError<E> {
+    fn from(violation: Violation) -> Self {
+        ApplyError::Invalid(violation)
+    }
+}
+
+/// Checks the transactions of `block` and applies them to `utxos`: every id
+/// must be its transaction's hash and unique, every spend valid, and the
+/// single coinbase must pay valid outputs worth no more than the subsidy
+/// plus the block's fees. The genesis coinbase carries the configured
+/// allocations instead and is exempt from the limit. Returns the outputs
+/// each transaction spent, in block order.
+pub fn apply_transactions<U: UtxoSet>(
+    config: &ChainConfig,
+    block: &Block,
+    utxos: &mut U,
+) -> Result<Vec<Spent>, ApplyError<U::Error>> {
+    let mut fees: u64 = 0;
+    let mut coinbase: Option<(String, u64)> = None;
+    let mut txids = HashSet::new();
+    let mut spent = Vec::new();
+    for tx in &block.transactions {
+        let txid = || tx.id.clone();
+        // Signatures do not cover the id, so a forged one could otherwise
+        // take over another transaction's outputs.
+        if tx.id != tx.hash() || !txids.insert(tx.id.as_str()) {
+            return Err(Violation::MismatchedId { txid: txid() }.into());
+        }
+        if tx.is_coinbase() {
+            let minted = amount::checked_sum(tx.outputs.iter().map(|o| o.amount))
+                .map_err(|_| Violation::AmountOverflow { txid: txid() })?;
+            let malformed = tx.outputs.iter().any(|o| o.amount == 0 || !wallet::is_valid_address(&o.address));
+            if coinbase.is_some() || malformed {
+                return Err(Violation::InvalidCoinbase { txid: txid() }.into());
+            }
+            coinbase = Some((txid(), minted));
+            create_outputs(tx, block.index, utxos)?;
+            spent.push(Vec::new());
+        } else {
+            let (fee, outputs) = apply_transaction(tx, block.index, utxos)?;
+            fees = fees.checked_add(fee).ok_or_else(|| Violation::AmountOverflow { txid: txid() })?;
+            spent.push(outputs);
+        }
+    }
+
+    if let Some((txid, minted)) = coinbase.filter(|_| block.index > 0) {
+        let allowed = config.block_subsidy(block.index).checked_add(fees);
+        if allowed.is_none_or(|allowed| minted > allowed) {
+            return Err(Violation::InvalidCoinbase { txid }.into());
+        }
+    }
+    Ok(spent)
+}
+
+/// Checks that `tx`, mined at `height`, has inputs and valid outputs, and
+/// spends only unspent outputs, each validly signed by its owner and past
+/// its timelock, worth at least what it pays out; then applies it to
+/// `utxos`. Does not check the id. Returns the fee and the outputs spent.
+pub fn apply_transaction<U: UtxoSet>(
+    tx: &Transaction,
+    height: u64,
+    utxos: &mut U,
+) -> Result<(u64, Spent), ApplyError<U::Error>> {
+    let txid = || tx.id.clone();
+    let malformed = tx.inputs.is_empty()
+        || tx.outputs.iter().any(|o| o.amount == 0 || !wallet::is_valid_address(&o.address));
+    if malformed {
+        return Err(Violation::InvalidTransaction { txid: txid() }.into());
+    }
+    let mut spent = Vec::new();
+    let mut input_total: u64 = 0;
+    for input in &tx.inputs {
+        let outpoint = input.outpoint();
+        let (output, created) = match utxos.spend(input, height).map_err(ApplyError::Storage)? {
+            Some(utxo) => utxo,
+            None => return Err(Violation::DoubleSpend { txid: txid(), outpoint }.into()),
+        };
+        if !tx.verify_input(input, &output.address) {
+            return Err(Violation::TransactionSignature { txid: txid(), outpoint }.into());
+        }
+        if !input.is_unlocked(created, height) {
+            return Err(Violation::Timelocked { txid: txid(), outpoint }.into());
+        }
+        input_total = input_total.checked_add(output.amount)
+            .ok_or_else(|| Violation::AmountOverflow { txid: txid() })?;
+        spent.push((outpoint, output));
+    }
+    let output_total = amount::checked_sum(tx.outputs.iter().map(|o| o.amount))
+        .map_err(|_| Violation::AmountOverflow { txid: txid() })?;
+    let fee = input_total.checked_sub(output_total)
+        .ok_or_else(|| Violation::Overspend { txid: txid() })?;
+    create_outputs(tx, height, utxos)?;
+    Ok((fee, spent))
+}
+
+fn create_outputs<U: UtxoSet>(tx: &Transaction, height: u64, utxos: &mut U) -> Result<(), ApplyError<U::Error>> {
+    for (vout, output) in tx.outputs.iter().enumerate() {
+        let vout = u32::try_from(vout).map_err(|_| Violation::InvalidTransaction { txid: tx.id.clone() })?;
+        if !utxos.create(outpoint_key(&tx.id, vout), output, height).map_err(ApplyError::Storage)? {
+            return Err(Violation::InvalidTransaction { txid: tx.id.clone() }.into());
+        }
+    }
+    Ok(())
+}
@@ -1,22 +1,68 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand::Rng;
+use sha2::{Sha256, Digest};
 
 #[derive(Debug, Clone)]
 pub struct Wallet {
     pub address: String,
-    pub balance: f64,
+    signing_key: SigningKey,
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Wallet::new()
+    }
 }
 
 impl Wallet {
     pub fn new() -> Self {
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        Wallet::from_secret(&secret)
+    }
+
+    pub fn from_secret(secret: &[u8; 32]) -> Self {
+        let signing_key = SigningKey::from_bytes(secret);
         Wallet {
-            address: Wallet::generate_address(),
-            balance: 0.0,
+            address: address_from_public_key(signing_key.verifying_key().as_bytes()),
+            signing_key,
         }
     }
 
-    fn generate_address() -> String {
-        let mut rng = rand::thread_rng();
-        let wallet_bytes: Vec<u8> = (0..20).map(|_| rng.gen()).collect();
-        hex::encode(wallet_bytes)
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.signing_key.sign(message).to_bytes())
     }
-}
\ No newline at end of file
+}
+
+/// Addresses are the first 20 bytes of the SHA-256 of the public key.
+pub fn address_from_public_key(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    hex::encode(&digest[..20])
+}
+
+pub fn is_valid_address(address: &str) -> bool {
+    address.len() == 40 && hex::decode(address).is_ok()
+}
+
+/// Checks a hex-encoded signature against a hex-encoded public key.
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let public_key: [u8; 32] = match hex::decode(public_key).ok().and_then(|b| b.try_into().ok()) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+    let signature: [u8; 64] = match hex::decode(signature).ok().and_then(|b| b.try_into().ok()) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+    match VerifyingKey::from_bytes(&public_key) {
+        Ok(key) => key.verify(message, &Signature::from_bytes(&signature)).is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use simple_blockchain::amount::COIN;
+use simple_blockchain::archive::{self, BlockReader, ExportFormat};
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::snapshot::Snapshot;
+use simple_blockchain::transaction::{Transaction, TxOutput};
+use simple_blockchain::wallet::Wallet;
+use tempfile::TempDir;
+
+fn open_chain(dir: &TempDir, alice: &Wallet) -> Blockchain {
+    let mut config = common::config(dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+    config.chain.pow.retarget_interval = 2;
+    Blockchain::open(&config).unwrap()
+}
+
+/// A chain of six blocks on top of genesis, with a payment from alice to
+/// bob in block 2.
+fn source_chain(dir: &TempDir, alice: &Wallet, bob: &Wallet, miner: &Wallet) -> Blockchain {
+    let mut chain = open_chain(dir, alice);
+    chain.mine(&miner.address).unwrap();
+    let inputs = chain.unspent_outputs(&alice.address).into_iter().map(|(input, _)| input).collect();
+    let mut payment = Transaction::new(inputs, vec![
+        TxOutput { amount: 20 * COIN, address: bob.address.clone() },
+        TxOutput { amount: 29 * COIN, address: alice.address.clone() },
+    ]);
+    payment.sign(alice);
+    chain.submit_transaction(payment).unwrap();
+    for _ in 0..5 {
+        chain.mine(&miner.address).unwrap();
+    }
+    chain
+}
+
+#[test]
+fn exported_chain_imports_in_either_format() {
+    let (alice, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let source_dir = TempDir::new().unwrap();
+    let source = source_chain(&source_dir, &alice, &bob, &miner);
+    let tip = source.get_last_block().unwrap();
+
+    for format in [ExportFormat::Json, ExportFormat::Binary] {
+        let path = source_dir.path().join(format!("chain.{}", format));
+        assert_eq!(archive::write_blocks(&path, format, source.export_blocks().unwrap()).unwrap(), 7);
+
+        let dir = TempDir::new().unwrap();
+        let mut chain = open_chain(&dir, &alice);
+        let reader = BlockReader::open(&path).unwrap();
+        assert_eq!(reader.format(), format);
+        let report = chain.import_blocks(reader).unwrap();
+        assert_eq!((report.imported, report.already_known), (6, 1));
+        assert_eq!(chain.get_last_block().unwrap().hash(), tip.hash());
+        assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 20 * COIN);
+        assert_eq!(chain.get_wallet_balance(&miner.address).unwrap(), source.get_wallet_balance(&miner.address).unwrap());
+        assert!(chain.validate_chain().first_invalid.is_none());
+
+        let again = chain.import_blocks(BlockReader::open(&path).unwrap()).unwrap();
+        assert_eq!((again.imported, again.already_known), (0, 7));
+    }
+}
+
+#[test]
+fn import_stops_at_a_tampered_block() {
+    let (alice, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let source_dir = TempDir::new().unwrap();
+    let source = source_chain(&source_dir, &alice, &bob, &miner);
+    let mut blocks: Vec<_> = source.export_blocks().unwrap().collect();
+    blocks[3].transactions[0].outputs[0].amount += COIN;
+    let path = source_dir.path().join("tampered.jsonl");
+    archive::write_blocks(&path, ExportFormat::Json, blocks).unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let mut chain = open_chain(&dir, &alice);
+    let error = chain.import_blocks(BlockReader::open(&path).unwrap()).unwrap_err();
+    assert!(error.starts_with("Block 3"), "{}", error);
+    assert_eq!(chain.get_last_block().unwrap().index, 2);
+}
+
+#[test]
+fn snapshot_bootstraps_a_new_node() {
+    let (alice, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let source_dir = TempDir::new().unwrap();
+    let source = source_chain(&source_dir, &alice, &bob, &miner);
+    let snapshot = source.snapshot(4).unwrap();
+    assert_eq!(snapshot.blocks.first().unwrap().index, 2);
+    assert_eq!(snapshot.balances().unwrap()[&bob.address], 20 * COIN);
+    let path = source_dir.path().join("snapshot.json");
+    snapshot.save(&path).unwrap();
+
+    let mut tampered = snapshot.clone();
+    tampered.utxos[0].output.amount += 1;
+    let dir = TempDir::new().unwrap();
+    let mut chain = open_chain(&dir, &alice);
+    assert!(chain.bootstrap(tampered).is_err());
+
+    chain.bootstrap(Snapshot::load(&path).unwrap()).unwrap();
+    assert_eq!(chain.snapshot_height(), Some(4));
+    assert_eq!(chain.get_last_block().unwrap().hash(), snapshot.block_hash);
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 20 * COIN);
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 29 * COIN);
+    assert!(chain.get_block(1).is_none());
+
+    let rest = source.export_blocks().unwrap().skip(5).map(Ok);
+    assert_eq!(chain.import_blocks(rest).unwrap().imported, 2);
+    assert_eq!(chain.get_last_block().unwrap().hash(), source.get_last_block().unwrap().hash());
+    assert_eq!(chain.get_wallet_balance(&miner.address).unwrap(), source.get_wallet_balance(&miner.address).unwrap());
+    assert!(chain.export_blocks().is_err());
+    assert!(chain.snapshot(3).is_err());
+    let report = chain.validate_chain();
+    assert!(report.first_invalid.is_none());
+    assert_eq!(report.blocks_checked, 2);
+    assert_eq!(chain.snapshot(6).unwrap().utxo_hash, source.snapshot(6).unwrap().utxo_hash);
+    chain.mine(&miner.address).unwrap();
+}
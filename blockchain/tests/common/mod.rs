@@ -0,0 +1,51 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use simple_blockchain::block::Block;
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::config::Config;
+use simple_blockchain::transaction::{Transaction, TxOutput};
+use simple_blockchain::wallet::Wallet;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A chain in `dir` mined at difficulty 1 without retargeting, whose
+/// genesis block pays `allocations`.
+pub fn config(dir: &TempDir, allocations: Vec<TxOutput>) -> Config {
+    let mut config = Config { data_dir: dir.path().to_path_buf(), ..Config::default() };
+    config.chain.pow.initial_difficulty = 1;
+    config.chain.pow.retarget_interval = 0;
+    config.chain.genesis_allocations = allocations;
+    config
+}
+
+pub fn open_chain(dir: &TempDir, allocations: Vec<TxOutput>) -> Blockchain {
+    Blockchain::open(&config(dir, allocations)).unwrap()
+}
+
+/// sled's flusher thread can hold the directory lock for a moment after the
+/// previous handle is dropped, so opening retries briefly.
+pub fn retry<T>(mut open: impl FnMut() -> Result<T, String>) -> Result<T, String> {
+    for _ in 0..50 {
+        match open() {
+            Err(e) if e.contains("could not acquire lock") => thread::sleep(Duration::from_millis(100)),
+            result => return result,
+        }
+    }
+    open()
+}
+
+pub fn reward(chain: &Blockchain) -> u64 {
+    chain.chain_config().block_reward
+}
+
+/// Mines a block on `parent`, which need not be the tip, paying `coinbase`
+/// base units to `miner`.
+pub fn mine_on(chain: &Blockchain, parent: &Block, miner: &Wallet, coinbase: u64, mut transactions: Vec<Transaction>) -> Block {
+    let index = parent.index + 1;
+    transactions.push(Transaction::coinbase(index, miner.address.clone(), coinbase).unwrap());
+    let mut block = Block::new(index, transactions, parent.hash(), chain.difficulty_after(parent));
+    Blockchain::proof_of_work(&mut block);
+    block
+}
@@ -0,0 +1,91 @@
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::config::Config;
+use simple_blockchain::consensus::ConsensusMode;
+use simple_blockchain::wallet::Wallet;
+use tempfile::TempDir;
+
+fn authority_config(dir: &TempDir, validators: &[&Wallet]) -> Config {
+    let mut config = Config { data_dir: dir.path().to_path_buf(), ..Config::default() };
+    config.chain.consensus = ConsensusMode::ProofOfAuthority {
+        validators: validators.iter().map(|w| w.public_key_hex()).collect(),
+    };
+    config
+}
+
+#[test]
+fn validators_sign_blocks_in_turn() {
+    let dir = TempDir::new().unwrap();
+    let (alice, bob, mallory) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let config = authority_config(&dir, &[&alice, &bob]);
+    let mut chain = Blockchain::open(&config).unwrap();
+    assert_eq!(chain.get_block(0).unwrap().difficulty, 0);
+    assert!(chain.mine(&alice.address).is_err());
+
+    assert!(chain.is_turn_of(&alice));
+    assert!(chain.sign_block(&bob).is_err());
+    let first = chain.sign_block(&alice).unwrap();
+    assert_eq!(first.validator, alice.public_key_hex());
+    assert!(chain.sign_block(&alice).is_err());
+    chain.sign_block(&bob).unwrap();
+    chain.sign_block(&alice).unwrap();
+    let reward = chain.chain_config().block_reward;
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 2 * reward);
+
+    // A block signed by an outsider, or by a validator out of turn, or with
+    // a signature that no longer matches the header, is refused.
+    let mut forged = chain.create_new_block(&mallory.address).unwrap();
+    forged.validator = mallory.public_key_hex();
+    forged.signature = mallory.sign(&forged.header_digest());
+    let error = chain.process_block(forged.clone()).unwrap_err();
+    assert!(error.contains("not signed by a validator"), "{}", error);
+    forged.validator = alice.public_key_hex();
+    forged.signature = alice.sign(&forged.header_digest());
+    let error = chain.process_block(forged.clone()).unwrap_err();
+    assert!(error.contains("out of turn"), "{}", error);
+    forged.validator = bob.public_key_hex();
+    forged.signature = bob.sign(&forged.header_digest());
+    forged.timestamp += 1;
+    let error = chain.process_block(forged).unwrap_err();
+    assert!(error.contains("signature"), "{}", error);
+
+    assert_eq!(chain.get_last_block().unwrap().index, 3);
+    assert!(chain.validate_chain().first_invalid.is_none());
+    drop(chain);
+
+    // The mode is fixed at genesis, whatever a later run asks for.
+    let reopened = Config { data_dir: dir.path().to_path_buf(), ..Config::default() };
+    let chain = Blockchain::open(&reopened).unwrap();
+    assert_eq!(chain.chain_config().consensus, config.chain.consensus);
+    assert!(chain.is_turn_of(&bob));
+}
+
+#[test]
+fn proof_of_work_chains_reject_sealed_blocks() {
+    let dir = TempDir::new().unwrap();
+    let miner = Wallet::new();
+    let mut config = Config { data_dir: dir.path().to_path_buf(), ..Config::default() };
+    config.chain.pow.initial_difficulty = 1;
+    let mut chain = Blockchain::open(&config).unwrap();
+    assert!(chain.sign_block(&miner).is_err());
+
+    let mut block = chain.create_new_block(&miner.address).unwrap();
+    block.validator = miner.public_key_hex();
+    Blockchain::proof_of_work(&mut block);
+    block.signature = miner.sign(&block.header_digest());
+    assert!(chain.process_block(block).is_err());
+    chain.mine(&miner.address).unwrap();
+}
+
+#[test]
+fn invalid_validator_sets_are_refused() {
+    let dir = TempDir::new().unwrap();
+    let alice = Wallet::new();
+    let mut config = authority_config(&dir, &[]);
+    assert!(Blockchain::open(&config).is_err());
+    config.chain.consensus = ConsensusMode::ProofOfAuthority { validators: vec![alice.address.clone()] };
+    assert!(Blockchain::open(&config).is_err());
+    config.chain.consensus = ConsensusMode::ProofOfAuthority {
+        validators: vec![alice.public_key_hex(), alice.public_key_hex()],
+    };
+    assert!(Blockchain::open(&config).is_err());
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use common::{config, retry};
+use proptest::prelude::*;
+use proptest::sample::Index;
+use simple_blockchain::amount::COIN;
+use simple_blockchain::block::{Block, BLOCK_VERSION};
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::encoding::{Decode, Encode};
+use simple_blockchain::pow;
+use simple_blockchain::transaction::{SpendingCondition, Timelock, Transaction, TxInput, TxOutput};
+use simple_blockchain::wallet::Wallet;
+use tempfile::TempDir;
+
+/// Recomputes the id of `tx` as a pre-binary-encoding transaction.
+fn legacy(mut tx: Transaction) -> Transaction {
+    tx.version = 0;
+    tx.id = tx.hash();
+    tx
+}
+
+fn mine_legacy(parent: &Block, transactions: Vec<Transaction>) -> Block {
+    let mut block = Block::new(parent.index + 1, transactions, parent.hash(), parent.difficulty);
+    block.version = 0;
+    Blockchain::proof_of_work(&mut block);
+    block
+}
+
+#[test]
+fn blocks_round_trip_through_the_binary_encoding() {
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    let mut payment = Transaction::new(
+        vec![TxInput::new("ab".repeat(32), 3)],
+        vec![TxOutput { amount: 5 * COIN, address: bob.address.clone() }],
+    );
+    payment.sign(&alice);
+    let coinbase = Transaction::coinbase(7, alice.address.clone(), 10 * COIN).unwrap();
+    let block = Block::new(7, vec![payment, coinbase], "cd".repeat(32), 3);
+
+    let bytes = block.to_bytes();
+    assert_eq!(bytes[0], BLOCK_VERSION);
+    assert!(bytes.len() < serde_json::to_vec(&block).unwrap().len());
+    let decoded = Block::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.hash(), block.hash());
+    assert_eq!(decoded.to_bytes(), bytes);
+    assert!(decoded.transactions[0].verify_input(&decoded.transactions[0].inputs[0], &alice.address));
+
+    assert!(Block::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(Block::from_bytes(&trailing).is_err());
+}
+
+#[test]
+fn versions_hash_differently() {
+    let address = Wallet::new().address;
+    let current = Transaction::coinbase(1, address.clone(), COIN).unwrap();
+    let old = legacy(current.clone());
+    assert_ne!(current.id, old.id);
+
+    let dir = TempDir::new().unwrap();
+    let config = config(&dir, vec![TxOutput { amount: COIN, address }]);
+    assert_ne!(config.chain.genesis_block().hash(), config.chain.genesis_block_at(0).hash());
+}
+
+#[test]
+fn json_store_is_migrated_and_verified() {
+    let dir = TempDir::new().unwrap();
+    let (alice, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let config = config(&dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+    let chain_config = config.chain.load_or_store(&config.data_dir).unwrap();
+
+    // A chain written before the binary encoding: version 0 blocks and
+    // transactions stored as JSON, with the indexes left to be rebuilt.
+    let genesis = chain_config.genesis_block_at(0);
+    let allocation = &genesis.transactions[0];
+    let mut payment = legacy(Transaction::new(
+        vec![TxInput::new(allocation.id.clone(), 0)],
+        vec![
+            TxOutput { amount: 20 * COIN, address: bob.address.clone() },
+            TxOutput { amount: 30 * COIN, address: alice.address.clone() },
+        ],
+    ));
+    payment.sign(&alice);
+    let coinbase = legacy(Transaction::coinbase(1, miner.address.clone(), chain_config.block_reward).unwrap());
+    let block = mine_legacy(&genesis, vec![payment, coinbase]);
+    // An orphan waiting on a parent that never arrived, keyed by arrival.
+    let missing = mine_legacy(&block, vec![legacy(Transaction::coinbase(2, miner.address.clone(), COIN).unwrap())]);
+    let orphan = mine_legacy(&missing, vec![legacy(Transaction::coinbase(3, miner.address.clone(), COIN).unwrap())]);
+    {
+        let db = sled::open(dir.path().join("blockchain")).unwrap();
+        let (blocks, block_work) = (db.open_tree("blocks").unwrap(), db.open_tree("block_work").unwrap());
+        let (best_chain, meta) = (db.open_tree("best_chain").unwrap(), db.open_tree("meta").unwrap());
+        let mut work = 0u128;
+        for block in [&genesis, &block] {
+            work += pow::block_work(block.difficulty);
+            blocks.insert(block.hash().as_bytes(), serde_json::to_vec(block).unwrap()).unwrap();
+            block_work.insert(block.hash().as_bytes(), &work.to_be_bytes()).unwrap();
+            best_chain.insert(block.index.to_be_bytes(), block.hash().as_bytes()).unwrap();
+        }
+        meta.insert(b"tip", block.hash().as_bytes()).unwrap();
+        let mut key = 0u64.to_be_bytes().to_vec();
+        key.extend_from_slice(orphan.hash().as_bytes());
+        db.open_tree("orphans").unwrap().insert(key, serde_json::to_vec(&orphan).unwrap()).unwrap();
+        db.flush().unwrap();
+    }
+
+    let refused = retry(|| Blockchain::open(&config)).err().unwrap();
+    assert!(refused.contains("JSON-encoded"), "{}", refused);
+
+    let report = retry(|| Blockchain::migrate_store(&config)).unwrap();
+    assert_eq!(report.blocks, 2);
+    assert_eq!(report.orphans, 1);
+    assert_eq!(report.chain.blocks_checked, 2);
+    assert!(report.chain.first_invalid.is_none());
+    assert!(retry(|| Blockchain::migrate_store(&config)).is_err());
+
+    let mut chain = retry(|| Blockchain::open(&config)).unwrap();
+    assert_eq!(chain.get_last_block().unwrap().hash(), block.hash());
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 20 * COIN);
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 30 * COIN);
+
+    // New blocks use the current version on top of the legacy ones.
+    let mut next = chain.create_new_block(&miner.address).unwrap();
+    Blockchain::proof_of_work(&mut next);
+    chain.process_block(next).unwrap();
+    assert_eq!(chain.get_last_block().unwrap().version, BLOCK_VERSION);
+    assert_eq!(chain.get_wallet_balance(&miner.address).unwrap(), 2 * chain_config.block_reward);
+    assert!(chain.validate_chain().first_invalid.is_none());
+
+    let mut downgrade = mine_legacy(&chain.get_last_block().unwrap(), Vec::new());
+    downgrade.transactions = vec![legacy(Transaction::coinbase(3, miner.address.clone(), COIN).unwrap())];
+    downgrade.merkle_root = downgrade.compute_merkle_root();
+    Blockchain::proof_of_work(&mut downgrade);
+    assert!(chain.process_block(downgrade).is_err());
+}
+
+/// A block exercising every optional part of the encoding: a multisig input
+/// with a timelock and signatures, and a validator seal.
+fn sample_block() -> Block {
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    let condition = SpendingCondition::new(1, vec![alice.public_key_hex(), bob.public_key_hex()], Some(Timelock::AfterBlocks(2))).unwrap();
+    let mut spend = Transaction::new(
+        vec![TxInput::with_condition("ab".repeat(32), 0, condition), TxInput::new("cd".repeat(32), 1)],
+        vec![TxOutput { amount: COIN, address: bob.address.clone() }],
+    );
+    spend.sign(&alice);
+    let coinbase = Transaction::coinbase(4, alice.address.clone(), 10 * COIN).unwrap();
+    let mut block = Block::new(4, vec![spend, coinbase], "ef".repeat(32), 0);
+    block.validator = alice.public_key_hex();
+    block.signature = alice.sign(&block.header_digest());
+    block
+}
+
+fn corrupt(mut bytes: Vec<u8>, edits: &[(Index, u8)], cut: Option<&Index>) -> Vec<u8> {
+    for (position, value) in edits {
+        let position = position.index(bytes.len());
+        bytes[position] = *value;
+    }
+    if let Some(cut) = cut {
+        bytes.truncate(cut.index(bytes.len()));
+    }
+    bytes
+}
+
+proptest! {
+    /// Corrupt bytes either fail to decode or decode to a value with that
+    /// exact encoding; they never panic.
+    #[test]
+    fn corrupted_encodings_decode_canonically_or_not_at_all(
+        edits in prop::collection::vec((any::<Index>(), any::<u8>()), 1..8),
+        cut in any::<Option<Index>>(),
+    ) {
+        let block = sample_block();
+        let bytes = corrupt(block.to_bytes(), &edits, cut.as_ref());
+        if let Ok(block) = Block::from_bytes(&bytes) {
+            prop_assert_eq!(&block.to_bytes(), &bytes);
+            block.hash();
+        }
+        let bytes = corrupt(block.transactions[0].to_bytes(), &edits, cut.as_ref());
+        if let Ok(tx) = Transaction::from_bytes(&bytes) {
+            prop_assert_eq!(&tx.to_bytes(), &bytes);
+            tx.hash();
+        }
+    }
+}
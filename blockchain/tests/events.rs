@@ -0,0 +1,117 @@
+mod common;
+
+use common::{mine_on, open_chain, reward};
+use simple_blockchain::amount::COIN;
+use simple_blockchain::events::{ChainEvent, EventFilter, Webhook};
+use simple_blockchain::transaction::{Transaction, TxOutput};
+use simple_blockchain::wallet::Wallet;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+fn drain(events: &Receiver<ChainEvent>) -> Vec<ChainEvent> {
+    events.try_iter().collect()
+}
+
+/// `(txid, confirmations)` of every transaction event.
+fn confirmations(events: &[ChainEvent]) -> Vec<(String, u64)> {
+    events.iter()
+        .filter_map(|event| match event {
+            ChainEvent::TransactionConfirmed { txid, confirmations, .. } => Some((txid.clone(), *confirmations)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn watched_payment_is_reported_when_mined_and_when_buried() {
+    let dir = TempDir::new().unwrap();
+    let (alice, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+    let events = chain.subscribe(EventFilter::new([bob.address.clone()], 3));
+    let everything = chain.subscribe(EventFilter::default());
+
+    let inputs = chain.unspent_outputs(&alice.address).into_iter().map(|(input, _)| input).collect();
+    let mut payment = Transaction::new(inputs, vec![
+        TxOutput { amount: 20 * COIN, address: bob.address.clone() },
+        TxOutput { amount: 29 * COIN, address: alice.address.clone() },
+    ]);
+    payment.sign(&alice);
+    let txid = payment.id.clone();
+    chain.submit_transaction(payment).unwrap();
+    chain.mine(&miner.address).unwrap();
+    let mined = drain(&events);
+    let tip = chain.get_last_block().unwrap();
+    assert_eq!(mined[0], ChainEvent::BlockConnected { index: 1, hash: tip.hash(), transactions: 2 });
+    assert_eq!(mined[1], ChainEvent::TransactionConfirmed {
+        txid: txid.clone(),
+        address: bob.address.clone(),
+        received: 20 * COIN,
+        sent: 0,
+        block_index: 1,
+        block_hash: tip.hash(),
+        confirmations: 1,
+    });
+    assert_eq!(mined.len(), 2);
+
+    chain.mine(&miner.address).unwrap();
+    assert!(confirmations(&drain(&events)).is_empty());
+    chain.mine(&miner.address).unwrap();
+    assert_eq!(confirmations(&drain(&events)), vec![(txid, 3)]);
+    chain.mine(&miner.address).unwrap();
+    assert!(confirmations(&drain(&events)).is_empty());
+
+    let blocks = drain(&everything);
+    assert_eq!(blocks.len(), 4);
+    assert!(blocks.iter().all(|event| matches!(event, ChainEvent::BlockConnected { .. })));
+}
+
+#[test]
+fn reorg_reports_disconnected_blocks_and_callbacks_see_them() {
+    let dir = TempDir::new().unwrap();
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    let mut chain = open_chain(&dir, Vec::new());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&seen);
+    chain.subscribe_with(EventFilter::new([bob.address.clone()], 0), move |event| {
+        log.lock().unwrap().push(event.clone());
+    });
+    let genesis = chain.get_block(0).unwrap();
+
+    let a1 = mine_on(&chain, &genesis, &alice, reward(&chain), Vec::new());
+    chain.process_block(a1.clone()).unwrap();
+    let b1 = mine_on(&chain, &genesis, &bob, reward(&chain), Vec::new());
+    let b2 = mine_on(&chain, &b1, &bob, reward(&chain), Vec::new());
+    chain.process_block(b1.clone()).unwrap();
+    chain.process_block(b2.clone()).unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen[1], ChainEvent::BlockDisconnected { index: 1, hash: a1.hash() });
+    assert!(matches!(&seen[2], ChainEvent::BlockConnected { index: 1, hash, .. } if *hash == b1.hash()));
+    assert_eq!(confirmations(&seen), vec![
+        (b1.transactions[0].id.clone(), 1),
+        (b2.transactions[0].id.clone(), 1),
+    ]);
+}
+
+#[test]
+fn webhook_posts_events_as_json() {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+    let received = thread::spawn(move || {
+        let mut request = server.recv().unwrap();
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body).unwrap();
+        let method = request.method().to_string();
+        request.respond(tiny_http::Response::empty(204)).unwrap();
+        (method, body)
+    });
+
+    let event = ChainEvent::BlockDisconnected { index: 7, hash: String::from("ab") };
+    Webhook::new(url).notify(&event).unwrap();
+    let (method, body) = received.join().unwrap();
+    assert_eq!(method, "POST");
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json, serde_json::json!({ "event": "block_disconnected", "index": 7, "hash": "ab" }));
+}
@@ -0,0 +1,54 @@
+use simple_blockchain::amount::COIN;
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::config::Config;
+use simple_blockchain::transaction::TxOutput;
+use simple_blockchain::wallet::Wallet;
+use tempfile::TempDir;
+
+fn config(dir: &TempDir, chain_id: &str) -> Config {
+    let mut config = Config { data_dir: dir.path().to_path_buf(), ..Config::default() };
+    config.chain.chain_id = chain_id.to_string();
+    config.chain.pow.initial_difficulty = 1;
+    config
+}
+
+#[test]
+fn genesis_allocations_fund_addresses() {
+    let dir = TempDir::new().unwrap();
+    let alice = Wallet::new();
+    let mut config = config(&dir, "test");
+    config.chain.genesis_allocations = vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }];
+
+    let chain = Blockchain::open(&config).unwrap();
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 50 * COIN);
+    assert!(chain.validate_chain().first_invalid.is_none());
+}
+
+#[test]
+fn chains_side_by_side_have_distinct_genesis() {
+    let (test_dir, staging_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let test = Blockchain::open(&config(&test_dir, "test")).unwrap();
+    let staging = Blockchain::open(&config(&staging_dir, "staging")).unwrap();
+    assert_ne!(test.get_block(0).unwrap().hash(), staging.get_block(0).unwrap().hash());
+}
+
+#[test]
+fn existing_chain_keeps_its_settings() {
+    let dir = TempDir::new().unwrap();
+    let miner = Wallet::new();
+    let mut config = config(&dir, "test");
+    config.chain.block_reward = 5 * COIN;
+    {
+        let mut chain = Blockchain::open(&config).unwrap();
+        chain.mine(&miner.address).unwrap();
+    }
+
+    config.chain.block_reward = 100 * COIN;
+    let mut chain = Blockchain::open(&config).unwrap();
+    chain.mine(&miner.address).unwrap();
+    assert_eq!(chain.get_wallet_balance(&miner.address).unwrap(), 10 * COIN);
+
+    drop(chain);
+    config.chain.chain_id = String::from("staging");
+    assert!(Blockchain::open(&config).is_err());
+}
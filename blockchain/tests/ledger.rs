@@ -0,0 +1,162 @@
+//! Randomized sequences of ledger operations against a chain on disk,
+//! checking after every step that the chain still adds up.
+
+mod common;
+
+use common::{config, retry};
+use proptest::prelude::*;
+use proptest::sample::Index;
+use simple_blockchain::amount::COIN;
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::transaction::TxOutput;
+use simple_blockchain::wallet::Wallet;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+const ALLOCATION: u64 = 50 * COIN;
+
+#[derive(Debug, Clone)]
+enum Op {
+    CreateWallet,
+    /// Sends `permille` of the sender's confirmed balance.
+    Send { from: Index, to: Index, permille: u64, fee: u64 },
+    Mine { miner: Index },
+    Reload,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        1 => Just(Op::CreateWallet),
+        4 => (any::<Index>(), any::<Index>(), 1..=1000u64, 0..10_000u64)
+            .prop_map(|(from, to, permille, fee)| Op::Send { from, to, permille, fee }),
+        3 => any::<Index>().prop_map(|miner| Op::Mine { miner }),
+        1 => Just(Op::Reload),
+    ]
+}
+
+/// What the chain should hold, tracked independently of it.
+struct Model {
+    /// Wallets are created in memory: the keystore's key derivation is too
+    /// slow to run in every case and holds no ledger state.
+    wallets: Vec<Wallet>,
+    balances: BTreeMap<String, u64>,
+    /// Accepted payments as `(from, to, amount, fee)`, not yet mined.
+    pending: Vec<(String, String, u64, u64)>,
+    supply: u64,
+}
+
+impl Model {
+    fn balance(&self, address: &str) -> u64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    fn credit(&mut self, address: &str, amount: u64) {
+        *self.balances.entry(address.to_string()).or_default() += amount;
+    }
+
+    fn debit(&mut self, address: &str, amount: u64) {
+        let balance = self.balances.get_mut(address).expect("debit from an unfunded address");
+        *balance = balance.checked_sub(amount).expect("balance went negative");
+    }
+}
+
+/// Everything that must survive closing and reopening the chain.
+#[derive(Debug, PartialEq)]
+struct State {
+    tip: String,
+    work: u128,
+    pending: Vec<String>,
+    utxo_hash: String,
+}
+
+fn state(chain: &Blockchain) -> State {
+    let tip = chain.get_last_block().unwrap();
+    let mut pending: Vec<String> = chain.pending_transactions().into_iter().map(|(_, tx)| tx.id).collect();
+    pending.sort();
+    State {
+        tip: tip.hash(),
+        work: chain.chain_work(),
+        pending,
+        utxo_hash: chain.snapshot(tip.index).unwrap().utxo_hash,
+    }
+}
+
+fn check_invariants(chain: &Blockchain, model: &Model) -> Result<(), TestCaseError> {
+    let report = chain.validate_chain();
+    prop_assert!(report.first_invalid.is_none(), "{:?}", report.first_invalid);
+
+    for wallet in &model.wallets {
+        prop_assert_eq!(chain.get_wallet_balance(&wallet.address).unwrap(), model.balance(&wallet.address));
+    }
+    let total: u64 = model.balances.values().sum();
+    prop_assert_eq!(total, model.supply);
+
+    // The UTXO set rebuilt by replaying every block agrees with the stored
+    // one: nothing is minted beyond the rewards, nor lost.
+    let tip = chain.get_last_block().unwrap().index;
+    let replayed = chain.snapshot(tip).unwrap().balances().unwrap();
+    let expected: BTreeMap<String, u64> = model.balances.iter()
+        .filter(|(_, balance)| **balance > 0)
+        .map(|(address, balance)| (address.clone(), *balance))
+        .collect();
+    prop_assert_eq!(replayed, expected);
+    Ok(())
+}
+
+fn run(ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let dir = TempDir::new().unwrap();
+    let founder = Wallet::new();
+    let config = config(&dir, vec![TxOutput { amount: ALLOCATION, address: founder.address.clone() }]);
+    let mut chain = Blockchain::open(&config).unwrap();
+    let mut model = Model {
+        wallets: vec![founder.clone(), Wallet::new()],
+        balances: BTreeMap::from([(founder.address, ALLOCATION)]),
+        pending: Vec::new(),
+        supply: ALLOCATION,
+    };
+
+    for op in ops.into_iter().chain([Op::Reload]) {
+        match op {
+            Op::CreateWallet => model.wallets.push(Wallet::new()),
+            Op::Send { from, to, permille, fee } => {
+                let from = from.get(&model.wallets).clone();
+                let to = to.get(&model.wallets).address.clone();
+                let amount = (model.balance(&from.address) / 1000 * permille).max(1);
+                match chain.send(&from, to.clone(), amount, fee) {
+                    Ok(_) => model.pending.push((from.address, to, amount, fee)),
+                    Err(e) => prop_assert!(e.contains("Insufficient funds"), "{}", e),
+                }
+            }
+            Op::Mine { miner } => {
+                let miner = miner.get(&model.wallets).address.clone();
+                let block = chain.mine(&miner).unwrap();
+                prop_assert_eq!(block.transactions.len(), model.pending.len() + 1);
+                let mut reward = chain.chain_config().block_subsidy(block.index);
+                model.supply += reward;
+                for (from, to, amount, fee) in std::mem::take(&mut model.pending) {
+                    model.debit(&from, amount + fee);
+                    model.credit(&to, amount);
+                    reward += fee;
+                }
+                model.credit(&miner, reward);
+            }
+            Op::Reload => {
+                let before = state(&chain);
+                drop(chain);
+                chain = retry(|| Blockchain::open(&config)).unwrap();
+                prop_assert_eq!(state(&chain), before);
+            }
+        }
+        check_invariants(&chain, &model)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn random_operations_keep_the_ledger_consistent(ops in prop::collection::vec(op(), 1..32)) {
+        run(ops)?;
+    }
+}
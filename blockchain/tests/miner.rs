@@ -0,0 +1,28 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use simple_blockchain::block::Block;
+use simple_blockchain::pow;
+use simple_blockchain::miner::{CancelToken, Miner};
+
+#[test]
+fn parallel_search_finds_a_valid_nonce() {
+    let block = Block::new(1, Vec::new(), String::from("parent"), 12);
+    let (found, progress) = Miner::new(4).mine(block, &CancelToken::default(), |_| {}).unwrap();
+    assert!(pow::valid_proof(&found));
+    assert!(progress.hashes > 0);
+}
+
+#[test]
+fn cancelled_search_stops_early() {
+    let block = Block::new(1, Vec::new(), String::from("parent"), 255);
+    let cancel = CancelToken::default();
+    let canceller = cancel.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        canceller.cancel();
+    });
+
+    let start = Instant::now();
+    assert!(Miner::new(2).mine(block, &cancel, |_| {}).is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
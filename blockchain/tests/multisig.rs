@@ -0,0 +1,113 @@
+mod common;
+
+use common::open_chain;
+use simple_blockchain::amount::COIN;
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::transaction::{SpendingCondition, Timelock, Transaction, TxInput, TxOutput};
+use simple_blockchain::wallet::Wallet;
+use tempfile::TempDir;
+
+fn condition(threshold: u32, keys: &[&Wallet], timelock: Option<Timelock>) -> SpendingCondition {
+    SpendingCondition::new(threshold, keys.iter().map(|w| w.public_key_hex()).collect(), timelock).unwrap()
+}
+
+/// Mines the next block with exactly `transactions` besides the coinbase,
+/// bypassing the mempool.
+fn mine_with(chain: &mut Blockchain, miner: &Wallet, mut transactions: Vec<Transaction>) -> Result<(), String> {
+    let mut block = chain.create_new_block(&miner.address)?;
+    let coinbase = block.transactions.pop().unwrap();
+    transactions.push(coinbase);
+    block.transactions = transactions;
+    block.merkle_root = block.compute_merkle_root();
+    Blockchain::proof_of_work(&mut block);
+    chain.process_block(block).map(|_| ())
+}
+
+#[test]
+fn two_of_three_multisig_needs_two_signatures() {
+    let dir = TempDir::new().unwrap();
+    let (a, b, c, outsider, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new());
+    let treasury = condition(2, &[&a, &b, &c], None);
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: treasury.address() }]);
+
+    let mut tx = chain.propose_spend(&treasury, bob.address.clone(), 10 * COIN, 0).unwrap();
+    let id = tx.id.clone();
+    assert_eq!(tx.sign(&outsider), 0);
+    assert_eq!(tx.sign(&a), 1);
+    assert_eq!(tx.sign(&a), 1);
+    assert!(chain.submit_transaction(tx.clone()).is_err());
+
+    assert_eq!(tx.sign(&c), 1);
+    assert_eq!(tx.id, id);
+    chain.submit_transaction(tx).unwrap();
+    chain.mine(&miner.address).unwrap();
+
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 10 * COIN);
+    assert_eq!(chain.get_wallet_balance(&treasury.address()).unwrap(), 40 * COIN);
+    let (_, mined) = chain.find_transaction(&id).unwrap();
+    assert_eq!(mined.inputs[0].condition.as_ref(), Some(&treasury));
+    assert_eq!(mined.inputs[0].signatures.len(), 2);
+    assert!(chain.validate_chain().first_invalid.is_none());
+}
+
+#[test]
+fn absolute_timelock_holds_until_its_height() {
+    let dir = TempDir::new().unwrap();
+    let (owner, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let vault = condition(1, &[&owner], Some(Timelock::AfterHeight(3)));
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: vault.address() }]);
+    assert!(chain.propose_spend(&vault, bob.address.clone(), COIN, 0).is_err());
+
+    let allocation = chain.get_block(0).unwrap().transactions[0].id.clone();
+    let mut early = Transaction::new(
+        vec![TxInput::with_condition(allocation, 0, vault.clone())],
+        vec![TxOutput { amount: 50 * COIN, address: bob.address.clone() }],
+    );
+    early.sign(&owner);
+    let error = chain.submit_transaction(early.clone()).unwrap_err();
+    assert!(error.contains("timelock"), "{}", error);
+    assert!(mine_with(&mut chain, &miner, vec![early.clone()]).is_err());
+
+    mine_with(&mut chain, &miner, Vec::new()).unwrap();
+    mine_with(&mut chain, &miner, Vec::new()).unwrap();
+    chain.submit_transaction(early).unwrap();
+    chain.mine(&miner.address).unwrap();
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 50 * COIN);
+    assert!(chain.validate_chain().first_invalid.is_none());
+}
+
+#[test]
+fn relative_timelock_counts_from_the_funding_block() {
+    let dir = TempDir::new().unwrap();
+    let (alice, owner, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new());
+    let vault = condition(1, &[&owner], Some(Timelock::AfterBlocks(2)));
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+
+    let inputs = chain.unspent_outputs(&alice.address).into_iter().map(|(input, _)| input).collect();
+    let mut funding = Transaction::new(inputs, vec![TxOutput { amount: 50 * COIN, address: vault.address() }]);
+    funding.sign(&alice);
+    chain.submit_transaction(funding).unwrap();
+    chain.mine(&miner.address).unwrap();
+
+    let error = chain.propose_spend(&vault, bob.address.clone(), 20 * COIN, 0).unwrap_err();
+    assert!(error.contains("unlocked"), "{}", error);
+    chain.mine(&miner.address).unwrap();
+
+    let mut tx = chain.propose_spend(&vault, bob.address.clone(), 20 * COIN, 0).unwrap();
+    tx.sign(&owner);
+    chain.submit_transaction(tx).unwrap();
+    chain.mine(&miner.address).unwrap();
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 20 * COIN);
+    assert_eq!(chain.get_wallet_balance(&vault.address()).unwrap(), 30 * COIN);
+    assert!(chain.validate_chain().first_invalid.is_none());
+}
+
+#[test]
+fn invalid_conditions_are_rejected() {
+    let (a, b) = (Wallet::new(), Wallet::new());
+    assert!(SpendingCondition::new(3, vec![a.public_key_hex(), b.public_key_hex()], None).is_err());
+    assert!(SpendingCondition::new(0, vec![a.public_key_hex()], None).is_err());
+    assert!(SpendingCondition::new(1, vec![a.public_key_hex(), a.public_key_hex()], None).is_err());
+    assert!(SpendingCondition::new(1, vec![a.address.clone()], None).is_err());
+    assert_ne!(condition(1, &[&a], None).address(), a.address);
+}
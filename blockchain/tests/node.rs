@@ -0,0 +1,62 @@
+mod common;
+
+use common::config;
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::node::{Message, Node, Producer};
+use simple_blockchain::wallet::Wallet;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn free_address() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+fn request(peer: &str, message: &Message) -> Option<Message> {
+    let mut stream = TcpStream::connect(peer).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+    writeln!(stream, "{}", serde_json::to_string(message).unwrap()).ok()?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).ok()?;
+    serde_json::from_str(&response).ok()
+}
+
+/// The height and hash of the node's best block.
+fn tip(peer: &str) -> Option<(u64, String)> {
+    let Message::Status { height, .. } = request(peer, &Message::GetStatus)? else {
+        return None;
+    };
+    let Message::Blocks(blocks) = request(peer, &Message::GetBlocks { from: height })? else {
+        return None;
+    };
+    Some((height, blocks.first()?.hash()))
+}
+
+#[test]
+fn mining_nodes_converge_on_one_tip() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let addresses = [free_address(), free_address()];
+    for (i, dir) in dirs.iter().enumerate() {
+        // Hard enough that blocks take a moment, so the nodes hear of each
+        // other's blocks before finding the next one.
+        let mut config = config(dir, Vec::new());
+        config.chain.pow.initial_difficulty = 16;
+        let chain = Blockchain::open(&config).unwrap();
+        let node = Node::new(chain, addresses[i].clone(), vec![addresses[1 - i].clone()]);
+        let miner = Wallet::new().address;
+        thread::spawn(move || node.run(Some(Producer::Miner(miner))));
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(120);
+    loop {
+        if let (Some(first), Some(second)) = (tip(&addresses[0]), tip(&addresses[1])) {
+            if first.0 >= 5 && first == second {
+                break;
+            }
+        }
+        assert!(Instant::now() < deadline, "nodes did not converge");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
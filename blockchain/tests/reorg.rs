@@ -0,0 +1,268 @@
+mod common;
+
+use common::{mine_on, open_chain, reward};
+use simple_blockchain::amount::COIN;
+use simple_blockchain::blockchain::BlockStatus;
+use simple_blockchain::transaction::{Transaction, TxOutput};
+use simple_blockchain::wallet::Wallet;
+use tempfile::TempDir;
+
+#[test]
+fn heavier_side_chain_triggers_reorg() {
+    let dir = TempDir::new().unwrap();
+    let mut chain = open_chain(&dir, Vec::new());
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    let genesis = chain.get_block(0).unwrap();
+
+    let a1 = mine_on(&chain, &genesis, &alice, reward(&chain), Vec::new());
+    let a2 = mine_on(&chain, &a1, &alice, reward(&chain), Vec::new());
+    assert_eq!(chain.process_block(a1.clone()).unwrap(), BlockStatus::Connected);
+    assert_eq!(chain.process_block(a2.clone()).unwrap(), BlockStatus::Connected);
+
+    let b1 = mine_on(&chain, &genesis, &bob, reward(&chain), Vec::new());
+    let b2 = mine_on(&chain, &b1, &bob, reward(&chain), Vec::new());
+    let b3 = mine_on(&chain, &b2, &bob, reward(&chain), Vec::new());
+    assert_eq!(chain.process_block(b1.clone()).unwrap(), BlockStatus::SideChain);
+    assert_eq!(chain.process_block(b2).unwrap(), BlockStatus::SideChain);
+    assert_eq!(chain.get_last_block().unwrap().hash(), a2.hash());
+
+    let status = chain.process_block(b3.clone()).unwrap();
+    assert_eq!(status, BlockStatus::Reorganized { disconnected: 2, connected: 3 });
+    assert_eq!(chain.get_last_block().unwrap().hash(), b3.hash());
+    assert_eq!(chain.get_block(1).unwrap().hash(), b1.hash());
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 0);
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 3 * reward(&chain));
+    assert!(chain.find_transaction(&a1.transactions[0].id).is_none());
+    assert!(chain.get_block_by_hash(&a1.hash()).is_some());
+    assert!(chain.validate_chain().first_invalid.is_none());
+    assert_eq!(chain.process_block(a2).unwrap(), BlockStatus::AlreadyKnown);
+}
+
+#[test]
+fn reorg_returns_abandoned_transactions_to_mempool() {
+    let dir = TempDir::new().unwrap();
+    let (alice, carol, miner_a, miner_b) = (Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new());
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+    let genesis = chain.get_block(0).unwrap();
+
+    let inputs = chain.unspent_outputs(&alice.address).into_iter().map(|(input, _)| input).collect();
+    let mut payment = Transaction::new(inputs, vec![
+        TxOutput { amount: 10 * COIN, address: carol.address.clone() },
+        TxOutput { amount: 40 * COIN, address: alice.address.clone() },
+    ]);
+    payment.sign(&alice);
+    chain.submit_transaction(payment.clone()).unwrap();
+
+    let a1 = mine_on(&chain, &genesis, &miner_a, reward(&chain), vec![payment.clone()]);
+    chain.process_block(a1).unwrap();
+    assert_eq!(chain.get_wallet_balance(&carol.address).unwrap(), 10 * COIN);
+    assert!(chain.pending_transactions().is_empty());
+
+    let b1 = mine_on(&chain, &genesis, &miner_b, reward(&chain), Vec::new());
+    let b2 = mine_on(&chain, &b1, &miner_b, reward(&chain), Vec::new());
+    chain.process_block(b1).unwrap();
+    chain.process_block(b2.clone()).unwrap();
+    assert_eq!(chain.get_wallet_balance(&carol.address).unwrap(), 0);
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 50 * COIN);
+    let pending: Vec<String> = chain.pending_transactions().into_iter().map(|(_, tx)| tx.id).collect();
+    assert_eq!(pending, vec![payment.id.clone()]);
+
+    let b3 = mine_on(&chain, &b2, &miner_b, reward(&chain), vec![payment]);
+    assert_eq!(chain.process_block(b3).unwrap(), BlockStatus::Connected);
+    assert_eq!(chain.get_wallet_balance(&carol.address).unwrap(), 10 * COIN);
+    assert!(chain.pending_transactions().is_empty());
+}
+
+#[test]
+fn orphans_connect_once_their_parent_arrives() {
+    let dir = TempDir::new().unwrap();
+    let mut chain = open_chain(&dir, Vec::new());
+    let miner = Wallet::new();
+    let genesis = chain.get_block(0).unwrap();
+
+    let b1 = mine_on(&chain, &genesis, &miner, reward(&chain), Vec::new());
+    let b2 = mine_on(&chain, &b1, &miner, reward(&chain), Vec::new());
+    let b3 = mine_on(&chain, &b2, &miner, reward(&chain), Vec::new());
+    assert_eq!(chain.process_block(b3.clone()).unwrap(), BlockStatus::Orphan);
+    assert_eq!(chain.process_block(b2).unwrap(), BlockStatus::Orphan);
+    assert_eq!(chain.get_last_block().unwrap().index, 0);
+
+    assert_eq!(chain.process_block(b1).unwrap(), BlockStatus::Connected);
+    assert_eq!(chain.get_last_block().unwrap().hash(), b3.hash());
+    assert_eq!(chain.get_wallet_balance(&miner.address).unwrap(), 3 * reward(&chain));
+}
+
+#[test]
+fn invalid_branch_leaves_best_chain_untouched() {
+    let dir = TempDir::new().unwrap();
+    let mut chain = open_chain(&dir, Vec::new());
+    let (alice, mallory) = (Wallet::new(), Wallet::new());
+    let genesis = chain.get_block(0).unwrap();
+
+    let a1 = mine_on(&chain, &genesis, &alice, reward(&chain), Vec::new());
+    chain.process_block(a1.clone()).unwrap();
+
+    let m1 = mine_on(&chain, &genesis, &mallory, reward(&chain), Vec::new());
+    let m2 = mine_on(&chain, &m1, &mallory, 1_000 * COIN, Vec::new());
+    assert_eq!(chain.process_block(m1.clone()).unwrap(), BlockStatus::SideChain);
+    assert!(chain.process_block(m2.clone()).is_err());
+
+    assert_eq!(chain.get_last_block().unwrap().hash(), a1.hash());
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), reward(&chain));
+    assert_eq!(chain.get_wallet_balance(&mallory.address).unwrap(), 0);
+    assert!(chain.get_block_by_hash(&m2.hash()).is_none());
+    assert!(chain.get_block_by_hash(&m1.hash()).is_some());
+    assert!(chain.validate_chain().first_invalid.is_none());
+}
+
+#[test]
+fn repeated_transactions_cannot_shadow_a_valid_block() {
+    let dir = TempDir::new().unwrap();
+    let (alice, bob, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let allocation = TxOutput { amount: 50 * COIN, address: alice.address.clone() };
+    let mut chain = open_chain(&dir, vec![allocation.clone(), allocation]);
+    let genesis = chain.get_block(0).unwrap();
+
+    let payments: Vec<Transaction> = chain.unspent_outputs(&alice.address).into_iter()
+        .map(|(input, _)| {
+            let mut payment = Transaction::new(vec![input], vec![TxOutput { amount: 50 * COIN, address: bob.address.clone() }]);
+            payment.sign(&alice);
+            payment
+        })
+        .collect();
+    let b1 = mine_on(&chain, &genesis, &miner, reward(&chain), Vec::new());
+    let b2 = mine_on(&chain, &b1, &miner, reward(&chain), payments);
+    assert_eq!(b2.transactions.len(), 3);
+
+    // Repeating the last of an odd number of transactions keeps the merkle
+    // root, and with it the block hash.
+    let mut mutated = b2.clone();
+    mutated.transactions.push(b2.transactions[2].clone());
+    assert_eq!(mutated.compute_merkle_root(), b2.merkle_root);
+    assert_eq!(mutated.hash(), b2.hash());
+
+    // Arriving before its parent, the copy would be held as an orphan under
+    // the real block's hash.
+    assert!(chain.process_block(mutated).unwrap_err().contains("repeats a transaction"));
+    assert_eq!(chain.process_block(b2.clone()).unwrap(), BlockStatus::Orphan);
+    assert_eq!(chain.process_block(b1).unwrap(), BlockStatus::Connected);
+    assert_eq!(chain.get_last_block().unwrap().hash(), b2.hash());
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 100 * COIN);
+}
+
+#[test]
+fn forged_transaction_ids_are_rejected() {
+    let dir = TempDir::new().unwrap();
+    let (alice, mallory, miner) = (Wallet::new(), Wallet::new(), Wallet::new());
+    let mut chain = open_chain(&dir, vec![
+        TxOutput { amount: 50 * COIN, address: alice.address.clone() },
+        TxOutput { amount: COIN, address: mallory.address.clone() },
+    ]);
+    let genesis = chain.get_block(0).unwrap();
+    let (mallory_input, _) = chain.unspent_outputs(&mallory.address).remove(0);
+
+    // Validly signed, but claiming the id of the genesis allocations, so its
+    // output would land on alice's unspent output.
+    let mut forged = Transaction::new(vec![mallory_input], vec![TxOutput { amount: COIN, address: mallory.address.clone() }]);
+    forged.sign(&mallory);
+    forged.id = genesis.transactions[0].id.clone();
+    let block = mine_on(&chain, &genesis, &miner, reward(&chain), vec![forged]);
+
+    assert!(chain.process_block(block).unwrap_err().contains("mismatched or repeated id"));
+    assert_eq!(chain.get_last_block().unwrap().hash(), genesis.hash());
+    assert_eq!(chain.get_wallet_balance(&alice.address).unwrap(), 50 * COIN);
+    assert_eq!(chain.get_wallet_balance(&mallory.address).unwrap(), COIN);
+    assert!(chain.validate_chain().first_invalid.is_none());
+}
+
+#[test]
+fn coinbases_paying_nothing_are_rejected() {
+    let dir = TempDir::new().unwrap();
+    let miner = Wallet::new();
+    let mut chain = open_chain(&dir, Vec::new());
+    let genesis = chain.get_block(0).unwrap();
+
+    let block = mine_on(&chain, &genesis, &miner, 0, Vec::new());
+    let error = chain.process_block(block).unwrap_err();
+    assert!(error.contains("coinbase"), "{}", error);
+    assert_eq!(chain.get_last_block().unwrap().hash(), genesis.hash());
+}
+
+#[test]
+fn pending_double_spends_are_evicted_when_a_block_spends_their_inputs() {
+    let dir = TempDir::new().unwrap();
+    let (alice, bob, carol, miner) = (Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new());
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+    let genesis = chain.get_block(0).unwrap();
+    let (input, _) = chain.unspent_outputs(&alice.address).remove(0);
+
+    let mut to_bob = Transaction::new(vec![input.clone()], vec![TxOutput { amount: 50 * COIN, address: bob.address.clone() }]);
+    to_bob.sign(&alice);
+    chain.submit_transaction(to_bob).unwrap();
+
+    // A peer mines a different spend of the same output.
+    let mut to_carol = Transaction::new(vec![input], vec![TxOutput { amount: 50 * COIN, address: carol.address.clone() }]);
+    to_carol.sign(&alice);
+    let peer_block = mine_on(&chain, &genesis, &miner, reward(&chain), vec![to_carol]);
+    assert_eq!(chain.process_block(peer_block).unwrap(), BlockStatus::Connected);
+    assert!(chain.pending_transactions().is_empty());
+
+    // Local mining carries on from the peer's block.
+    let block = chain.mine(&miner.address).unwrap();
+    assert_eq!(block.index, 2);
+    assert_eq!(block.transactions.len(), 1);
+    assert_eq!(chain.get_wallet_balance(&carol.address).unwrap(), 50 * COIN);
+    assert_eq!(chain.get_wallet_balance(&bob.address).unwrap(), 0);
+}
+
+#[test]
+fn reorg_requeues_abandoned_payments_and_evicts_what_spent_them() {
+    let dir = TempDir::new().unwrap();
+    let (alice, carol, dave, miner_a, miner_b) = (Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new(), Wallet::new());
+    let mut chain = open_chain(&dir, vec![TxOutput { amount: 50 * COIN, address: alice.address.clone() }]);
+    let genesis = chain.get_block(0).unwrap();
+
+    let inputs = chain.unspent_outputs(&alice.address).into_iter().map(|(input, _)| input).collect();
+    let mut payment = Transaction::new(inputs, vec![TxOutput { amount: 50 * COIN, address: carol.address.clone() }]);
+    payment.sign(&alice);
+    let a1 = mine_on(&chain, &genesis, &miner_a, reward(&chain), vec![payment.clone()]);
+    assert_eq!(chain.process_block(a1).unwrap(), BlockStatus::Connected);
+
+    // Carol spends the output she was paid in the block about to be abandoned.
+    chain.send(&carol, dave.address.clone(), 20 * COIN, 0).unwrap();
+    assert_eq!(chain.pending_transactions().len(), 1);
+
+    let b1 = mine_on(&chain, &genesis, &miner_b, reward(&chain), Vec::new());
+    let b2 = mine_on(&chain, &b1, &miner_b, reward(&chain), Vec::new());
+    chain.process_block(b1).unwrap();
+    assert_eq!(chain.process_block(b2).unwrap(), BlockStatus::Reorganized { disconnected: 1, connected: 2 });
+
+    let pending: Vec<String> = chain.pending_transactions().into_iter().map(|(_, tx)| tx.id).collect();
+    assert_eq!(pending, vec![payment.id]);
+    let block = chain.mine(&miner_b.address).unwrap();
+    assert_eq!(block.transactions.len(), 2);
+    assert_eq!(chain.get_wallet_balance(&carol.address).unwrap(), 50 * COIN);
+    assert_eq!(chain.get_wallet_balance(&dave.address).unwrap(), 0);
+}
+
+#[test]
+fn full_orphan_pool_drops_the_longest_waiting() {
+    let dir = TempDir::new().unwrap();
+    let mut chain = open_chain(&dir, Vec::new());
+    let miner = Wallet::new();
+    let mut blocks = vec![chain.get_block(0).unwrap()];
+    for _ in 0..102 {
+        let block = mine_on(&chain, blocks.last().unwrap(), &miner, reward(&chain), Vec::new());
+        blocks.push(block);
+    }
+
+    // 101 orphans arrive for a pool of 100, block 2 first.
+    for block in &blocks[2..] {
+        assert_eq!(chain.process_block(block.clone()).unwrap(), BlockStatus::Orphan);
+    }
+    assert_eq!(chain.process_block(blocks[1].clone()).unwrap(), BlockStatus::Connected);
+    assert_eq!(chain.get_last_block().unwrap().index, 1);
+
+    assert_eq!(chain.process_block(blocks[2].clone()).unwrap(), BlockStatus::Connected);
+    assert_eq!(chain.get_last_block().unwrap().hash(), blocks[102].hash());
+}
@@ -0,0 +1,77 @@
+//! Compares the storage backends on writes, reads of present keys and reads
+//! of absent keys. Logs are never synced, so the engines are measured rather
+//! than the disk.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use distributed_kv_store::server::storage::{self, Backend, Options, Storage, SyncPolicy};
+use tempfile::TempDir;
+
+const KEYS: u64 = 20_000;
+
+fn options() -> Options {
+    Options { sync: SyncPolicy::Never, memtable_bytes: 256 << 10, ..Options::default() }
+}
+
+fn open(backend: Backend, dir: &TempDir) -> Box<dyn Storage> {
+    storage::open(backend, Some(dir.path()), options()).unwrap()
+}
+
+/// Spreads keys over the key space instead of writing them in order.
+fn key(i: u64) -> String {
+    format!("key{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+fn populated(backend: Backend) -> (TempDir, Box<dyn Storage>) {
+    let dir = TempDir::new().unwrap();
+    let mut storage = open(backend, &dir);
+    for i in 0..KEYS {
+        storage.set(&key(i), &format!("value{}", i)).unwrap();
+    }
+    // Reopen so the LSM tree serves reads from its tables.
+    drop(storage);
+    let storage = open(backend, &dir);
+    (dir, storage)
+}
+
+fn backends(c: &mut Criterion) {
+    for backend in [Backend::Hashmap, Backend::Lsm] {
+        let name = format!("{:?}", backend).to_lowercase();
+
+        c.bench_function(&format!("{}/set {} keys", name, KEYS), |b| {
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |dir| {
+                    let mut storage = open(backend, &dir);
+                    for i in 0..KEYS {
+                        storage.set(&key(i), "value").unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+
+        let (_dir, storage) = populated(backend);
+        let mut group = c.benchmark_group(name);
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("get", "present"), |b| {
+            b.iter(|| {
+                i = (i + 7919) % KEYS;
+                storage.get(&key(i)).unwrap().unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("get", "absent"), |b| {
+            b.iter(|| {
+                i += 1;
+                storage.get(&key(KEYS + i)).unwrap()
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = backends
+}
+criterion_main!(benches);
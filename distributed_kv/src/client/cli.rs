@@ -1,15 +1,17 @@
 use std::error::Error;
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, stdin, stdout};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, stdin, stdout};
 use tokio::net::TcpStream;
 
 pub async fn start_cli(server: &str) -> Result<(), Box<dyn Error>> {
-    let mut stream = TcpStream::connect(server).await?;
+    let (reader, mut writer) = TcpStream::connect(server).await?.into_split();
+    let mut reader = BufReader::new(reader);
     println!("Connected to server at {}", server);
     println!("Available commands:");
     println!("  GET <key>");
     println!("  SET <key> <value>");
     println!("  DELETE <key>");
     println!("  LIST");
+    println!("  CLUSTER");
     println!("  exit");
 
     let mut stdin = BufReader::new(stdin());
@@ -20,20 +22,31 @@ pub async fn start_cli(server: &str) -> Result<(), Box<dyn Error>> {
         stdout.flush().await?;
 
         let mut input = String::new();
-        stdin.read_line(&mut input).await?;
+        if stdin.read_line(&mut input).await? == 0 {
+            break;
+        }
 
         let command = input.trim();
         if command == "exit" {
             break;
         }
 
-        stream.write_all(command.as_bytes()).await?;
-        stream.write_all(b"\n").await?;
-        stream.flush().await?;
+        writer.write_all(command.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+        writer.flush().await?;
 
+        // The response runs up to the first empty line.
         let mut response = String::new();
-        let mut reader = BufReader::new(&mut stream);
-        reader.read_line(&mut response).await?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err("The server closed the connection".into());
+            }
+            if line.trim_end().is_empty() {
+                break;
+            }
+            response.push_str(&line);
+        }
 
         stdout.write_all(b"Server response: ").await?;
         stdout.write_all(response.as_bytes()).await?;
@@ -1,8 +1,7 @@
-mod server;
-mod client;
-
 use clap::{Parser, Subcommand};
+use distributed_kv_store::{client, server};
 use std::error::Error;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -16,6 +15,36 @@ enum Commands {
     Server {
         #[arg(short, long, default_value = "127.0.0.1:8080")]
         address: String,
+        /// The other nodes of the Raft cluster, comma-separated
+        #[arg(long, value_delimiter = ',')]
+        peers: Vec<String>,
+        /// Directory to persist data in; kept in memory only if omitted
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Storage engine; lsm needs --data-dir
+        #[arg(long, value_enum, default_value = "hashmap")]
+        storage: server::storage::Backend,
+        /// When to force the write-ahead log to disk
+        #[arg(long, value_enum, default_value = "always")]
+        fsync: server::storage::SyncPolicy,
+        /// Log records after which a snapshot is taken and the log emptied
+        #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+        snapshot_every: u64,
+        /// Bytes the lsm memtable holds before it is written to disk
+        #[arg(long, default_value_t = 4 << 20)]
+        memtable_size: usize,
+        /// Nodes to partition keys across, comma-separated; enables sharding
+        #[arg(long, value_delimiter = ',')]
+        shards: Vec<String>,
+        /// A node of an existing ring to join; enables sharding
+        #[arg(long)]
+        join: Option<String>,
+        /// Nodes holding each key when sharding
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        replicas: u64,
+        /// Positions each node takes on the hash ring
+        #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+        vnodes: u64,
     },
     Client {
         #[arg(short, long, default_value = "127.0.0.1:8080")]
@@ -30,8 +59,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Server { address } => {
-            server::run_server(address).await?;
+        Commands::Server {
+            address,
+            peers,
+            data_dir,
+            storage,
+            fsync,
+            snapshot_every,
+            memtable_size,
+            shards,
+            join,
+            replicas,
+            vnodes,
+        } => {
+            let options = server::storage::Options {
+                sync: *fsync,
+                snapshot_every: *snapshot_every,
+                memtable_bytes: *memtable_size,
+            };
+            let sharding = (!shards.is_empty() || join.is_some()).then(|| server::sharding::ShardOptions {
+                members: shards.clone(),
+                join: join.clone(),
+                vnodes: *vnodes as usize,
+                replicas: *replicas as usize,
+            });
+            server::run_server(address, peers, *storage, data_dir.as_deref(), options, sharding).await?;
         }
         Commands::Client { server } => {
             client::run_client(server).await?;
@@ -1,16 +1,59 @@
 mod network;
-mod storage;
+mod raft;
+pub mod storage;
 mod replication;
+pub mod sharding;
+#[cfg(test)]
+mod simulator;
+mod wal;
 
 use std::error::Error;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
-pub async fn run_server(address: &str) -> Result<(), Box<dyn Error>> {
-    let storage = Arc::new(Mutex::new(storage::Storage::new()));
-    let replication = Arc::new(Mutex::new(replication::Replication::new()));
+use sharding::{ShardOptions, Sharding};
+use storage::{Backend, Options, Storage, SyncPolicy};
 
-    network::start_server(address, storage, replication).await?;
+/// Serves clients on `address` as one node of a Raft cluster with `peers`,
+/// which must list every other node. Without peers the node leads alone.
+/// Data is kept by the `backend` engine, in `data_dir` if given. With
+/// `shards`, keys are partitioned across a consistent-hash ring of nodes.
+pub async fn run_server(
+    address: &str,
+    peers: &[String],
+    backend: Backend,
+    data_dir: Option<&Path>,
+    options: Options,
+    shards: Option<ShardOptions>,
+) -> Result<(), Box<dyn Error>> {
+    let storage = Arc::new(Mutex::new(storage::open(backend, data_dir, options)?));
+    if data_dir.is_some() && options.sync == SyncPolicy::Periodic {
+        tokio::spawn(sync_periodically(Arc::clone(&storage)));
+    }
+    let (replication, failed) = replication::Replication::start(address, peers, data_dir, Arc::clone(&storage))?;
+    let sharding = shards.map(|shards| {
+        let sharding = Arc::new(Sharding::new(address, &shards));
+        if let Some(through) = shards.join {
+            let (sharding, storage, replication) = (Arc::clone(&sharding), Arc::clone(&storage), Arc::clone(&replication));
+            tokio::spawn(async move { sharding.join(&through, &storage, &replication).await });
+        }
+        sharding
+    });
 
-    Ok(())
-}
\ No newline at end of file
+    tokio::select! {
+        served = network::start_server(address, storage, replication, sharding) => served,
+        Ok(e) = failed => Err(e.into()),
+    }
+}
+
+async fn sync_periodically(storage: Arc<Mutex<Box<dyn Storage>>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.lock().await.sync() {
+            log::error!("Cannot sync the write-ahead log: {}", e);
+        }
+    }
+}
@@ -1,16 +1,21 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 
-use super::storage::Storage;
-use super::replication::Replication;
+use super::storage::{Operation, Storage};
+use super::raft::Role;
+use super::replication::{self, Replication};
+use super::sharding::{self, MemberChange, Sharding};
 
 pub async fn start_server(
     address: &str,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
     replication: Arc<Mutex<Replication>>,
+    sharding: Option<Arc<Sharding>>,
 ) -> Result<(), Box<dyn Error>> {
     let listener = TcpListener::bind(address).await?;
     log::info!("Server listening on {}", address);
@@ -19,18 +24,23 @@ pub async fn start_server(
         let (socket, _) = listener.accept().await?;
         let storage = Arc::clone(&storage);
         let replication = Arc::clone(&replication);
+        let sharding = sharding.clone();
 
         tokio::spawn(async move {
-            handle_connection(socket, storage, replication).await;
+            handle_connection(socket, storage, replication, sharding).await;
         });
     }
 }
 
 async fn handle_connection(
     mut socket: TcpStream,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
     replication: Arc<Mutex<Replication>>,
+    sharding: Option<Arc<Sharding>>,
 ) {
+    let Ok(peer) = socket.peer_addr() else {
+        return;
+    };
     let (reader, mut writer) = socket.split();
     let mut reader = BufReader::new(reader);
     let mut line = String::new();
@@ -39,10 +49,22 @@ async fn handle_connection(
         if bytes_read == 0 {
             break;
         }
+        // Another node of the cluster; the connection carries only Raft
+        // messages from here on.
+        if let Some(peer) = line.trim().strip_prefix("RAFT ") {
+            let peer = peer.to_string();
+            if let Err(e) = replication::receive(&peer, &mut reader, &replication).await {
+                log::warn!("Raft connection from {} failed: {}", peer, e);
+            }
+            break;
+        }
 
-        let response = process_command(&line, &storage, &replication).await;
-        writer.write_all(response.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
+        // An empty line ends each response, however many lines it has.
+        let response = process_command(&line, peer, &storage, &replication, sharding.as_ref()).await;
+        let written = writer.write_all(format!("{}\n", response).as_bytes()).await;
+        if written.is_err() || writer.flush().await.is_err() {
+            break;
+        }
 
         line.clear();
     }
@@ -50,34 +72,100 @@ async fn handle_connection(
 
 async fn process_command(
     command: &str,
-    storage: &Arc<Mutex<Storage>>,
+    peer: SocketAddr,
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
     replication: &Arc<Mutex<Replication>>,
+    sharding: Option<&Arc<Sharding>>,
 ) -> String {
-    let parts: Vec<&str> = command.trim().split_whitespace().collect();
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    let Some(sharding) = sharding else {
+        return execute(&parts, storage, replication).await;
+    };
 
     match parts.as_slice() {
+        ["GET", key] | ["SET", key, _] | ["DELETE", key] => {
+            route(&parts, key, storage, replication, sharding).await
+        }
+        // Sent by a node that does not own the key.
+        ["COORDINATE", rest @ ..] => match rest {
+            ["SET", key, _] | ["DELETE", key] => {
+                let owners = sharding.ring().await.owners(key);
+                if owners.iter().any(|owner| owner == sharding.address()) {
+                    coordinate(rest, &owners, storage, replication, sharding).await
+                } else {
+                    format!("Not an owner of {} in ring version {}\n", key, sharding.ring().await.version())
+                }
+            }
+            // Sent to the coordinator by the node that was asked.
+            ["CLUSTER", "JOIN", node] => {
+                sharding.coordinate_change(MemberChange::Join(node.to_string()), storage, replication).await
+            }
+            ["CLUSTER", "LEAVE", node] => {
+                sharding.coordinate_change(MemberChange::Leave(node.to_string()), storage, replication).await
+            }
+            _ => "Invalid command\n".to_string(),
+        },
+        // Sent by an owner: served from this node's storage alone.
+        ["REPLICA", sender, rest @ ..] => {
+            if sharding.is_peer(sender, peer).await {
+                serve_replica(rest, storage, replication, sharding).await
+            } else {
+                format!("{} is not a member of the ring\n", sender)
+            }
+        }
+        ["CLUSTER"] => sharding.ring().await.describe(),
+        ["CLUSTER", "JOIN", node] => sharding.change(MemberChange::Join(node.to_string()), storage, replication).await,
+        ["CLUSTER", "LEAVE", node] => sharding.change(MemberChange::Leave(node.to_string()), storage, replication).await,
+        ["RING", version, members] => sharding.receive_ring(version, members, storage, replication).await,
+        _ => execute(&parts, storage, replication).await,
+    }
+}
+
+/// Runs a command against this node's own storage.
+async fn execute(
+    parts: &[&str],
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    replication: &Arc<Mutex<Replication>>,
+) -> String {
+    match parts {
         ["GET", key] => {
             let storage = storage.lock().await;
             match storage.get(key) {
-                Some(value) => format!("Value: {}\n", value),
-                None => "Key not found\n".to_string(),
+                Ok(Some(value)) => format!("Value: {}\n", value),
+                Ok(None) => "Key not found\n".to_string(),
+                Err(e) => format!("Storage error: {}\n", e),
             }
         }
         ["SET", key, value] => {
-            let mut storage = storage.lock().await;
-            storage.set(key, value);
-            replication.lock().await.replicate(&storage);
-            "OK\n".to_string()
+            let operation = Operation::Set { key: key.to_string(), value: value.to_string() };
+            write(operation, replication).await
         }
         ["DELETE", key] => {
-            let mut storage = storage.lock().await;
-            storage.delete(key);
-            replication.lock().await.replicate(&storage);
-            "OK\n".to_string()
+            let operation = Operation::Delete { key: key.to_string() };
+            write(operation, replication).await
+        }
+        ["REPLICATION"] => {
+            let replication = replication.lock().await;
+            let raft = replication.raft();
+            let mut response = match (raft.role(), raft.leader()) {
+                (Role::Leader, _) => format!("Leader in term {}\n", raft.term()),
+                (_, Some(leader)) => format!("Following {} in term {}\n", leader, raft.term()),
+                (_, None) => format!("No leader in term {}\n", raft.term()),
+            };
+            response.push_str(&format!("Log length: {}, committed: {}\n", raft.log_len(), raft.commit_index()));
+            if raft.role() == Role::Leader {
+                for (peer, matched) in raft.match_indexes() {
+                    response.push_str(&format!("{}: {}\n", peer, matched));
+                }
+            }
+            response
         }
         ["LIST"] => {
             let storage = storage.lock().await;
-            let all_pairs = storage.list_all();
+            let all_pairs = match storage.list_all() {
+                Ok(pairs) => pairs,
+                Err(e) => return format!("Storage error: {}\n", e),
+            };
             if all_pairs.is_empty() {
                 "No key-value pairs stored\n".to_string()
             } else {
@@ -90,4 +178,174 @@ async fn process_command(
         }
         _ => "Invalid command\n".to_string(),
     }
-}
\ No newline at end of file
+}
+
+/// Serves a command from another owner against this node's storage.
+async fn serve_replica(
+    parts: &[&str],
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    replication: &Arc<Mutex<Replication>>,
+    sharding: &Sharding,
+) -> String {
+    match parts {
+        ["UNDO", write @ ..] => {
+            let mut written = sharding.lock_writes().await;
+            undo(write, &mut written, storage, replication, sharding).await
+        }
+        ["HANDOFF", version, key, value] => {
+            let Ok(version) = version.parse::<u64>() else {
+                return "Invalid ring version\n".to_string();
+            };
+            let written = sharding.lock_writes().await;
+            if written.get(*key).is_some_and(|taken| *taken >= version) {
+                return "Superseded\n".to_string();
+            }
+            execute(&["SET", key, value], storage, replication).await
+        }
+        ["SET", ..] | ["DELETE", ..] => {
+            let mut written = sharding.lock_writes().await;
+            write_here(parts, &mut written, storage, replication, sharding).await
+        }
+        _ => execute(parts, storage, replication).await,
+    }
+}
+
+/// Runs the write `parts` here while the caller holds the sharding write
+/// lock, `written`, and records the ring version it was taken under.
+async fn write_here(
+    parts: &[&str],
+    written: &mut HashMap<String, u64>,
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    replication: &Arc<Mutex<Replication>>,
+    sharding: &Sharding,
+) -> String {
+    let response = execute(parts, storage, replication).await;
+    if response == "OK\n" {
+        written.insert(parts[1].to_string(), sharding.ring().await.version());
+    }
+    response
+}
+
+/// Reverts `write`, a `SET` or `DELETE` command followed by the key's value
+/// before it if there was one, provided the key still holds what the write
+/// left; a later write is kept. The caller holds the sharding write lock.
+async fn undo(
+    write: &[&str],
+    written: &mut HashMap<String, u64>,
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    replication: &Arc<Mutex<Replication>>,
+    sharding: &Sharding,
+) -> String {
+    let (key, left, previous) = match write {
+        ["SET", key, value] => (*key, Some(*value), None),
+        ["SET", key, value, previous] => (*key, Some(*value), Some(*previous)),
+        ["DELETE", key] => (*key, None, None),
+        ["DELETE", key, previous] => (*key, None, Some(*previous)),
+        _ => return "Invalid command\n".to_string(),
+    };
+    let current = match storage.lock().await.get(key) {
+        Ok(current) => current,
+        Err(e) => return format!("Storage error: {}\n", e),
+    };
+    if current.as_deref() != left {
+        return "OK\n".to_string();
+    }
+    match (previous, left) {
+        (Some(previous), _) => write_here(&["SET", key, previous], written, storage, replication, sharding).await,
+        (None, Some(_)) => write_here(&["DELETE", key], written, storage, replication, sharding).await,
+        (None, None) => "OK\n".to_string(),
+    }
+}
+
+/// Commits a client write through Raft, answering only once it is applied.
+async fn write(operation: Operation, replication: &Arc<Mutex<Replication>>) -> String {
+    match replication::write(replication, operation).await {
+        Ok(()) => "OK\n".to_string(),
+        Err(e) => format!("{}\n", e),
+    }
+}
+
+/// Serves a key from this node if it owns it, and from its owners otherwise.
+async fn route(
+    parts: &[&str],
+    key: &str,
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    replication: &Arc<Mutex<Replication>>,
+    sharding: &Arc<Sharding>,
+) -> String {
+    let owners = sharding.ring().await.owners(key);
+    let local = owners.iter().any(|owner| owner == sharding.address());
+    match (parts[0], local) {
+        ("GET", true) => execute(parts, storage, replication).await,
+        ("GET", false) => forward(&owners, &sharding.replica(&parts.join(" "))).await,
+        (_, true) => coordinate(parts, &owners, storage, replication, sharding).await,
+        (_, false) => forward(&owners, &format!("COORDINATE {}", parts.join(" "))).await,
+    }
+}
+
+/// Applies a write here and on the other owners, succeeding once a majority
+/// of them hold it. Otherwise the write is undone on the owners that took
+/// it, each getting the key's previous value back unless a later write has
+/// replaced this one there, so a write reported as failed is not served.
+async fn coordinate(
+    parts: &[&str],
+    owners: &[String],
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    replication: &Arc<Mutex<Replication>>,
+    sharding: &Arc<Sharding>,
+) -> String {
+    let key = parts[1];
+    let previous = {
+        let mut written = sharding.lock_writes().await;
+        let previous = match storage.lock().await.get(key) {
+            Ok(previous) => previous,
+            Err(e) => return format!("Storage error: {}\n", e),
+        };
+        let response = write_here(parts, &mut written, storage, replication, sharding).await;
+        if response != "OK\n" {
+            return response;
+        }
+        previous
+    };
+    let command = sharding.replica(&parts.join(" "));
+    let mut acknowledged = vec![sharding.address()];
+    for owner in owners.iter().filter(|owner| *owner != sharding.address()) {
+        match sharding::request(owner, &command).await {
+            Ok(response) if response == "OK" => acknowledged.push(owner),
+            Ok(response) => log::warn!("{} refused {}: {}", owner, command, response),
+            Err(e) => log::warn!("Cannot reach {}: {}", owner, e),
+        }
+    }
+    if acknowledged.len() > owners.len() / 2 {
+        return "OK\n".to_string();
+    }
+
+    let mut write = parts.to_vec();
+    write.extend(previous.as_deref());
+    for owner in &acknowledged {
+        let response = if *owner == sharding.address() {
+            let mut written = sharding.lock_writes().await;
+            undo(&write, &mut written, storage, replication, sharding).await
+        } else {
+            match sharding::request(owner, &sharding.replica(&format!("UNDO {}", write.join(" ")))).await {
+                Ok(response) => format!("{}\n", response),
+                Err(e) => format!("{}\n", e),
+            }
+        };
+        if response != "OK\n" {
+            log::warn!("Cannot undo the failed write of {} on {}: {}", key, owner, response.trim_end());
+        }
+    }
+    format!("Only {} of {} replicas acknowledged the write\n", acknowledged.len(), owners.len())
+}
+
+/// Passes a command to the first owner that answers.
+async fn forward(owners: &[String], command: &str) -> String {
+    for owner in owners {
+        match sharding::request(owner, command).await {
+            Ok(response) => return format!("{}\n", response),
+            Err(e) => log::warn!("Cannot reach {}: {}", owner, e),
+        }
+    }
+    "No owner of the key is reachable\n".to_string()
+}
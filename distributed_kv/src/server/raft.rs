@@ -0,0 +1,529 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::storage::Operation;
+
+/// Nodes are identified by the address they serve on.
+pub type NodeId = String;
+
+/// Ticks a leader waits between heartbeats.
+const HEARTBEAT_TICKS: u32 = 2;
+/// A follower that hears nothing from a leader for a random number of ticks
+/// in this range starts an election.
+const ELECTION_TICKS: (u32, u32) = (10, 20);
+/// Most entries sent in one AppendEntries message.
+const MAX_APPEND_ENTRIES: usize = 100;
+
+/// One slot of the replicated log. Leaders open their term with an entry
+/// carrying no operation, which lets them commit entries from earlier terms.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub operation: Option<Operation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Message {
+    RequestVote { term: u64, last_log_index: u64, last_log_term: u64 },
+    Vote { term: u64, granted: bool },
+    AppendEntries { term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64 },
+    /// `match_index` is the last index known to match the leader's log on
+    /// success, and a hint of where to retry from on failure.
+    AppendResponse { term: u64, success: bool, match_index: u64 },
+    /// Sent in place of entries the leader has compacted away: `data` is
+    /// the state built by applying every entry through `last_index`. It is
+    /// answered like AppendEntries. `RaftNode` leaves `data` null for the
+    /// caller to fill in with its state machine as it stands when the
+    /// message is taken, which is then exactly that state.
+    InstallSnapshot { term: u64, last_index: u64, last_term: u64, data: serde_json::Value },
+}
+
+/// A change to the state a node must keep across restarts: its term, its
+/// vote, its log and how much of it is applied. Replaying the changes in
+/// order rebuilds that state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Change {
+    Vote { term: u64, voted_for: Option<NodeId> },
+    /// `entry` was stored at `index`, replacing any entries from there on.
+    Entry { index: u64, entry: Entry },
+    /// The log was dropped through `index`, an entry of `term`, its effect
+    /// being durable in the state machine. Entries after it follow.
+    Snapshot { index: u64, term: u64 },
+    /// The state machine durably holds the effect of every entry through
+    /// `index`. `RaftNode` never returns this; the caller saves it once
+    /// what it applied is on disk.
+    Applied { index: u64 },
+}
+
+impl Message {
+    pub fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. }
+            | Message::InstallSnapshot { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The Raft state of one node, free of I/O and clocks: time advances through
+/// `tick`, messages arrive through `step`, and the caller delivers whatever
+/// `take_messages` returns and applies whatever `take_committed` returns.
+/// A caller that persists the node saves whatever `take_changes` returns
+/// before delivering the messages. A snapshot from the leader comes out of
+/// `take_snapshot` and replaces the state machine before either. Log
+/// indices start at 1; index 0 stands for the empty prefix.
+pub struct RaftNode {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    term: u64,
+    voted_for: Option<NodeId>,
+    /// The entries after the snapshot.
+    log: Vec<Entry>,
+    /// The last index and term compacted into the snapshot.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// A snapshot from the leader, until `take_snapshot` returns it.
+    installed: Option<serde_json::Value>,
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    votes: BTreeSet<NodeId>,
+    /// Leader only: next index to send and highest index known replicated,
+    /// per peer.
+    next_index: BTreeMap<NodeId, u64>,
+    match_index: BTreeMap<NodeId, u64>,
+    elapsed: u32,
+    election_timeout: u32,
+    rng: u64,
+    outbox: Vec<(NodeId, Message)>,
+    /// What `take_changes` last reported: the term and vote, the snapshot
+    /// index, and the length of the log prefix that has not changed since.
+    saved: (u64, Option<NodeId>, u64, u64),
+}
+
+impl RaftNode {
+    /// `seed` drives the randomized election timeouts; nodes of one cluster
+    /// should use different seeds. A node without peers leads at once.
+    #[cfg(test)]
+    pub fn new(id: NodeId, peers: Vec<NodeId>, seed: u64) -> Self {
+        RaftNode::restore(id, peers, seed, Vec::new())
+    }
+
+    /// Like `new`, but resumes from the term, vote, snapshot and log rebuilt
+    /// from `changes`, as earlier returned by `take_changes` and `compact`.
+    /// Entries through the last one saved as applied count as committed and
+    /// are not returned by `take_committed` again; the rest are applied
+    /// once a leader confirms them.
+    pub fn restore(id: NodeId, peers: Vec<NodeId>, seed: u64, changes: Vec<Change>) -> Self {
+        let (mut term, mut voted_for, mut log) = (0, None, Vec::new());
+        let (mut snapshot_index, mut snapshot_term, mut applied) = (0, 0, 0);
+        for change in changes {
+            match change {
+                Change::Vote { term: saved, voted_for: vote } => (term, voted_for) = (saved, vote),
+                Change::Entry { index, entry } => {
+                    log.truncate((index - snapshot_index) as usize - 1);
+                    log.push(entry);
+                }
+                Change::Snapshot { index, term } => {
+                    (snapshot_index, snapshot_term) = (index, term);
+                    log.clear();
+                }
+                Change::Applied { index } => applied = index,
+            }
+        }
+        let applied = applied.max(snapshot_index);
+        let saved = (term, voted_for.clone(), snapshot_index, snapshot_index + log.len() as u64);
+        let mut node = RaftNode {
+            id,
+            peers,
+            term,
+            voted_for,
+            log,
+            snapshot_index,
+            snapshot_term,
+            installed: None,
+            commit_index: applied,
+            last_applied: applied,
+            role: Role::Follower,
+            leader: None,
+            votes: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            elapsed: 0,
+            election_timeout: 0,
+            rng: seed | 1,
+            outbox: Vec::new(),
+            saved,
+        };
+        node.reset_election_timeout();
+        if node.peers.is_empty() {
+            node.start_election();
+        }
+        node
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    /// The index of the last entry, counting those compacted away.
+    pub fn log_len(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// The last index `take_committed` has returned.
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// The last index compacted into the snapshot.
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    /// Leader only: the highest index each peer is known to hold.
+    pub fn match_indexes(&self) -> &BTreeMap<NodeId, u64> {
+        &self.match_index
+    }
+
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= HEARTBEAT_TICKS => {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+            Role::Follower | Role::Candidate if self.elapsed >= self.election_timeout => self.start_election(),
+            _ => {}
+        }
+    }
+
+    /// Appends `operation` to the leader's log, returning its index and
+    /// term. It is applied once `take_committed` returns that index with
+    /// that term; a different term there means the write was lost to a new
+    /// leader. Fails on any node but the leader.
+    pub fn propose(&mut self, operation: Operation) -> Result<(u64, u64), Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader.clone());
+        }
+        self.log.push(Entry { term: self.term, operation: Some(operation) });
+        self.broadcast_append();
+        self.advance_commit();
+        Ok((self.log_len(), self.term))
+    }
+
+    /// Handles a message from `from`.
+    pub fn step(&mut self, from: &str, message: Message) {
+        if message.term() > self.term {
+            self.become_follower(message.term(), None);
+        }
+        match message {
+            Message::RequestVote { term, last_log_index, last_log_term } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.last_log_term(), self.log_len());
+                let free = self.voted_for.as_deref().is_none_or(|vote| vote == from);
+                let granted = term == self.term && free && up_to_date;
+                if granted {
+                    self.voted_for = Some(from.to_string());
+                    self.elapsed = 0;
+                }
+                self.send(from, Message::Vote { term: self.term, granted });
+            }
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from.to_string());
+                    if self.is_majority(self.votes.len()) {
+                        self.become_leader();
+                    }
+                }
+            }
+            Message::AppendEntries { term, mut prev_log_index, mut prev_log_term, mut entries, leader_commit } => {
+                if term < self.term {
+                    self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: 0 });
+                    return;
+                }
+                self.follow(from);
+
+                if prev_log_index < self.snapshot_index {
+                    // Entries through the snapshot are committed, so they
+                    // match the leader's.
+                    let skip = ((self.snapshot_index - prev_log_index) as usize).min(entries.len());
+                    entries.drain(..skip);
+                    (prev_log_index, prev_log_term) = (self.snapshot_index, self.snapshot_term);
+                }
+                if prev_log_index > self.log_len() || self.term_at(prev_log_index) != prev_log_term {
+                    let hint = self.log_len().min(prev_log_index.saturating_sub(1));
+                    self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: hint });
+                    return;
+                }
+                let last_new = prev_log_index + entries.len() as u64;
+                for (index, entry) in (prev_log_index + 1..).zip(entries) {
+                    if index <= self.log_len() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        // A conflicting suffix was never committed; drop it.
+                        self.log.truncate((index - self.snapshot_index) as usize - 1);
+                        self.saved.3 = self.saved.3.min(index - 1);
+                    }
+                    self.log.push(entry);
+                }
+                if leader_commit > self.commit_index {
+                    self.commit_index = leader_commit.min(last_new);
+                }
+                self.send(from, Message::AppendResponse { term: self.term, success: true, match_index: last_new });
+            }
+            Message::InstallSnapshot { term, last_index, last_term, data } => {
+                if term < self.term {
+                    self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: 0 });
+                    return;
+                }
+                self.follow(from);
+
+                if last_index > self.commit_index {
+                    // Entries after the snapshot may already count towards
+                    // a commit, so they stay if the log agrees with it.
+                    if last_index <= self.log_len() && self.term_at(last_index) == last_term {
+                        self.log.drain(..(last_index - self.snapshot_index) as usize);
+                    } else {
+                        self.log.clear();
+                    }
+                    (self.snapshot_index, self.snapshot_term) = (last_index, last_term);
+                    self.commit_index = last_index;
+                    self.last_applied = last_index;
+                    self.installed = Some(data);
+                }
+                self.send(from, Message::AppendResponse { term: self.term, success: true, match_index: last_index });
+            }
+            Message::AppendResponse { term, success, match_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                if success {
+                    let matched = self.match_index.entry(from.to_string()).or_default();
+                    *matched = (*matched).max(match_index);
+                    let next = *matched + 1;
+                    self.next_index.insert(from.to_string(), next);
+                    self.advance_commit();
+                    if next <= self.log_len() {
+                        self.send_append(from);
+                    }
+                } else {
+                    let next = self.next_index.get(from).copied().unwrap_or(1);
+                    self.next_index.insert(from.to_string(), (match_index + 1).min(next.saturating_sub(1)).max(1));
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+
+    /// Messages to deliver, as `(recipient, message)`. Delivery may drop,
+    /// delay or reorder them.
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Changes to the term, vote, snapshot and log since the last call, in
+    /// the order `restore` replays them.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        let (term, voted_for, snapshot_index, mut unchanged) = self.saved.clone();
+        let mut changes = Vec::new();
+        if (term, &voted_for) != (self.term, &self.voted_for) {
+            changes.push(Change::Vote { term: self.term, voted_for: self.voted_for.clone() });
+        }
+        if snapshot_index != self.snapshot_index {
+            changes.push(Change::Snapshot { index: self.snapshot_index, term: self.snapshot_term });
+            unchanged = self.snapshot_index;
+        }
+        changes.extend((unchanged + 1..=self.log_len()).map(|index| Change::Entry { index, entry: self.entry(index).clone() }));
+        self.saved = (self.term, self.voted_for.clone(), self.snapshot_index, self.log_len());
+        changes
+    }
+
+    /// Newly committed entries with their indices, in log order. Each is
+    /// returned exactly once.
+    pub fn take_committed(&mut self) -> Vec<(u64, Entry)> {
+        let from = self.last_applied;
+        self.last_applied = self.commit_index;
+        (from + 1..=self.commit_index).map(|index| (index, self.entry(index).clone())).collect()
+    }
+
+    /// The state a leader sent in place of entries it had compacted, once
+    /// they are committed here. It replaces the state machine before
+    /// anything `take_committed` returns is applied, and must be durable
+    /// before the changes that follow it are saved.
+    pub fn take_snapshot(&mut self) -> Option<serde_json::Value> {
+        self.installed.take()
+    }
+
+    /// Drops the log through the last applied entry, once the state machine
+    /// durably holds its effect. Returns the changes that rebuild the node
+    /// from now on, replacing every change taken before.
+    pub fn compact(&mut self) -> Vec<Change> {
+        let index = self.last_applied;
+        self.snapshot_term = self.term_at(index);
+        self.log.drain(..(index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.saved = (self.term, self.voted_for.clone(), self.snapshot_index, self.log_len());
+        let mut changes = vec![
+            Change::Vote { term: self.term, voted_for: self.voted_for.clone() },
+            Change::Snapshot { index, term: self.snapshot_term },
+        ];
+        changes.extend((index + 1..=self.log_len()).map(|index| Change::Entry { index, entry: self.entry(index).clone() }));
+        changes
+    }
+
+    fn start_election(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id.clone());
+        self.votes = BTreeSet::from([self.id.clone()]);
+        self.elapsed = 0;
+        self.reset_election_timeout();
+        if self.is_majority(self.votes.len()) {
+            self.become_leader();
+            return;
+        }
+        let request = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.log_len(),
+            last_log_term: self.last_log_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(&peer, request.clone());
+        }
+    }
+
+    /// Takes `leader` as the leader of the current term on hearing from it.
+    fn follow(&mut self, leader: &str) {
+        self.role = Role::Follower;
+        self.leader = Some(leader.to_string());
+        self.elapsed = 0;
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        self.term = term;
+        self.voted_for = None;
+        self.role = Role::Follower;
+        self.leader = leader;
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.elapsed = 0;
+        self.log.push(Entry { term: self.term, operation: None });
+        self.next_index = self.peers.iter().map(|peer| (peer.clone(), self.log_len())).collect();
+        self.match_index = self.peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        self.broadcast_append();
+        self.advance_commit();
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: &str) {
+        let next = self.next_index.get(peer).copied().unwrap_or(1);
+        if next <= self.snapshot_index {
+            // The entries it lacks are gone; send the state they built, and
+            // carry on from there unless it fails to arrive.
+            let message = Message::InstallSnapshot {
+                term: self.term,
+                last_index: self.last_applied,
+                last_term: self.term_at(self.last_applied),
+                data: serde_json::Value::Null,
+            };
+            self.next_index.insert(peer.to_string(), self.last_applied + 1);
+            self.send(peer, message);
+            return;
+        }
+        let prev_log_index = next - 1;
+        let entries = self.log[(prev_log_index - self.snapshot_index) as usize..].iter().take(MAX_APPEND_ENTRIES).cloned().collect();
+        let message = Message::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit_index,
+        };
+        self.send(peer, message);
+    }
+
+    /// Commits the highest entry of the current term stored on a majority,
+    /// and with it every entry before it.
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.log_len()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|matched| **matched >= index).count();
+            if self.is_majority(replicas) {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn is_majority(&self, count: usize) -> bool {
+        count * 2 > self.peers.len() + 1
+    }
+
+    /// The term of the entry at `index`, which must not precede the
+    /// snapshot; 0 past the end of the log.
+    fn term_at(&self, index: u64) -> u64 {
+        match index.checked_sub(self.snapshot_index + 1) {
+            None => self.snapshot_term,
+            Some(offset) => self.log.get(offset as usize).map_or(0, |entry| entry.term),
+        }
+    }
+
+    /// The entry at `index`, which must be in the log.
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.snapshot_index) as usize - 1]
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.log_len())
+    }
+
+    fn send(&mut self, to: &str, message: Message) {
+        self.outbox.push((to.to_string(), message));
+    }
+
+    fn reset_election_timeout(&mut self) {
+        // xorshift64: cheap, and deterministic for a given seed.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let (low, high) = ELECTION_TICKS;
+        self.election_timeout = low + (self.rng % u64::from(high - low)) as u32;
+    }
+}
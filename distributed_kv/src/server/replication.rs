@@ -1,16 +1,301 @@
-use super::storage::Storage;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
 
+use super::raft::{Change, Message, NodeId, RaftNode};
+use super::storage::{Operation, Storage, SyncPolicy};
+use super::wal::Wal;
+
+/// File in the data directory holding the node's Raft term, vote and log.
+const RAFT_LOG_FILE: &str = "raft.log";
+/// Wall-clock length of one Raft tick.
+const TICK: Duration = Duration::from_millis(50);
+/// How long a client write waits to be committed.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Applied entries kept in the Raft log before it is compacted into the
+/// state they built, which storage holds.
+const COMPACT_AFTER: u64 = 1_000;
+/// How long sending to an unreachable peer may block before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Messages queued per peer; beyond this they are dropped, which Raft
+/// tolerates like any other message loss.
+const PEER_QUEUE: usize = 1024;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum WriteError {
+    #[error("Not the leader; send writes to {0}")]
+    NotLeader(NodeId),
+    #[error("No leader elected yet; try again shortly")]
+    NoLeader,
+    #[error("Write lost to a leader change; try again")]
+    Lost,
+    #[error("Write not committed in time; it may still be applied")]
+    TimedOut,
+}
+
+/// Runs this node's Raft state machine over TCP and applies committed
+/// entries to storage.
 pub struct Replication {
-    // In a real implementation, this would contain information about other nodes
+    raft: RaftNode,
+    /// Where the Raft state is saved, for a cluster node with a data directory.
+    raft_log: Option<Wal<Change>>,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    /// Outgoing messages per peer, drained by `send_to_peer`.
+    peers: BTreeMap<NodeId, mpsc::Sender<Message>>,
+    /// Log index → the term it was proposed in and the client waiting on it.
+    waiting: BTreeMap<u64, (u64, oneshot::Sender<bool>)>,
+    /// Told why the node stopped, after which nothing more is sent or
+    /// applied.
+    failure: Option<oneshot::Sender<io::Error>>,
 }
 
 impl Replication {
-    pub fn new() -> Self {
-        Replication {}
+    /// Joins the cluster of `address` and `peers`, spawning the tick and
+    /// peer connection tasks. With a `data_dir`, a node with peers keeps its
+    /// Raft term, vote and log there and picks them up again on restart; a
+    /// lone node has no votes to remember and its storage holds its writes.
+    /// The receiver learns of an error that stopped the node, after which
+    /// the server should shut down.
+    pub fn start(
+        address: &str,
+        peers: &[String],
+        data_dir: Option<&Path>,
+        storage: Arc<Mutex<Box<dyn Storage>>>,
+    ) -> io::Result<(Arc<Mutex<Replication>>, oneshot::Receiver<io::Error>)> {
+        let (raft_log, changes) = match data_dir.filter(|_| !peers.is_empty()) {
+            Some(dir) => {
+                // Synced explicitly before anything that depends on it is sent.
+                let (wal, changes) = Wal::open(&dir.join(RAFT_LOG_FILE), SyncPolicy::Never)?;
+                (Some(wal), changes)
+            }
+            None => (None, Vec::new()),
+        };
+        let mut hasher = DefaultHasher::new();
+        address.hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+
+        let mut queues = BTreeMap::new();
+        for peer in peers {
+            let (queue, outgoing) = mpsc::channel(PEER_QUEUE);
+            tokio::spawn(send_to_peer(address.to_string(), peer.clone(), outgoing));
+            queues.insert(peer.clone(), queue);
+        }
+        let raft = RaftNode::restore(address.to_string(), peers.to_vec(), hasher.finish(), changes);
+        if raft.log_len() > 0 {
+            log::info!("Resuming in term {} at log index {}, applied through {}", raft.term(), raft.log_len(), raft.last_applied());
+        }
+        let (failure, failed) = oneshot::channel();
+        let replication = Arc::new(Mutex::new(Replication {
+            raft,
+            raft_log,
+            storage,
+            peers: queues,
+            waiting: BTreeMap::new(),
+            failure: Some(failure),
+        }));
+        tokio::spawn(run_ticker(Arc::clone(&replication)));
+        Ok((replication, failed))
+    }
+
+    pub fn raft(&self) -> &RaftNode {
+        &self.raft
+    }
+
+    /// Installs a snapshot from the leader, saves changes to the Raft
+    /// state, then sends the messages Raft has queued and applies newly
+    /// committed entries, answering the clients waiting on them. Votes and
+    /// acknowledgements thus never outrun what a restart would remember.
+    /// On an error the node stops: carrying on could break a promise made
+    /// before a restart, or serve state that no longer matches the log.
+    async fn flush(&mut self) {
+        if self.failure.is_none() {
+            return;
+        }
+        if let Err(e) = self.try_flush().await {
+            log::error!("Stopping the server: {}", e);
+            if let Some(failure) = self.failure.take() {
+                let _ = failure.send(e);
+            }
+        }
+    }
+
+    async fn try_flush(&mut self) -> io::Result<()> {
+        if let Some(data) = self.raft.take_snapshot() {
+            let mut storage = self.storage.lock().await;
+            install(storage.as_mut(), serde_json::from_value(data)?)
+                .and_then(|()| storage.sync())
+                .map_err(|e| context(e, "Cannot install a snapshot"))?;
+            log::info!("Installed a snapshot through log index {}", self.raft.snapshot_index());
+        }
+        let changes = self.raft.take_changes();
+        if let Some(raft_log) = self.raft_log.as_mut().filter(|_| !changes.is_empty()) {
+            changes
+                .iter()
+                .try_for_each(|change| raft_log.append(change))
+                .and_then(|()| raft_log.sync())
+                .map_err(|e| context(e, "Cannot save the Raft state"))?;
+        }
+        for (peer, mut message) in self.raft.take_messages() {
+            if let Message::InstallSnapshot { data, .. } = &mut message {
+                let pairs = self.storage.lock().await.list_all().map_err(|e| context(e, "Cannot read a snapshot"))?;
+                *data = serde_json::to_value(pairs)?;
+            }
+            if let Some(queue) = self.peers.get(&peer) {
+                let _ = queue.try_send(message);
+            }
+        }
+        let committed = self.raft.take_committed();
+        if committed.is_empty() {
+            return Ok(());
+        }
+        let mut storage = self.storage.lock().await;
+        for (index, entry) in committed {
+            if let Some(operation) = &entry.operation {
+                operation.apply(storage.as_mut()).map_err(|e| context(e, &format!("Cannot apply entry {}", index)))?;
+            }
+            if let Some((term, waiter)) = self.waiting.remove(&index) {
+                let _ = waiter.send(term == entry.term);
+            }
+        }
+        // Storage must hold the applied entries before the log says so, or
+        // a restart would skip them.
+        if let Some(raft_log) = &mut self.raft_log {
+            storage
+                .sync()
+                .and_then(|()| raft_log.append(&Change::Applied { index: self.raft.last_applied() }))
+                .and_then(|()| raft_log.sync())
+                .map_err(|e| context(e, "Cannot save the applied index"))?;
+        }
+        if self.raft.last_applied() - self.raft.snapshot_index() >= COMPACT_AFTER {
+            let changes = self.raft.compact();
+            if let Some(raft_log) = &mut self.raft_log {
+                raft_log.replace(&changes).map_err(|e| context(e, "Cannot compact the Raft log"))?;
+            }
+            log::debug!("Compacted the Raft log through index {}", self.raft.snapshot_index());
+        }
+        Ok(())
+    }
+}
+
+/// Replaces everything in `storage` with `pairs`.
+fn install(storage: &mut dyn Storage, pairs: Vec<(String, String)>) -> io::Result<()> {
+    let mut pairs: BTreeMap<String, String> = pairs.into_iter().collect();
+    for (key, value) in storage.list_all()? {
+        match pairs.get(&key) {
+            None => storage.delete(&key)?,
+            Some(kept) if *kept == value => {
+                pairs.remove(&key);
+            }
+            Some(_) => {}
+        }
     }
+    pairs.iter().try_for_each(|(key, value)| storage.set(key, value))
+}
+
+fn context(e: io::Error, what: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+/// Proposes a client write and waits until it is committed and applied.
+pub async fn write(replication: &Arc<Mutex<Replication>>, operation: Operation) -> Result<(), WriteError> {
+    let (index, committed) = {
+        let mut replication = replication.lock().await;
+        let (index, term) = replication.raft.propose(operation).map_err(|leader| match leader {
+            Some(leader) => WriteError::NotLeader(leader),
+            None => WriteError::NoLeader,
+        })?;
+        let (waiter, committed) = oneshot::channel();
+        replication.waiting.insert(index, (term, waiter));
+        replication.flush().await;
+        (index, committed)
+    };
+    match tokio::time::timeout(COMMIT_TIMEOUT, committed).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(_) => Err(WriteError::Lost),
+        Err(_) => {
+            replication.lock().await.waiting.remove(&index);
+            Err(WriteError::TimedOut)
+        }
+    }
+}
 
-    pub fn replicate(&self, _storage: &Storage) {
-        // In a real implementation, this would send updates to other nodes
-        log::info!("Replicating data to other nodes");
+async fn run_ticker(replication: Arc<Mutex<Replication>>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let mut replication = replication.lock().await;
+        let term = replication.raft.term();
+        replication.raft.tick();
+        if replication.raft.term() != term {
+            log::info!("Starting an election for term {}", replication.raft.term());
+        }
+        replication.flush().await;
     }
-}
\ No newline at end of file
+}
+
+/// Delivers queued messages to `peer` over a connection opened with
+/// `RAFT <address>`, reconnecting as needed. Messages that cannot be sent
+/// are dropped.
+async fn send_to_peer(address: String, peer: NodeId, mut outgoing: mpsc::Receiver<Message>) {
+    let mut connection: Option<TcpStream> = None;
+    while let Some(message) = outgoing.recv().await {
+        if connection.is_none() {
+            match connect(&address, &peer).await {
+                Ok(stream) => connection = Some(stream),
+                Err(e) => {
+                    log::debug!("Cannot reach {}: {}", peer, e);
+                    continue;
+                }
+            }
+        }
+        let line = format!("{}\n", serde_json::to_string(&message).expect("messages serialize"));
+        if let Some(stream) = connection.as_mut() {
+            if let Err(e) = stream.write_all(line.as_bytes()).await {
+                log::debug!("Lost connection to {}: {}", peer, e);
+                connection = None;
+            }
+        }
+    }
+}
+
+async fn connect(address: &str, peer: &str) -> Result<TcpStream, BoxError> {
+    let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(peer)).await??;
+    stream.write_all(format!("RAFT {}\n", address).as_bytes()).await?;
+    Ok(stream)
+}
+
+/// Peer side of a connection that opened with `RAFT <from>`: hands each
+/// message to Raft. Replies travel over this node's own connection to `from`.
+pub async fn receive<R>(from: &str, reader: &mut R, replication: &Arc<Mutex<Replication>>) -> Result<(), BoxError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let message: Message = serde_json::from_str(&line)?;
+        let mut replication = replication.lock().await;
+        let leader = replication.raft.leader().map(str::to_string);
+        replication.raft.step(from, message);
+        if replication.raft.leader() != leader.as_deref() {
+            if let Some(leader) = replication.raft.leader() {
+                log::info!("{} leads term {}", leader, replication.raft.term());
+            }
+        }
+        replication.flush().await;
+    }
+}
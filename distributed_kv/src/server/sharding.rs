@@ -0,0 +1,410 @@
+//! Consistent-hash partitioning. Every member of the ring places virtual
+//! nodes at hashed positions; a key belongs to the nodes of the first
+//! virtual nodes at or after its own position, walking clockwise until the
+//! replication factor is met. Adding or removing a member only moves the
+//! keys next to its virtual nodes.
+//!
+//! Requests for keys this node does not own are forwarded: reads to an
+//! owner as `REPLICA <sender> <command>`, which it answers from its own
+//! storage, and writes as `COORDINATE <command>`, which an owner applies and
+//! passes on to the other owners as `REPLICA` writes. `REPLICA` commands
+//! bypass the owners' agreement, so they are only served for a `sender`
+//! that is a member of the ring and connects from that member's host.
+//! Membership changes are made one at a time by the ring's first member,
+//! the coordinator, and broadcast as `RING <version> <members>`; each node
+//! then hands the keys it holds to their new owners.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+
+use super::replication::{self, Replication};
+use super::storage::{Operation, Storage};
+
+/// How long a forwarded request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a joining node waits between attempts to reach the ring.
+const JOIN_RETRY: Duration = Duration::from_millis(500);
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct ShardOptions {
+    /// The ring's members at startup, this node included.
+    pub members: Vec<String>,
+    /// A member of an existing ring to join through.
+    pub join: Option<String>,
+    pub vnodes: usize,
+    pub replicas: usize,
+}
+
+/// A membership change, as requested with `CLUSTER JOIN` or `CLUSTER LEAVE`.
+#[derive(Debug, Clone)]
+pub enum MemberChange {
+    Join(String),
+    Leave(String),
+}
+
+impl MemberChange {
+    fn command(&self) -> String {
+        match self {
+            MemberChange::Join(node) => format!("CLUSTER JOIN {}", node),
+            MemberChange::Leave(node) => format!("CLUSTER LEAVE {}", node),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ring {
+    version: u64,
+    members: BTreeSet<String>,
+    vnodes: usize,
+    replicas: usize,
+    /// Position → the member whose virtual node sits there.
+    points: BTreeMap<u64, String>,
+}
+
+impl Ring {
+    pub fn new(version: u64, members: BTreeSet<String>, vnodes: usize, replicas: usize) -> Self {
+        let points = members
+            .iter()
+            .flat_map(|member| (0..vnodes).map(move |i| (position(&format!("{}#{}", member, i)), member.clone())))
+            .collect();
+        Ring { version, members, vnodes, replicas, points }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn members(&self) -> &BTreeSet<String> {
+        &self.members
+    }
+
+    /// The next version of the ring, made of `members`.
+    pub fn with_members(&self, members: BTreeSet<String>) -> Ring {
+        Ring::new(self.version + 1, members, self.vnodes, self.replicas)
+    }
+
+    /// The nodes holding `key`, primary first.
+    pub fn owners(&self, key: &str) -> Vec<String> {
+        self.owners_from(position(key))
+    }
+
+    /// The fraction of keys each member is primary for, and holds.
+    pub fn ownership(&self) -> BTreeMap<String, (f64, f64)> {
+        let mut shares: BTreeMap<String, (f64, f64)> =
+            self.members.iter().map(|member| (member.clone(), (0.0, 0.0))).collect();
+        let points: Vec<u64> = self.points.keys().copied().collect();
+        for (i, point) in points.iter().enumerate() {
+            // Keys after the previous point and up to this one.
+            let previous = points[(i + points.len() - 1) % points.len()];
+            let arc = match point.wrapping_sub(previous) {
+                0 => 1.0,
+                len => len as f64 / 2f64.powi(64),
+            };
+            for (rank, owner) in self.owners_from(*point).into_iter().enumerate() {
+                let share = shares.get_mut(&owner).unwrap();
+                if rank == 0 {
+                    share.0 += arc;
+                }
+                share.1 += arc;
+            }
+        }
+        shares
+    }
+
+    /// The ring and each member's share of it, for the `CLUSTER` command.
+    pub fn describe(&self) -> String {
+        let mut response = format!(
+            "Ring version {}: {} nodes, {} virtual nodes each, replication factor {}\n",
+            self.version,
+            self.members.len(),
+            self.vnodes,
+            self.replicas,
+        );
+        for (member, (primary, held)) in self.ownership() {
+            response.push_str(&format!("{}: primary for {:.1}%, holds {:.1}% of keys\n", member, primary * 100.0, held * 100.0));
+        }
+        for (point, member) in &self.points {
+            response.push_str(&format!("{:016x} {}\n", point, member));
+        }
+        response
+    }
+
+    fn owners_from(&self, start: u64) -> Vec<String> {
+        let wanted = self.replicas.min(self.members.len());
+        let mut owners: Vec<String> = Vec::with_capacity(wanted);
+        for (_, member) in self.points.range(start..).chain(self.points.range(..start)) {
+            if owners.len() == wanted {
+                break;
+            }
+            if !owners.contains(member) {
+                owners.push(member.clone());
+            }
+        }
+        owners
+    }
+}
+
+/// FNV-1a, finished with the SplitMix64 mixer so that names differing only
+/// in their last characters still land far apart.
+fn position(name: &str) -> u64 {
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3));
+    let mut z = hash.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// This node's view of the ring.
+pub struct Sharding {
+    address: String,
+    ring: RwLock<Ring>,
+    /// Members of the ring before the current one, whose hand-offs may
+    /// still be arriving.
+    previous: RwLock<BTreeSet<String>>,
+    /// Held while this node, as coordinator, makes a membership change.
+    changing: Mutex<()>,
+    /// Held while a key is written here, so that a conditional write reads
+    /// the key and writes it with no other write in between. Maps each key
+    /// written here, other than by a hand-off, to the version of the ring it
+    /// was written under.
+    writes: Mutex<HashMap<String, u64>>,
+}
+
+impl Sharding {
+    pub fn new(address: &str, options: &ShardOptions) -> Self {
+        let mut members: BTreeSet<String> = options.members.iter().cloned().collect();
+        members.insert(address.to_string());
+        Sharding {
+            address: address.to_string(),
+            ring: RwLock::new(Ring::new(0, members, options.vnodes, options.replicas)),
+            previous: RwLock::new(BTreeSet::new()),
+            changing: Mutex::new(()),
+            writes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub async fn ring(&self) -> Ring {
+        self.ring.read().await.clone()
+    }
+
+    /// Waits until no other write of a key is in progress here, and returns
+    /// the ring versions keys were last written under.
+    pub async fn lock_writes(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.writes.lock().await
+    }
+
+    /// `command` as sent to another owner, naming this node as the sender.
+    pub fn replica(&self, command: &str) -> String {
+        format!("REPLICA {} {}", self.address, command)
+    }
+
+    /// Whether `sender` is a member of the current or the previous ring and
+    /// listens on the host `peer` connects from.
+    pub async fn is_peer(&self, sender: &str, peer: SocketAddr) -> bool {
+        let member = self.ring.read().await.members().contains(sender) || self.previous.read().await.contains(sender);
+        if !member {
+            return false;
+        }
+        match tokio::net::lookup_host(sender).await {
+            Ok(mut addresses) => addresses.any(|address| address.ip() == peer.ip()),
+            Err(_) => false,
+        }
+    }
+
+    /// Adds or removes a member through the ring's coordinator, so that no
+    /// two rings are made with the same version.
+    pub async fn change(
+        self: &Arc<Self>,
+        change: MemberChange,
+        storage: &Arc<Mutex<Box<dyn Storage>>>,
+        replication: &Arc<Mutex<Replication>>,
+    ) -> String {
+        let coordinator = self.ring().await.members().first().cloned().unwrap_or_default();
+        if coordinator == self.address {
+            return self.coordinate_change(change, storage, replication).await;
+        }
+        match request(&coordinator, &format!("COORDINATE {}", change.command())).await {
+            Ok(response) => format!("{}\n", response),
+            Err(e) => format!("Cannot reach the ring's coordinator {}: {}\n", coordinator, e),
+        }
+    }
+
+    /// Makes a membership change here, after any other in progress, and
+    /// broadcasts the new ring to every node of the old and the new one,
+    /// this one included.
+    pub async fn coordinate_change(
+        self: &Arc<Self>,
+        change: MemberChange,
+        storage: &Arc<Mutex<Box<dyn Storage>>>,
+        replication: &Arc<Mutex<Replication>>,
+    ) -> String {
+        let _changing = self.changing.lock().await;
+        let old = self.ring().await;
+        let mut members = old.members().clone();
+        match change {
+            MemberChange::Join(node) => members.insert(node),
+            MemberChange::Leave(node) => members.remove(&node),
+        };
+        if members.is_empty() {
+            return "The ring needs at least one node\n".to_string();
+        }
+        let new = old.with_members(members);
+        let message = format!("RING {} {}", new.version(), new.members().iter().cloned().collect::<Vec<_>>().join(","));
+        // Joining nodes hear of the ring first, so they know the members
+        // that start handing keys to them once they hear of it too.
+        let joining = new.members().difference(old.members());
+        for node in joining.chain(old.members()).filter(|node| **node != self.address) {
+            if let Err(e) = request(node, &message).await {
+                log::warn!("Cannot send ring version {} to {}: {}", new.version(), node, e);
+            }
+        }
+        self.adopt(new.clone(), storage, replication).await;
+        format!("Ring version {}: {}\n", new.version(), new.members().iter().cloned().collect::<Vec<_>>().join(", "))
+    }
+
+    /// Switches to `ring` if it is newer than the current one, then hands
+    /// off keys in the background. Rings of one version made by different
+    /// coordinators, while nodes disagreed on which one that was, are
+    /// ordered by their members so every node settles on the same one.
+    pub async fn adopt(
+        self: &Arc<Self>,
+        ring: Ring,
+        storage: &Arc<Mutex<Box<dyn Storage>>>,
+        replication: &Arc<Mutex<Replication>>,
+    ) {
+        let old = {
+            let mut current = self.ring.write().await;
+            if (ring.version(), ring.members()) <= (current.version(), current.members()) {
+                return;
+            }
+            std::mem::replace(&mut *current, ring.clone())
+        };
+        *self.previous.write().await = old.members().clone();
+        log::info!("Adopted ring version {} with {} nodes", ring.version(), ring.members().len());
+        let sharding = Arc::clone(self);
+        let (storage, replication) = (Arc::clone(storage), Arc::clone(replication));
+        tokio::spawn(async move {
+            if let Err(e) = sharding.rebalance(&old, &ring, &storage, &replication).await {
+                log::error!("Rebalancing to ring version {} failed: {}", ring.version(), e);
+            }
+        });
+    }
+
+    /// Adopts a ring broadcast as `RING <version> <members>`.
+    pub async fn receive_ring(
+        self: &Arc<Self>,
+        version: &str,
+        members: &str,
+        storage: &Arc<Mutex<Box<dyn Storage>>>,
+        replication: &Arc<Mutex<Replication>>,
+    ) -> String {
+        let Ok(version) = version.parse() else {
+            return "Invalid ring version\n".to_string();
+        };
+        let template = self.ring().await;
+        let members = members.split(',').map(str::to_string).collect();
+        let ring = Ring::new(version, members, template.vnodes, template.replicas);
+        self.adopt(ring, storage, replication).await;
+        "OK\n".to_string()
+    }
+
+    /// Asks a member of an existing ring to add this node, retrying until it
+    /// answers, and adopts the ring it replies with in case the broadcast
+    /// reached this node before it was listening.
+    pub async fn join(
+        self: &Arc<Self>,
+        through: &str,
+        storage: &Arc<Mutex<Box<dyn Storage>>>,
+        replication: &Arc<Mutex<Replication>>,
+    ) {
+        loop {
+            match request(through, &format!("CLUSTER JOIN {}", self.address)).await {
+                Ok(response) => {
+                    let ring = response.strip_prefix("Ring version ").and_then(|ring| ring.split_once(": "));
+                    if let Some((version, members)) = ring {
+                        log::info!("Joined the ring through {}: {}", through, response);
+                        self.receive_ring(version, &members.replace(", ", ","), storage, replication).await;
+                        return;
+                    }
+                    log::warn!("Cannot join the ring through {}: {}", through, response);
+                }
+                Err(e) => log::warn!("Cannot join the ring through {}: {}", through, e),
+            }
+            tokio::time::sleep(JOIN_RETRY).await;
+        }
+    }
+
+    /// Sends every key held here to the owners the new ring gives it that
+    /// the old one did not, and drops the keys this node no longer owns once
+    /// they are handed off, unless they changed meanwhile. A hand-off names
+    /// the new ring's version, and an owner that has taken a write of the
+    /// key under that ring or a later one keeps its own value.
+    async fn rebalance(
+        &self,
+        old: &Ring,
+        new: &Ring,
+        storage: &Arc<Mutex<Box<dyn Storage>>>,
+        replication: &Arc<Mutex<Replication>>,
+    ) -> Result<(), BoxError> {
+        let pairs = storage.lock().await.list_all()?;
+        let (mut moved, mut dropped) = (0, 0);
+        for (key, value) in pairs {
+            let (before, after) = (old.owners(&key), new.owners(&key));
+            let mut handed_off = true;
+            for owner in after.iter().filter(|owner| !before.contains(owner) && **owner != self.address) {
+                let command = self.replica(&format!("HANDOFF {} {} {}", new.version(), key, value));
+                match request(owner, &command).await {
+                    Ok(response) if response == "OK" => moved += 1,
+                    Ok(response) if response == "Superseded" => {}
+                    outcome => {
+                        log::warn!("Cannot hand {} to {}: {:?}", key, owner, outcome.map_err(|e| e.to_string()));
+                        handed_off = false;
+                    }
+                }
+            }
+            if handed_off && !after.contains(&self.address) {
+                let _writes = self.lock_writes().await;
+                if storage.lock().await.get(&key)?.as_deref() == Some(value.as_str()) {
+                    replication::write(replication, Operation::Delete { key }).await?;
+                    dropped += 1;
+                }
+            }
+        }
+        log::info!("Rebalanced to ring version {}: sent {} copies, dropped {} keys", new.version(), moved, dropped);
+        Ok(())
+    }
+}
+
+/// Sends one command to `node` and returns its response, without the empty
+/// line that ends it.
+pub async fn request(node: &str, command: &str) -> Result<String, BoxError> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(node).await?;
+        stream.write_all(format!("{}\n", command).as_bytes()).await?;
+        let mut reader = BufReader::new(&mut stream);
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err::<_, BoxError>("connection closed".into());
+            }
+            if line.trim_end().is_empty() {
+                return Ok(response.trim_end().to_string());
+            }
+            response.push_str(&line);
+        }
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange).await?
+}
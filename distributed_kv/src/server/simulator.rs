@@ -0,0 +1,402 @@
+//! A deterministic in-process network for exercising Raft: every node runs
+//! in one thread, time advances in rounds, and a seeded generator decides
+//! message order and loss, so any failure replays exactly from its seed.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::raft::{Change, Entry, Message, NodeId, RaftNode, Role};
+use super::storage::Operation;
+
+pub struct Simulator {
+    nodes: BTreeMap<NodeId, RaftNode>,
+    /// Messages sent during the last round, delivered during the next.
+    in_flight: Vec<(NodeId, NodeId, Message)>,
+    rng: u64,
+    /// Chance in a thousand that a message is lost.
+    loss: u64,
+    /// While partitioned, nodes only reach nodes in their own group.
+    groups: Option<Vec<BTreeSet<NodeId>>>,
+    crashed: BTreeSet<NodeId>,
+    /// Every change to each node's term, vote and log, as saved to disk.
+    saved: BTreeMap<NodeId, Vec<Change>>,
+    /// Every entry each node has applied, in log order: its state machine,
+    /// which survives restarts as storage on disk would.
+    applied: BTreeMap<NodeId, Vec<Entry>>,
+    /// Applied entries a node keeps in its log before compacting it.
+    compact_after: u64,
+    /// The leader seen in each term.
+    leaders: BTreeMap<u64, NodeId>,
+}
+
+impl Simulator {
+    pub fn new(size: usize, seed: u64) -> Self {
+        let ids: Vec<NodeId> = (0..size).map(|i| format!("node{}", i)).collect();
+        let nodes = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let peers = ids.iter().filter(|peer| *peer != id).cloned().collect();
+                (id.clone(), RaftNode::new(id.clone(), peers, seed.wrapping_mul(31).wrapping_add(i as u64 + 1)))
+            })
+            .collect();
+        Simulator {
+            nodes,
+            in_flight: Vec::new(),
+            rng: seed | 1,
+            loss: 0,
+            groups: None,
+            crashed: BTreeSet::new(),
+            saved: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            applied: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            compact_after: u64::MAX,
+            leaders: BTreeMap::new(),
+        }
+    }
+
+    pub fn id(&self, node: usize) -> NodeId {
+        format!("node{}", node)
+    }
+
+    pub fn set_loss(&mut self, permille: u64) {
+        self.loss = permille;
+    }
+
+    /// Makes every node compact its log once it has applied `entries`
+    /// entries past its snapshot.
+    pub fn set_compaction(&mut self, entries: u64) {
+        self.compact_after = entries;
+    }
+
+    /// Splits the network into `groups`; nodes left out are isolated.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.groups = Some(groups.iter().map(|group| group.iter().cloned().collect()).collect());
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = None;
+    }
+
+    /// Stops a node: it neither ticks nor receives until it recovers, but
+    /// keeps its state, as a restarted node with durable storage would.
+    pub fn crash(&mut self, id: &str) {
+        self.crashed.insert(id.to_string());
+    }
+
+    pub fn recover(&mut self, id: &str) {
+        self.crashed.remove(id);
+    }
+
+    /// Replaces a node with one rebuilt from the state it saved, as a
+    /// process restarting from its data directory. It carries on applying
+    /// from where it stopped.
+    pub fn restart(&mut self, id: &str) {
+        let peers = self.nodes.keys().filter(|peer| *peer != id).cloned().collect();
+        let seed = self.random();
+        let node = RaftNode::restore(id.to_string(), peers, seed, self.saved[id].clone());
+        self.nodes.insert(id.to_string(), node);
+        self.crashed.remove(id);
+    }
+
+    /// The live leader of the highest term, if any.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.live()
+            .filter(|node| node.role() == Role::Leader)
+            .max_by_key(|node| node.term())
+            .and_then(|node| node.leader().map(str::to_string))
+    }
+
+    pub fn propose(&mut self, id: &str, operation: Operation) -> Option<(u64, u64)> {
+        let proposed = self.nodes.get_mut(id).unwrap().propose(operation).ok();
+        self.collect();
+        proposed
+    }
+
+    /// The operations `id` has applied, in order.
+    pub fn applied(&self, id: &str) -> Vec<Operation> {
+        self.applied[id].iter().filter_map(|entry| entry.operation.clone()).collect()
+    }
+
+    pub fn term(&self, id: &str) -> u64 {
+        self.nodes[id].term()
+    }
+
+    pub fn snapshot_index(&self, id: &str) -> u64 {
+        self.nodes[id].snapshot_index()
+    }
+
+    pub fn run(&mut self, rounds: usize) {
+        for _ in 0..rounds {
+            self.round();
+        }
+    }
+
+    /// Runs until `condition` holds, failing after `rounds` rounds.
+    pub fn run_until(&mut self, rounds: usize, mut condition: impl FnMut(&Simulator) -> bool) {
+        for _ in 0..rounds {
+            if condition(self) {
+                return;
+            }
+            self.round();
+        }
+        assert!(condition(self), "condition not reached within {} rounds", rounds);
+    }
+
+    fn round(&mut self) {
+        let mut messages = std::mem::take(&mut self.in_flight);
+        for i in (1..messages.len()).rev() {
+            let j = (self.random() % (i as u64 + 1)) as usize;
+            messages.swap(i, j);
+        }
+        for (from, to, message) in messages {
+            let lost = self.random() % 1000 < self.loss;
+            if !lost && !self.crashed.contains(&to) && self.connected(&from, &to) {
+                self.nodes.get_mut(&to).unwrap().step(&from, message);
+            }
+        }
+        for (id, node) in &mut self.nodes {
+            if !self.crashed.contains(id) {
+                node.tick();
+            }
+        }
+        self.collect();
+    }
+
+    /// Queues what every node has sent, records what it has applied and
+    /// saves that, compacting logs as due, and checks the Raft safety
+    /// properties.
+    fn collect(&mut self) {
+        for (id, node) in &mut self.nodes {
+            let applied = self.applied.get_mut(id).unwrap();
+            if let Some(data) = node.take_snapshot() {
+                *applied = serde_json::from_value(data).unwrap();
+            }
+            let saved = self.saved.get_mut(id).unwrap();
+            saved.extend(node.take_changes());
+            let mut messages = node.take_messages();
+            if self.crashed.contains(id) {
+                continue;
+            }
+            for (_, message) in &mut messages {
+                if let Message::InstallSnapshot { data, .. } = message {
+                    *data = serde_json::to_value(&*applied).unwrap();
+                }
+            }
+            self.in_flight.extend(messages.into_iter().map(|(to, message)| (id.clone(), to, message)));
+            let committed = node.take_committed();
+            if !committed.is_empty() {
+                applied.extend(committed.into_iter().map(|(_, entry)| entry));
+                saved.push(Change::Applied { index: node.last_applied() });
+            }
+            if node.last_applied() - node.snapshot_index() >= self.compact_after {
+                *saved = node.compact();
+            }
+            if node.role() == Role::Leader {
+                let leader = self.leaders.entry(node.term()).or_insert_with(|| id.clone());
+                assert_eq!(leader, id, "two leaders in term {}", node.term());
+            }
+        }
+        // Every node applies a prefix of the same sequence.
+        let longest = self.applied.values().max_by_key(|entries| entries.len()).unwrap();
+        for (id, entries) in &self.applied {
+            assert_eq!(entries[..], longest[..entries.len()], "{} applied a diverging log", id);
+        }
+    }
+
+    fn connected(&self, from: &str, to: &str) -> bool {
+        match &self.groups {
+            None => true,
+            Some(groups) => groups.iter().any(|group| group.contains(from) && group.contains(to)),
+        }
+    }
+
+    fn live(&self) -> impl Iterator<Item = &RaftNode> {
+        self.nodes.iter().filter(|(id, _)| !self.crashed.contains(*id)).map(|(_, node)| node)
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+fn set(key: &str, value: usize) -> Operation {
+    Operation::Set { key: key.to_string(), value: value.to_string() }
+}
+
+fn elect(sim: &mut Simulator) -> NodeId {
+    sim.run_until(500, |sim| sim.leader().is_some());
+    sim.leader().unwrap()
+}
+
+#[test]
+fn elects_one_leader_and_replicates_to_every_node() {
+    let mut sim = Simulator::new(3, 7);
+    let leader = elect(&mut sim);
+    for i in 0..3 {
+        sim.propose(&leader, set("key", i)).unwrap();
+    }
+    let expected = vec![set("key", 0), set("key", 1), set("key", 2)];
+    sim.run_until(100, |sim| (0..3).all(|i| sim.applied(&sim.id(i)) == expected));
+
+    let follower = (0..3).map(|i| sim.id(i)).find(|id| *id != leader).unwrap();
+    assert!(sim.propose(&follower, set("key", 3)).is_none());
+}
+
+#[test]
+fn majority_side_of_a_partition_elects_a_new_leader() {
+    let mut sim = Simulator::new(5, 11);
+    let old = elect(&mut sim);
+    let ids: Vec<NodeId> = (0..5).map(|i| sim.id(i)).collect();
+    let others: Vec<NodeId> = ids.iter().filter(|id| **id != old).cloned().collect();
+    sim.partition(&[&[old.clone(), others[0].clone()], &others[1..]]);
+
+    // The old leader keeps its role for a while but can no longer commit.
+    sim.propose(&old, set("lost", 0)).unwrap();
+    sim.run_until(500, |sim| sim.leader().is_some_and(|leader| leader != old));
+    let new = sim.leader().unwrap();
+    assert!(sim.term(&new) > sim.term(&old));
+    sim.propose(&new, set("kept", 1)).unwrap();
+    sim.run_until(100, |sim| others[1..].iter().all(|id| sim.applied(id) == [set("kept", 1)]));
+    assert!(sim.applied(&old).is_empty());
+
+    // Once healed, the old leader discards its uncommitted write.
+    sim.heal();
+    sim.run_until(500, |sim| ids.iter().all(|id| sim.applied(id) == [set("kept", 1)]));
+}
+
+#[test]
+fn writes_commit_while_a_minority_is_down() {
+    let mut sim = Simulator::new(5, 3);
+    let first = elect(&mut sim);
+    let ids: Vec<NodeId> = (0..5).map(|i| sim.id(i)).collect();
+    let others: Vec<NodeId> = ids.iter().filter(|id| **id != first).cloned().collect();
+    sim.crash(&first);
+    sim.crash(&others[0]);
+
+    let leader = elect(&mut sim);
+    sim.propose(&leader, set("a", 1)).unwrap();
+    sim.run_until(100, |sim| others[1..].iter().all(|id| sim.applied(id) == [set("a", 1)]));
+
+    // With a majority down nothing commits, until one node returns.
+    let down = others[1..].iter().find(|id| **id != leader).unwrap().clone();
+    sim.crash(&down);
+    sim.propose(&leader, set("b", 2)).unwrap();
+    sim.run(200);
+    assert_eq!(sim.applied(&leader), [set("a", 1)]);
+    sim.recover(&first);
+    let live: Vec<&NodeId> = ids.iter().filter(|id| **id != down && **id != others[0]).collect();
+    sim.run_until(500, |sim| live.iter().all(|id| sim.applied(id) == [set("a", 1), set("b", 2)]));
+}
+
+#[test]
+fn survives_message_loss_random_partitions_and_restarts() {
+    for seed in 1..=20 {
+        let mut sim = Simulator::new(5, seed);
+        sim.set_loss(200);
+        sim.set_compaction(10);
+        let ids: Vec<NodeId> = (0..5).map(|i| sim.id(i)).collect();
+        let mut writes = 0;
+        for round in 0..2_000 {
+            if round % 100 == 0 {
+                if sim.random().is_multiple_of(2) {
+                    sim.heal();
+                } else {
+                    // A random node ends up isolated, alone or in pairs.
+                    let mut shuffled = ids.clone();
+                    for i in (1..shuffled.len()).rev() {
+                        let j = (sim.random() % (i as u64 + 1)) as usize;
+                        shuffled.swap(i, j);
+                    }
+                    let split = 1 + (sim.random() % 2) as usize;
+                    sim.partition(&[&shuffled[..split], &shuffled[split..]]);
+                }
+            }
+            if round % 100 == 50 {
+                let restarted = ids[(sim.random() % 5) as usize].clone();
+                sim.restart(&restarted);
+            }
+            if round % 10 == 0 {
+                if let Some(leader) = sim.leader() {
+                    if sim.propose(&leader, set("key", writes)).is_some() {
+                        writes += 1;
+                    }
+                }
+            }
+            sim.run(1);
+        }
+
+        // Healed and lossless, every node converges on the same writes,
+        // including one made after the chaos.
+        sim.heal();
+        sim.set_loss(0);
+        // A leader cut off during the chaos may linger until it hears of the
+        // newer term, so wait for one the whole cluster follows.
+        sim.run_until(500, |sim| sim.leader().is_some_and(|leader| ids.iter().all(|id| sim.term(id) == sim.term(&leader))));
+        let leader = sim.leader().unwrap();
+        sim.propose(&leader, set("final", seed as usize)).unwrap();
+        sim.run_until(500, |sim| ids.iter().all(|id| sim.applied(id).last() == Some(&set("final", seed as usize))));
+        let applied = sim.applied(&ids[0]);
+        assert!(applied.len() > 1, "seed {}: nothing committed during the chaos", seed);
+        assert!(ids.iter().all(|id| sim.applied(id) == applied));
+    }
+}
+
+#[test]
+fn restarted_nodes_remember_their_votes() {
+    let peers = vec!["b".to_string(), "c".to_string()];
+    let mut node = RaftNode::new("a".to_string(), peers.clone(), 1);
+    let request = Message::RequestVote { term: 1, last_log_index: 0, last_log_term: 0 };
+    node.step("b", request.clone());
+    assert_eq!(node.take_messages(), [("b".to_string(), Message::Vote { term: 1, granted: true })]);
+
+    let mut node = RaftNode::restore("a".to_string(), peers, 2, node.take_changes());
+    node.step("c", request);
+    assert_eq!(node.take_messages(), [("c".to_string(), Message::Vote { term: 1, granted: false })]);
+}
+
+#[test]
+fn committed_writes_survive_restarting_every_node() {
+    let mut sim = Simulator::new(3, 11);
+    let ids: Vec<NodeId> = (0..3).map(|i| sim.id(i)).collect();
+    let leader = elect(&mut sim);
+    sim.propose(&leader, set("a", 1)).unwrap();
+    sim.run_until(100, |sim| ids.iter().all(|id| sim.applied(id) == [set("a", 1)]));
+
+    for id in &ids {
+        sim.restart(id);
+    }
+    let leader = elect(&mut sim);
+    sim.propose(&leader, set("b", 2)).unwrap();
+    sim.run_until(500, |sim| ids.iter().all(|id| sim.applied(id) == [set("a", 1), set("b", 2)]));
+}
+
+#[test]
+fn lagging_followers_catch_up_from_a_snapshot() {
+    let mut sim = Simulator::new(3, 5);
+    sim.set_compaction(5);
+    let ids: Vec<NodeId> = (0..3).map(|i| sim.id(i)).collect();
+    let leader = elect(&mut sim);
+    let lagging = ids.iter().find(|id| **id != leader).unwrap().clone();
+    sim.crash(&lagging);
+    let mut expected: Vec<Operation> = (0..20).map(|i| set("key", i)).collect();
+    for operation in &expected {
+        sim.propose(&leader, operation.clone()).unwrap();
+    }
+    sim.run_until(100, |sim| sim.applied(&leader) == expected);
+    assert!(sim.snapshot_index(&leader) > expected.len() as u64);
+
+    // The entries it lacks are gone from the leader's log.
+    sim.recover(&lagging);
+    sim.run_until(500, |sim| sim.applied(&lagging) == expected);
+
+    // Restarted from their snapshots, nodes apply nothing twice.
+    for id in &ids {
+        sim.restart(id);
+    }
+    let leader = elect(&mut sim);
+    expected.push(set("key", 20));
+    sim.propose(&leader, set("key", 20)).unwrap();
+    sim.run_until(500, |sim| ids.iter().all(|id| sim.applied(id) == expected));
+}
@@ -0,0 +1,52 @@
+//! Bloom filters over the keys of an SSTable, so lookups can skip tables
+//! that cannot hold a key without reading them.
+
+/// Bits per key; with the matching number of probes this gives about a 1%
+/// false positive rate.
+const BITS_PER_KEY: usize = 10;
+const PROBES: u32 = 7;
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Builds a filter over keys given by their `hash_key` hashes.
+    pub fn build(hashes: &[u64]) -> Self {
+        let bytes = (hashes.len() * BITS_PER_KEY).div_ceil(8).max(1);
+        let mut filter = BloomFilter { bits: vec![0; bytes] };
+        for &hash in hashes {
+            for bit in filter.probes(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub fn from_bytes(bits: Vec<u8>) -> Self {
+        BloomFilter { bits }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// False means the key is certainly absent; true means it may be there.
+    pub fn may_contain(&self, key: &str) -> bool {
+        !self.bits.is_empty() && self.probes(hash_key(key)).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bit positions for a key, by double hashing: the upper and lower halves
+    /// of its hash stand in for two independent hashes.
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        let step = (hash >> 32) | 1;
+        (0..u64::from(PROBES)).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
+
+/// FNV-1a, which unlike the standard library's hasher is stable across
+/// builds, as filters written to disk need.
+pub fn hash_key(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3))
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::super::wal::Wal;
+use super::{Operation, Options, Storage};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const WAL_FILE: &str = "wal.log";
+
+/// On disk, the data is the last snapshot plus the write-ahead log of every
+/// write since. Replaying a log over a snapshot that already holds its
+/// writes gives the same result, so a crash between writing a snapshot and
+/// emptying the log is harmless.
+struct Durability {
+    dir: PathBuf,
+    wal: Wal,
+    options: Options,
+}
+
+pub struct HashMapStorage {
+    data: HashMap<String, String>,
+    durability: Option<Durability>,
+}
+
+impl Default for HashMapStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashMapStorage {
+    /// Storage kept only in memory.
+    pub fn new() -> Self {
+        HashMapStorage {
+            data: HashMap::new(),
+            durability: None,
+        }
+    }
+
+    /// Storage persisted in `dir`, recovered from the last snapshot and the
+    /// log written after it.
+    pub fn open(dir: &Path, options: Options) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let data = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let (wal, operations) = Wal::open(&dir.join(WAL_FILE), options.sync)?;
+        let mut storage = HashMapStorage { data, durability: None };
+        for operation in &operations {
+            storage.update(operation);
+        }
+        log::info!("Recovered {} keys from {}, replaying {} log records", storage.data.len(), dir.display(), operations.len());
+        storage.durability = Some(Durability { dir: dir.to_path_buf(), wal, options });
+        Ok(storage)
+    }
+
+    /// Writes the data to a new snapshot and empties the log.
+    pub fn snapshot(&mut self) -> io::Result<()> {
+        let Some(durability) = &mut self.durability else {
+            return Ok(());
+        };
+        let path = durability.dir.join(SNAPSHOT_FILE);
+        let temporary = path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        serde_json::to_writer(&mut writer, &self.data)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&temporary, &path)?;
+        File::open(&durability.dir)?.sync_all()?;
+        durability.wal.reset()?;
+        log::debug!("Snapshotted {} keys to {}", self.data.len(), path.display());
+        Ok(())
+    }
+
+    /// Logs `operation` ahead of applying it, snapshotting once the log has
+    /// grown long enough.
+    fn write(&mut self, operation: Operation) -> io::Result<()> {
+        let Some(durability) = &mut self.durability else {
+            self.update(&operation);
+            return Ok(());
+        };
+        durability.wal.append(&operation)?;
+        let due = durability.wal.records() >= durability.options.snapshot_every;
+        self.update(&operation);
+        if due {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, operation: &Operation) {
+        match operation {
+            Operation::Set { key, value } => {
+                self.data.insert(key.clone(), value.clone());
+            }
+            Operation::Delete { key } => {
+                self.data.remove(key);
+            }
+        }
+    }
+}
+
+impl Storage for HashMapStorage {
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write(Operation::Set { key: key.to_string(), value: value.to_string() })
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.write(Operation::Delete { key: key.to_string() })
+    }
+
+    fn list_all(&self) -> io::Result<Vec<(String, String)>> {
+        Ok(self.data.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match &mut self.durability {
+            Some(durability) => durability.wal.sync(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! A log-structured merge tree. Writes go to the write-ahead log and an
+//! in-memory memtable; a full memtable is written out as an SSTable. Tables
+//! are compacted size-tiered: a flushed table starts in tier 0, and once a
+//! tier holds `TIER_FANIN` tables they are merged into one table of the next
+//! tier. The manifest lists the live tables newest first, which is also
+//! lowest tier first, and is replaced atomically after every flush or merge.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+
+use super::super::wal::Wal;
+use super::sstable::{Entry, SsTable, SsTableWriter};
+use super::{Operation, Options, Storage};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const WAL_FILE: &str = "wal.log";
+/// Tables of one tier that are merged into a table of the next tier.
+const TIER_FANIN: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TableInfo {
+    file: String,
+    tier: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_table: u64,
+    /// Newest first.
+    tables: Vec<TableInfo>,
+}
+
+pub struct LsmStorage {
+    dir: PathBuf,
+    options: Options,
+    wal: Wal,
+    /// Writes not yet flushed; `None` is a tombstone.
+    memtable: BTreeMap<String, Option<String>>,
+    memtable_bytes: usize,
+    /// Newest first, as in the manifest.
+    tables: Vec<(TableInfo, SsTable)>,
+    next_table: u64,
+}
+
+impl LsmStorage {
+    /// Opens the tree in `dir`, replaying the write-ahead log into the
+    /// memtable and removing files a crash left behind mid-flush or
+    /// mid-merge.
+    pub fn open(dir: &Path, options: Options) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let manifest: Manifest = match File::open(dir.join(MANIFEST_FILE)) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e),
+        };
+        let mut tables = Vec::new();
+        for info in manifest.tables {
+            let table = SsTable::open(&dir.join(&info.file))?;
+            tables.push((info, table));
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            let live = tables.iter().any(|(_, table)| table.path() == path);
+            if matches!(extension, Some("sst" | "tmp")) && !live {
+                log::warn!("Removing leftover file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let (wal, operations) = Wal::open(&dir.join(WAL_FILE), options.sync)?;
+        let mut storage = LsmStorage {
+            dir: dir.to_path_buf(),
+            options,
+            wal,
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            tables,
+            next_table: manifest.next_table,
+        };
+        log::info!("Opened {} SSTables in {}, replaying {} log records", storage.tables.len(), dir.display(), operations.len());
+        for operation in operations {
+            storage.update(operation);
+        }
+        Ok(storage)
+    }
+
+    /// Writes the memtable out as a tier 0 table, empties the log and
+    /// compacts any tier that has filled up.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let (info, mut writer) = self.create_table(0)?;
+        for (key, value) in &self.memtable {
+            writer.add(key, value.as_deref())?;
+        }
+        let table = writer.finish()?;
+        log::debug!("Flushed {} memtable entries to {}", self.memtable.len(), info.file);
+        self.tables.insert(0, (info, table));
+        self.save_manifest()?;
+        self.wal.reset()?;
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        self.compact()
+    }
+
+    /// SSTables on disk, newest first, as `(tier, bytes)`.
+    pub fn tables(&self) -> Vec<(u32, u64)> {
+        self.tables.iter().map(|(info, table)| (info.tier, table.size())).collect()
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        loop {
+            // Each tier's tables are adjacent; find the first full one.
+            let mut start = 0;
+            let (tier, end) = loop {
+                let Some((info, _)) = self.tables.get(start) else {
+                    return Ok(());
+                };
+                let tier = info.tier;
+                let end = start + self.tables[start..].iter().take_while(|(info, _)| info.tier == tier).count();
+                if end - start >= TIER_FANIN {
+                    break (tier, end);
+                }
+                start = end;
+            };
+            // Nothing older can hold a deleted key, so its tombstone can go.
+            let oldest = end == self.tables.len();
+
+            let (info, mut writer) = self.create_table(tier + 1)?;
+            let sources = self.tables[start..end].iter().map(|(_, table)| boxed(table.iter())).collect();
+            for entry in Merge::new(sources) {
+                let (key, value) = entry?;
+                if value.is_some() || !oldest {
+                    writer.add(&key, value.as_deref())?;
+                }
+            }
+            // Tombstones alone leave nothing to keep.
+            let merged = if writer.is_empty() {
+                writer.abandon()?;
+                None
+            } else {
+                Some((info, writer.finish()?))
+            };
+            log::debug!("Merged {} tier {} tables", end - start, tier);
+
+            let replaced: Vec<_> = self.tables.splice(start..end, merged).collect();
+            self.save_manifest()?;
+            for (_, table) in replaced {
+                fs::remove_file(table.path())?;
+            }
+        }
+    }
+
+    fn create_table(&mut self, tier: u32) -> io::Result<(TableInfo, SsTableWriter)> {
+        let file = format!("{:08}.sst", self.next_table);
+        self.next_table += 1;
+        let writer = SsTableWriter::create(&self.dir.join(&file))?;
+        Ok((TableInfo { file, tier }, writer))
+    }
+
+    fn save_manifest(&self) -> io::Result<()> {
+        let manifest = Manifest {
+            next_table: self.next_table,
+            tables: self.tables.iter().map(|(info, _)| info.clone()).collect(),
+        };
+        let path = self.dir.join(MANIFEST_FILE);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec(&manifest)?)?;
+        File::open(&temporary)?.sync_all()?;
+        fs::rename(&temporary, &path)?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    fn write(&mut self, operation: Operation) -> io::Result<()> {
+        self.wal.append(&operation)?;
+        self.update(operation);
+        if self.memtable_bytes >= self.options.memtable_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, operation: Operation) {
+        let (key, value) = match operation {
+            Operation::Set { key, value } => (key, Some(value)),
+            Operation::Delete { key } => (key, None),
+        };
+        let size = |key: &str, value: &Option<String>| key.len() + value.as_ref().map_or(0, String::len);
+        self.memtable_bytes += size(&key, &value);
+        if let Some(old) = self.memtable.get(&key) {
+            self.memtable_bytes -= size(&key, old);
+        }
+        self.memtable.insert(key, value);
+    }
+}
+
+impl Storage for LsmStorage {
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for (_, table) in &self.tables {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write(Operation::Set { key: key.to_string(), value: value.to_string() })
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.write(Operation::Delete { key: key.to_string() })
+    }
+
+    fn list_all(&self) -> io::Result<Vec<(String, String)>> {
+        let memtable = self.memtable.iter().map(|(key, value)| Ok((key.clone(), value.clone())));
+        let mut sources = vec![boxed(memtable)];
+        sources.extend(self.tables.iter().map(|(_, table)| boxed(table.iter())));
+        let mut pairs = Vec::new();
+        for entry in Merge::new(sources) {
+            if let (key, Some(value)) = entry? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.wal.sync()
+    }
+}
+
+type Source<'a> = Box<dyn Iterator<Item = io::Result<Entry>> + 'a>;
+
+fn boxed<'a>(source: impl Iterator<Item = io::Result<Entry>> + 'a) -> Source<'a> {
+    Box::new(source)
+}
+
+/// Merges sources sorted by key into one sorted stream. Sources are given
+/// newest first, and of a key found in several only the newest entry is
+/// kept.
+struct Merge<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Self {
+        Merge { sources: sources.into_iter().map(Iterator::peekable).collect() }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) if smallest.as_ref().is_none_or(|(_, smallest)| key < smallest) => {
+                    smallest = Some((i, key.clone()));
+                }
+                _ => {}
+            }
+        }
+        // Ties go to the earliest, newest source; older copies are skipped.
+        let (newest, key) = smallest?;
+        for source in &mut self.sources[newest + 1..] {
+            if matches!(source.peek(), Some(Ok((other, _))) if *other == key) {
+                source.next();
+            }
+        }
+        self.sources[newest].next()
+    }
+}
@@ -0,0 +1,96 @@
+mod bloom;
+mod hashmap;
+mod lsm;
+mod sstable;
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+pub use hashmap::HashMapStorage;
+pub use lsm::LsmStorage;
+
+/// A key-value store the server can keep its data in.
+pub trait Storage: Send {
+    fn get(&self, key: &str) -> io::Result<Option<String>>;
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()>;
+
+    fn delete(&mut self, key: &str) -> io::Result<()>;
+
+    /// Every pair, in no particular order.
+    fn list_all(&self) -> io::Result<Vec<(String, String)>>;
+
+    /// Forces logged writes to disk, for the periodic sync policy and before
+    /// Raft records them as applied.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A write, as proposed by clients, committed through the Raft log and
+/// recorded in the write-ahead log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+impl Operation {
+    pub fn apply(&self, storage: &mut dyn Storage) -> io::Result<()> {
+        match self {
+            Operation::Set { key, value } => storage.set(key, value),
+            Operation::Delete { key } => storage.delete(key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Every key in memory, persisted as snapshots plus a write-ahead log.
+    Hashmap,
+    /// A log-structured merge tree on disk, for data larger than memory.
+    Lsm,
+}
+
+/// When appended log records are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SyncPolicy {
+    /// After every write, before it is acknowledged.
+    Always,
+    /// Once a second; a power failure can lose the last second of writes.
+    Periodic,
+    /// Whenever the operating system flushes its buffers.
+    Never,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub sync: SyncPolicy,
+    /// Hashmap: log records after which the data is snapshotted and the log
+    /// emptied.
+    pub snapshot_every: u64,
+    /// LSM: bytes of keys and values the memtable holds before it is written
+    /// out as an SSTable.
+    pub memtable_bytes: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { sync: SyncPolicy::Always, snapshot_every: 10_000, memtable_bytes: 4 << 20 }
+    }
+}
+
+/// Opens the `backend` storage, persisted in `data_dir` if given. The LSM
+/// backend lives on disk and needs one.
+pub fn open(backend: Backend, data_dir: Option<&Path>, options: Options) -> io::Result<Box<dyn Storage>> {
+    Ok(match (backend, data_dir) {
+        (Backend::Hashmap, None) => Box::new(HashMapStorage::new()),
+        (Backend::Hashmap, Some(dir)) => Box::new(HashMapStorage::open(dir, options)?),
+        (Backend::Lsm, Some(dir)) => Box::new(LsmStorage::open(dir, options)?),
+        (Backend::Lsm, None) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the lsm storage backend needs --data-dir"));
+        }
+    })
+}
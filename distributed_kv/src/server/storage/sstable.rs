@@ -0,0 +1,314 @@
+//! Sorted string tables: immutable files of key-ordered entries, where an
+//! entry without a value is a tombstone recording a delete.
+//!
+//! ```text
+//! data blocks   entries of  key len u32 | key | tag u8 | [value len u32 | value]
+//! block index   per block:  last key len u32 | last key | offset u64 | len u32 | crc32 u32
+//! bloom filter  bit array over every key
+//! footer        index offset u64 | bloom offset u64 | entries u64 | magic u64
+//! ```
+//!
+//! Integers are little-endian. A lookup consults the bloom filter, binary
+//! searches the index for the one block that can hold the key, and reads
+//! only that block.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::super::wal::crc32;
+use super::bloom::{self, BloomFilter};
+
+const BLOCK_SIZE: usize = 4096;
+const FOOTER_LEN: u64 = 32;
+const MAGIC: u64 = 0x4b56_5353_5441_4231;
+const TAG_TOMBSTONE: u8 = 0;
+const TAG_VALUE: u8 = 1;
+
+/// A key and its value, or `None` for a tombstone.
+pub type Entry = (String, Option<String>);
+
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u32,
+    checksum: u32,
+}
+
+pub struct SsTable {
+    path: PathBuf,
+    file: File,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    size: u64,
+}
+
+impl SsTable {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(invalid("SSTable too short"));
+        }
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let mut footer = Cursor::new(&footer);
+        let (index_offset, bloom_offset, _entries, magic) = (footer.u64()?, footer.u64()?, footer.u64()?, footer.u64()?);
+        if magic != MAGIC || index_offset > bloom_offset || bloom_offset > size - FOOTER_LEN {
+            return Err(invalid("bad SSTable footer"));
+        }
+
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut bytes = vec![0; (size - FOOTER_LEN - index_offset) as usize];
+        file.read_exact(&mut bytes)?;
+        let (index_bytes, bloom_bytes) = bytes.split_at((bloom_offset - index_offset) as usize);
+        let mut cursor = Cursor::new(index_bytes);
+        let mut index = Vec::new();
+        while !cursor.is_empty() {
+            index.push(BlockHandle {
+                last_key: cursor.str()?.to_string(),
+                offset: cursor.u64()?,
+                len: cursor.u32()?,
+                checksum: cursor.u32()?,
+            });
+        }
+        let bloom = BloomFilter::from_bytes(bloom_bytes.to_vec());
+        Ok(SsTable { path: path.to_path_buf(), file, index, bloom, size })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The entry for `key`: `Some(None)` if this table holds its tombstone,
+    /// `None` if it says nothing about it.
+    pub fn get(&self, key: &str) -> io::Result<Option<Option<String>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self.index.partition_point(|handle| handle.last_key.as_str() < key);
+        let Some(handle) = self.index.get(block) else {
+            return Ok(None);
+        };
+        let bytes = self.read_block(handle)?;
+        let mut cursor = Cursor::new(&bytes);
+        while !cursor.is_empty() {
+            let (k, value) = cursor.entry()?;
+            if k == key {
+                return Ok(Some(value.map(str::to_string)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every entry in key order, reading one block at a time.
+    pub fn iter(&self) -> SsTableIter<'_> {
+        SsTableIter { table: self, block: 0, entries: VecDeque::new() }
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; handle.len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(handle.offset))?;
+        file.read_exact(&mut bytes)?;
+        if crc32(&bytes) != handle.checksum {
+            return Err(invalid(&format!("corrupt block at offset {} of {}", handle.offset, self.path.display())));
+        }
+        Ok(bytes)
+    }
+}
+
+pub struct SsTableIter<'a> {
+    table: &'a SsTable,
+    block: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl Iterator for SsTableIter<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            let handle = self.table.index.get(self.block)?;
+            self.block += 1;
+            let entries = self.table.read_block(handle).and_then(|bytes| {
+                let mut cursor = Cursor::new(&bytes);
+                let mut entries = VecDeque::new();
+                while !cursor.is_empty() {
+                    let (key, value) = cursor.entry()?;
+                    entries.push_back((key.to_string(), value.map(str::to_string)));
+                }
+                Ok(entries)
+            });
+            match entries {
+                Ok(entries) => self.entries = entries,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+/// Writes an SSTable from entries added in strictly increasing key order.
+/// The table only appears at `path` once finished.
+pub struct SsTableWriter {
+    path: PathBuf,
+    temporary: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    last_key: String,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl SsTableWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let temporary = path.with_extension("tmp");
+        Ok(SsTableWriter {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(File::create(&temporary)?),
+            temporary,
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last_key: String::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> io::Result<()> {
+        debug_assert!(self.hashes.is_empty() || key > self.last_key.as_str(), "keys out of order");
+        put_bytes(&mut self.block, key.as_bytes());
+        match value {
+            Some(value) => {
+                self.block.push(TAG_VALUE);
+                put_bytes(&mut self.block, value.as_bytes());
+            }
+            None => self.block.push(TAG_TOMBSTONE),
+        }
+        self.last_key = key.to_string();
+        self.hashes.push(bloom::hash_key(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Gives up on the table, removing what was written of it.
+    pub fn abandon(self) -> io::Result<()> {
+        drop(self.writer);
+        fs::remove_file(&self.temporary)
+    }
+
+    /// Writes the index, filter and footer, syncs the file and moves it into
+    /// place.
+    pub fn finish(mut self) -> io::Result<SsTable> {
+        self.finish_block()?;
+        let index_offset = self.offset;
+        let mut index = Vec::new();
+        for handle in &self.index {
+            put_bytes(&mut index, handle.last_key.as_bytes());
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.len.to_le_bytes());
+            index.extend_from_slice(&handle.checksum.to_le_bytes());
+        }
+        let bloom = BloomFilter::build(&self.hashes);
+        let bloom_offset = index_offset + index.len() as u64;
+
+        self.writer.write_all(&index)?;
+        self.writer.write_all(bloom.as_bytes())?;
+        for field in [index_offset, bloom_offset, self.hashes.len() as u64, MAGIC] {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.temporary, &self.path)?;
+        SsTable::open(&self.path)
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u32,
+            checksum: crc32(&self.block),
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Bounds-checked reads from a byte slice.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Cursor { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(invalid("truncated SSTable record"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> io::Result<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| invalid("key or value is not UTF-8"))
+    }
+
+    fn entry(&mut self) -> io::Result<(&'a str, Option<&'a str>)> {
+        let key = self.str()?;
+        match self.u8()? {
+            TAG_VALUE => Ok((key, Some(self.str()?))),
+            TAG_TOMBSTONE => Ok((key, None)),
+            _ => Err(invalid("bad entry tag")),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Append-only log of storage operations, or of changes to the Raft state.
+//! Each record is the length and the CRC-32 of its payload, both
+//! little-endian `u32`, followed by the payload: one JSON-encoded operation
+//! or change. A crash can only tear or garble the record being written, so
+//! recovery keeps every intact record up to the first bad one and cuts the
+//! file off there.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use super::storage::{Operation, SyncPolicy};
+
+const HEADER_LEN: usize = 8;
+
+pub struct Wal<T = Operation> {
+    path: PathBuf,
+    file: File,
+    sync: SyncPolicy,
+    /// Records appended since the log was last reset.
+    records: u64,
+    record: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Wal<T> {
+    /// Opens or creates the log at `path`, returning it with the contents of
+    /// every intact record. A damaged tail is discarded.
+    pub fn open(path: &Path, sync: SyncPolicy) -> io::Result<(Wal<T>, Vec<T>)> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut contents = Vec::new();
+        let mut offset = 0;
+        while let Some((record, len)) = decode(&bytes[offset..]) {
+            contents.push(record);
+            offset += len;
+        }
+        if offset < bytes.len() {
+            log::warn!("Discarding {} bytes of damaged log tail in {}", bytes.len() - offset, path.display());
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let records = contents.len() as u64;
+        Ok((Wal { path: path.to_path_buf(), file, sync, records, record: PhantomData }, contents))
+    }
+
+    pub fn append(&mut self, record: &T) -> io::Result<()> {
+        self.file.write_all(&encode(record)?)?;
+        self.records += 1;
+        if self.sync == SyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Forces appended records to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Empties the log once a snapshot holds everything in it.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        self.records = 0;
+        Ok(())
+    }
+
+    /// Replaces the log with `records`. The new log is written beside the
+    /// old one and renamed over it, so a crash leaves one or the other.
+    pub fn replace(&mut self, records: &[T]) -> io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        for record in records {
+            file.write_all(&encode(record)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+        self.file = file;
+        self.records = records.len() as u64;
+        Ok(())
+    }
+}
+
+/// The bytes of one record holding `record`.
+fn encode<T: Serialize>(record: &T) -> io::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(record)?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Decodes the record at the start of `bytes`, returning its contents and
+/// length, or `None` if it is incomplete or damaged.
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Option<(T, usize)> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if crc32(payload) != checksum {
+        return None;
+    }
+    let record = serde_json::from_slice(payload).ok()?;
+    Some((record, HEADER_LEN + len))
+}
+
+/// CRC-32 (IEEE), a byte at a time.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
@@ -0,0 +1,85 @@
+//! Helpers shared by the tests that run server processes.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server process, killed when dropped.
+pub struct Server {
+    pub address: String,
+    child: Child,
+}
+
+impl Server {
+    /// Starts a server on `address`, passing it `args`.
+    pub fn start(address: &str, args: &[&str]) -> Server {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_distributed_kv_store"));
+        command.args(["server", "--address", address]).args(args).stdout(Stdio::null()).stderr(Stdio::null());
+        let server = Server { address: address.to_string(), child: command.spawn().unwrap() };
+        eventually(|| TcpStream::connect(&server.address).is_ok());
+        server
+    }
+
+    /// Sends one command and returns the first line of the response.
+    pub fn send(&self, command: &str) -> String {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        stream.write_all(format!("{}\n", command).as_bytes()).unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    /// Sends one command and returns every line of the response.
+    pub fn lines(&self, command: &str) -> Vec<String> {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        stream.write_all(format!("{}\n", command).as_bytes()).unwrap();
+        BufReader::new(stream).lines().map(Result::unwrap).take_while(|line| !line.is_empty()).collect()
+    }
+
+    pub fn get(&self, key: &str) -> String {
+        self.send(&format!("GET {}", key))
+    }
+
+    /// The first `lines` lines of the REPLICATION report.
+    pub fn status(&self, lines: usize) -> Vec<String> {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        stream.write_all(b"REPLICATION\n").unwrap();
+        BufReader::new(stream).lines().take(lines).map(Result::unwrap).collect()
+    }
+}
+
+/// Runs the interactive client against `address`, typing `input`, and
+/// returns what it prints.
+pub fn client(address: &str, input: &str) -> String {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_distributed_kv_store"));
+    command.args(["client", "--server", address]).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null());
+    let mut child = command.spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+pub fn eventually(mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "condition not reached within {:?}", TIMEOUT);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
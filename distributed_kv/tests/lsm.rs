@@ -0,0 +1,122 @@
+use distributed_kv_store::server::storage::{HashMapStorage, LsmStorage, Options, Storage};
+use std::fs;
+use tempfile::TempDir;
+
+/// A memtable this small flushes every few writes.
+fn options() -> Options {
+    Options { memtable_bytes: 256, ..Options::default() }
+}
+
+fn sorted(mut pairs: Vec<(String, String)>) -> Vec<(String, String)> {
+    pairs.sort();
+    pairs
+}
+
+#[test]
+fn matches_a_hashmap_through_flushes_and_compactions() {
+    let dir = TempDir::new().unwrap();
+    let mut lsm = LsmStorage::open(dir.path(), options()).unwrap();
+    let mut model = HashMapStorage::new();
+
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    for step in 0..4_000 {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let key = format!("key{:03}", seed % 300);
+        if seed.is_multiple_of(4) {
+            lsm.delete(&key).unwrap();
+            model.delete(&key).unwrap();
+        } else {
+            let value = format!("value{}", step);
+            lsm.set(&key, &value).unwrap();
+            model.set(&key, &value).unwrap();
+        }
+        if step % 1_000 == 999 {
+            drop(lsm);
+            lsm = LsmStorage::open(dir.path(), options()).unwrap();
+        }
+    }
+
+    for i in 0..300 {
+        let key = format!("key{:03}", i);
+        assert_eq!(lsm.get(&key).unwrap(), model.get(&key).unwrap(), "{}", key);
+    }
+    assert_eq!(sorted(lsm.list_all().unwrap()), sorted(model.list_all().unwrap()));
+
+    // Merges keep few tables, sorted from the lowest tier.
+    let tables = lsm.tables();
+    assert!(tables.iter().any(|(tier, _)| *tier >= 2), "{:?}", tables);
+    assert!(tables.len() < 12, "{:?}", tables);
+    assert!(tables.windows(2).all(|pair| pair[0].0 <= pair[1].0), "{:?}", tables);
+}
+
+#[test]
+fn tombstones_hide_older_values_until_merged_away() {
+    let dir = TempDir::new().unwrap();
+    let options = Options { memtable_bytes: usize::MAX, ..Options::default() };
+    let mut lsm = LsmStorage::open(dir.path(), options).unwrap();
+    for i in 0..100 {
+        lsm.set(&format!("key{:03}", i), "old").unwrap();
+    }
+    lsm.flush().unwrap();
+    for i in (0..100).step_by(2) {
+        lsm.delete(&format!("key{:03}", i)).unwrap();
+    }
+    lsm.flush().unwrap();
+    assert_eq!(lsm.get("key000").unwrap(), None);
+    assert_eq!(lsm.get("key001").unwrap().as_deref(), Some("old"));
+    assert_eq!(lsm.list_all().unwrap().len(), 50);
+
+    // Two more flushes fill tier 0; merging it into the oldest table drops
+    // the tombstones along with the values they hid.
+    lsm.set("key000", "new").unwrap();
+    lsm.flush().unwrap();
+    lsm.delete("key001").unwrap();
+    lsm.flush().unwrap();
+    assert_eq!(lsm.tables().iter().map(|(tier, _)| *tier).collect::<Vec<_>>(), [1]);
+    assert_eq!(lsm.get("key000").unwrap().as_deref(), Some("new"));
+    assert_eq!(lsm.get("key001").unwrap(), None);
+    assert_eq!(lsm.get("key002").unwrap(), None);
+    assert_eq!(lsm.list_all().unwrap().len(), 50);
+}
+
+#[test]
+fn recovers_the_memtable_and_removes_leftover_files() {
+    let dir = TempDir::new().unwrap();
+    let mut lsm = LsmStorage::open(dir.path(), options()).unwrap();
+    for i in 0..50 {
+        lsm.set(&format!("key{}", i), &i.to_string()).unwrap();
+    }
+    let flushed = lsm.tables().len();
+    assert!(flushed > 0);
+    drop(lsm);
+
+    // A table a crash left unfinished is not in the manifest.
+    let leftover = dir.path().join("99999999.tmp");
+    fs::write(&leftover, b"half a table").unwrap();
+    let lsm = LsmStorage::open(dir.path(), options()).unwrap();
+    assert!(!leftover.exists());
+    assert_eq!(lsm.tables().len(), flushed);
+    for i in 0..50 {
+        assert_eq!(lsm.get(&format!("key{}", i)).unwrap(), Some(i.to_string()));
+    }
+}
+
+#[test]
+fn corrupt_blocks_are_reported() {
+    let dir = TempDir::new().unwrap();
+    let mut lsm = LsmStorage::open(dir.path(), options()).unwrap();
+    lsm.set("key", "value").unwrap();
+    lsm.flush().unwrap();
+    drop(lsm);
+
+    let table = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|e| e == "sst"))
+        .unwrap();
+    let mut bytes = fs::read(&table).unwrap();
+    bytes[4] ^= 0xff;
+    fs::write(&table, bytes).unwrap();
+    let lsm = LsmStorage::open(dir.path(), options()).unwrap();
+    assert!(lsm.get("key").is_err());
+}
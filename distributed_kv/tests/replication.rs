@@ -0,0 +1,123 @@
+mod common;
+
+use common::{eventually, free_address, Server};
+use tempfile::TempDir;
+
+/// Starts a cluster of `size` nodes, each listing all the others.
+fn cluster(size: usize) -> Vec<Server> {
+    let addresses: Vec<String> = (0..size).map(|_| free_address()).collect();
+    addresses.iter().map(|address| start(address, &addresses)).collect()
+}
+
+/// Starts the node at `address` of the cluster made of `addresses`.
+fn start(address: &str, addresses: &[String]) -> Server {
+    start_with(address, addresses, &[])
+}
+
+fn start_with(address: &str, addresses: &[String], args: &[&str]) -> Server {
+    let peers: Vec<&str> = addresses.iter().map(String::as_str).filter(|peer| *peer != address).collect();
+    let peers = peers.join(",");
+    let mut args = args.to_vec();
+    args.extend(["--peers", &peers]);
+    Server::start(address, &args)
+}
+
+/// The term and log length from the REPLICATION report.
+fn raft_state(server: &Server) -> (u64, u64) {
+    let status = server.status(2);
+    let term = status[0].rsplit(' ').next().unwrap().parse().unwrap();
+    let log = status[1].strip_prefix("Log length: ").unwrap().split(',').next().unwrap().parse().unwrap();
+    (term, log)
+}
+
+/// Waits for one of `servers` to report itself leader.
+fn leader(servers: &[Server]) -> usize {
+    let mut leader = None;
+    eventually(|| {
+        leader = servers.iter().position(|server| server.status(1)[0].starts_with("Leader in term"));
+        leader.is_some()
+    });
+    leader.unwrap()
+}
+
+#[test]
+fn writes_commit_through_the_leader_and_reach_every_node() {
+    let servers = cluster(3);
+    let leader = &servers[leader(&servers)];
+
+    assert_eq!(leader.send("SET colour blue"), "OK");
+    assert_eq!(leader.send("SET shape round"), "OK");
+    assert_eq!(leader.send("SET colour green"), "OK");
+    assert_eq!(leader.send("DELETE shape"), "OK");
+    for server in &servers {
+        eventually(|| server.get("colour") == "Value: green" && server.get("shape") == "Key not found");
+        if server.address != leader.address {
+            assert_eq!(server.send("SET colour red"), format!("Not the leader; send writes to {}", leader.address));
+        }
+    }
+
+    // Both followers hold the whole log: the leader's opening entry plus
+    // the four writes.
+    eventually(|| {
+        let status = leader.status(4);
+        status[1] == "Log length: 5, committed: 5" && status[2..].iter().all(|line| line.ends_with(": 5"))
+    });
+}
+
+#[test]
+fn cluster_keeps_accepting_writes_when_the_leader_fails() {
+    let mut servers = cluster(3);
+    let first = leader(&servers);
+    for i in 0..20 {
+        assert_eq!(servers[first].send(&format!("SET key{} {}", i, i)), "OK");
+    }
+
+    // The remaining two nodes are still a majority and elect a new leader.
+    let failed = servers.remove(first);
+    let address = failed.address.clone();
+    drop(failed);
+    let second = leader(&servers);
+    assert_eq!(servers[second].send("SET late write"), "OK");
+    for server in &servers {
+        eventually(|| server.get("late") == "Value: write");
+    }
+
+    // The failed node rejoins with no data and is sent the whole log.
+    let mut addresses: Vec<String> = servers.iter().map(|server| server.address.clone()).collect();
+    addresses.push(address.clone());
+    let rejoined = start(&address, &addresses);
+    eventually(|| rejoined.get("late") == "Value: write");
+    for i in 0..20 {
+        assert_eq!(rejoined.get(&format!("key{}", i)), format!("Value: {}", i));
+    }
+}
+
+#[test]
+fn restarted_nodes_resume_from_their_data_directories() {
+    let addresses: Vec<String> = (0..3).map(|_| free_address()).collect();
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let start_all = || -> Vec<Server> {
+        addresses
+            .iter()
+            .zip(&dirs)
+            .map(|(address, dir)| start_with(address, &addresses, &["--data-dir", dir.path().to_str().unwrap()]))
+            .collect()
+    };
+
+    let servers = start_all();
+    let first = leader(&servers);
+    assert_eq!(servers[first].send("SET colour blue"), "OK");
+    let (term, log) = raft_state(&servers[first]);
+    drop(servers);
+
+    // Every node comes back at once, resuming its term and log rather than
+    // starting over.
+    let servers = start_all();
+    let second = leader(&servers);
+    let (new_term, new_log) = raft_state(&servers[second]);
+    assert!(new_term > term && new_log > log, "term {} log {} after term {} log {}", new_term, new_log, term, log);
+    assert_eq!(servers[second].send("SET shape round"), "OK");
+    for server in &servers {
+        eventually(|| server.get("colour") == "Value: blue" && server.get("shape") == "Value: round");
+    }
+}
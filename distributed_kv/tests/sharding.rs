@@ -0,0 +1,236 @@
+mod common;
+
+use common::{client, eventually, free_address, Server};
+use distributed_kv_store::server::sharding::Ring;
+
+const KEYS: usize = 40;
+
+fn ring(version: u64, members: &[&str], vnodes: usize, replicas: usize) -> Ring {
+    Ring::new(version, members.iter().map(|member| member.to_string()).collect(), vnodes, replicas)
+}
+
+/// Starts a sharded node placing each key on two nodes.
+fn start(address: &str, args: &[&str]) -> Server {
+    let mut args = args.to_vec();
+    args.extend(["--replicas", "2", "--vnodes", "16"]);
+    Server::start(address, &args)
+}
+
+/// Starts `size` nodes forming one ring.
+fn ring_of(size: usize) -> Vec<Server> {
+    let addresses: Vec<String> = (0..size).map(|_| free_address()).collect();
+    let shards = addresses.join(",");
+    addresses.iter().map(|address| start(address, &["--shards", &shards])).collect()
+}
+
+/// The servers holding `key` in their own storage, sorted.
+fn holders(servers: &[Server], key: &str) -> Vec<String> {
+    let mut holders: Vec<String> = servers
+        .iter()
+        .filter(|server| server.send(&format!("REPLICA {} GET {}", server.address, key)) != "Key not found")
+        .map(|server| server.address.clone())
+        .collect();
+    holders.sort();
+    holders
+}
+
+/// Whether every key is held by exactly the owners `ring` gives it.
+fn placed(servers: &[Server], ring: &Ring) -> bool {
+    (0..KEYS).all(|i| {
+        let key = format!("key{}", i);
+        let mut owners = ring.owners(&key);
+        owners.sort();
+        holders(servers, &key) == owners
+    })
+}
+
+#[test]
+fn keys_get_distinct_owners_spread_evenly() {
+    let ring = ring(0, &["a:1", "b:2", "c:3"], 64, 2);
+    let mut primaries = [0; 3];
+    for i in 0..3_000 {
+        let owners = ring.owners(&format!("key{}", i));
+        assert_eq!(owners.len(), 2);
+        assert_ne!(owners[0], owners[1]);
+        primaries[["a:1", "b:2", "c:3"].iter().position(|member| *member == owners[0]).unwrap()] += 1;
+    }
+    assert!(primaries.iter().all(|count| (700..1_300).contains(count)), "{:?}", primaries);
+
+    let shares = ring.ownership();
+    assert!((shares.values().map(|(primary, _)| primary).sum::<f64>() - 1.0).abs() < 1e-9);
+    assert!((shares.values().map(|(_, held)| held).sum::<f64>() - 2.0).abs() < 1e-9);
+    assert_eq!(self::ring(0, &["a:1"], 64, 3).owners("key"), ["a:1"]);
+}
+
+#[test]
+fn a_new_member_only_takes_keys() {
+    let before = ring(0, &["a:1", "b:2", "c:3"], 64, 1);
+    let after = before.with_members(["a:1", "b:2", "c:3", "d:4"].iter().map(|m| m.to_string()).collect());
+    assert_eq!(after.version(), 1);
+    let moved = (0..4_000)
+        .map(|i| format!("key{}", i))
+        .filter(|key| before.owners(key) != after.owners(key))
+        .inspect(|key| assert_eq!(after.owners(key), ["d:4"]))
+        .count();
+    assert!((600..1_400).contains(&moved), "{}", moved);
+}
+
+#[test]
+fn any_node_serves_keys_held_by_their_owners() {
+    let servers = ring_of(3);
+    for i in 0..KEYS {
+        assert_eq!(servers[i % 3].send(&format!("SET key{} {}", i, i)), "OK");
+    }
+    assert_eq!(servers[1].send("DELETE key0"), "OK");
+
+    let members: Vec<&str> = servers.iter().map(|server| server.address.as_str()).collect();
+    let ring = ring(0, &members, 16, 2);
+    for i in 1..KEYS {
+        let key = format!("key{}", i);
+        let mut owners = ring.owners(&key);
+        owners.sort();
+        assert_eq!(holders(&servers, &key), owners, "{}", key);
+        for server in &servers {
+            assert_eq!(server.get(&key), format!("Value: {}", i));
+        }
+    }
+    assert!(holders(&servers, "key0").is_empty());
+    assert_eq!(servers[2].get("key0"), "Key not found");
+
+    let report = servers[0].send("CLUSTER");
+    assert_eq!(report, "Ring version 0: 3 nodes, 16 virtual nodes each, replication factor 2");
+
+    // The client shows the whole report, then the answer to the next command.
+    let output = client(&servers[0].address, "CLUSTER\nGET key1\nexit\n");
+    assert_eq!(ring.describe().lines().count(), 1 + 3 + 3 * 16);
+    assert!(output.contains(&format!("> Server response: {}> Server response: Value: 1\n> ", ring.describe())), "{}", output);
+}
+
+#[test]
+fn keys_move_when_nodes_join_and_leave() {
+    let mut servers = ring_of(3);
+    for i in 0..KEYS {
+        assert_eq!(servers[0].send(&format!("SET key{} {}", i, i)), "OK");
+    }
+
+    let address = free_address();
+    servers.push(start(&address, &["--join", &servers[1].address]));
+    let members: Vec<&str> = servers.iter().map(|server| server.address.as_str()).collect();
+    let grown = ring(1, &members, 16, 2);
+    for server in &servers {
+        eventually(|| server.send("CLUSTER").starts_with("Ring version 1: 4 nodes"));
+    }
+    eventually(|| placed(&servers, &grown));
+    assert!((0..KEYS).any(|i| grown.owners(&format!("key{}", i)).contains(&address)));
+
+    let leaving = servers[0].address.clone();
+    assert!(servers[3].send(&format!("CLUSTER LEAVE {}", leaving)).starts_with("Ring version 2: "));
+    let remaining: Vec<&str> = members.into_iter().filter(|member| *member != leaving).collect();
+    let shrunk = ring(2, &remaining, 16, 2);
+    eventually(|| placed(&servers, &shrunk));
+    for i in 0..KEYS {
+        assert_eq!(servers[0].get(&format!("key{}", i)), format!("Value: {}", i));
+    }
+}
+
+#[test]
+fn writes_missing_a_majority_are_undone() {
+    let mut servers = ring_of(3);
+    let addresses: Vec<String> = servers.iter().map(|server| server.address.clone()).collect();
+    let ring = ring(0, &addresses.iter().map(String::as_str).collect::<Vec<_>>(), 16, 2);
+    let owners = ring.owners("key0");
+    let unset = (1..).map(|i| format!("key{}", i)).find(|key| ring.owners(key) == owners).unwrap();
+    assert_eq!(servers[0].send("SET key0 old"), "OK");
+
+    // With one of its two owners down, writes fail and leave the other
+    // owner as it was.
+    servers.retain(|server| server.address != owners[1]);
+    let owner = servers.iter().find(|server| server.address == owners[0]).unwrap();
+    for write in ["SET key0 new", "DELETE key0", &format!("SET {} new", unset)] {
+        assert_eq!(owner.send(write), "Only 1 of 2 replicas acknowledged the write");
+    }
+    assert_eq!(owner.send(&format!("REPLICA {} GET key0", owner.address)), "Value: old");
+    assert_eq!(owner.send(&format!("REPLICA {} GET {}", owner.address, unset)), "Key not found");
+}
+
+#[test]
+fn undoing_a_write_keeps_a_later_one() {
+    let servers = ring_of(1);
+    let replica = |command: &str| servers[0].send(&format!("REPLICA {} {}", servers[0].address, command));
+    assert_eq!(replica("SET key0 later"), "OK");
+
+    assert_eq!(replica("UNDO SET key0 failed old"), "OK");
+    assert_eq!(replica("GET key0"), "Value: later");
+    assert_eq!(replica("UNDO DELETE key0 old"), "OK");
+    assert_eq!(replica("GET key0"), "Value: later");
+
+    assert_eq!(replica("UNDO SET key0 later old"), "OK");
+    assert_eq!(replica("GET key0"), "Value: old");
+    assert_eq!(replica("UNDO SET key0 old"), "OK");
+    assert_eq!(replica("GET key0"), "Key not found");
+}
+
+#[test]
+fn hand_offs_do_not_replace_writes_under_their_ring() {
+    let servers = ring_of(1);
+    let replica = |command: &str| servers[0].send(&format!("REPLICA {} {}", servers[0].address, command));
+    assert_eq!(replica("SET key0 new"), "OK");
+    assert_eq!(replica("SET key1 new"), "OK");
+    assert_eq!(replica("DELETE key1"), "OK");
+
+    assert_eq!(replica("HANDOFF 0 key0 stale"), "Superseded");
+    assert_eq!(replica("GET key0"), "Value: new");
+    assert_eq!(replica("HANDOFF 0 key1 stale"), "Superseded");
+    assert_eq!(replica("GET key1"), "Key not found");
+
+    assert_eq!(replica("HANDOFF 1 key0 moved"), "OK");
+    assert_eq!(replica("GET key0"), "Value: moved");
+    assert_eq!(replica("HANDOFF 0 key2 moved"), "OK");
+    assert_eq!(replica("GET key2"), "Value: moved");
+}
+
+#[test]
+fn replica_commands_are_only_served_for_ring_members() {
+    let servers = ring_of(2);
+    let outsider = free_address();
+    assert_eq!(servers[0].send("SET key0 kept"), "OK");
+
+    let refused = format!("{} is not a member of the ring", outsider);
+    assert_eq!(servers[0].send(&format!("REPLICA {} SET key0 forged", outsider)), refused);
+    assert_eq!(servers[1].get("key0"), "Value: kept");
+}
+
+#[test]
+fn concurrent_joins_through_different_nodes_both_take_effect() {
+    let servers = ring_of(3);
+    let joining: Vec<Server> = (0..2)
+        .map(|_| {
+            let address = free_address();
+            start(&address, &["--shards", &address])
+        })
+        .collect();
+
+    std::thread::scope(|scope| {
+        for (through, node) in [&servers[0], &servers[2]].into_iter().zip(&joining) {
+            scope.spawn(move || assert!(through.send(&format!("CLUSTER JOIN {}", node.address)).starts_with("Ring version ")));
+        }
+    });
+    for server in servers.iter().chain(&joining) {
+        eventually(|| server.send("CLUSTER").starts_with("Ring version 2: 5 nodes"));
+    }
+}
+
+#[test]
+fn rings_of_one_version_settle_the_same_way_whatever_their_order() {
+    let (first, second) = (free_address(), free_address());
+    let servers = [start(&first, &["--shards", &first]), start(&second, &["--shards", &second])];
+    let rings = [format!("RING 1 {},{},a:1", first, second), format!("RING 1 {},{},b:2", first, second)];
+    for (server, order) in servers.iter().zip([[0, 1], [1, 0]]) {
+        for i in order {
+            assert_eq!(server.send(&rings[i]), "OK");
+        }
+    }
+    let reports: Vec<Vec<String>> = servers.iter().map(|server| server.lines("CLUSTER")).collect();
+    assert!(reports[0][0].starts_with("Ring version 1: 3 nodes"));
+    assert_eq!(reports[0], reports[1]);
+}
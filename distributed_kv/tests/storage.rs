@@ -0,0 +1,107 @@
+mod common;
+
+use common::{free_address, Server};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn start(dir: &Path, args: &[&str]) -> Server {
+    let mut args = args.to_vec();
+    args.extend(["--data-dir", dir.to_str().unwrap()]);
+    Server::start(&free_address(), &args)
+}
+
+/// Writes `key0`..`key{count - 1}`, then kills the server without warning.
+fn write_keys(dir: &Path, count: usize) {
+    let server = start(dir, &[]);
+    for i in 0..count {
+        assert_eq!(server.send(&format!("SET key{} {}", i, i)), "OK");
+    }
+}
+
+fn assert_keys(server: &Server, present: usize, absent: usize) {
+    for i in 0..present {
+        assert_eq!(server.get(&format!("key{}", i)), format!("Value: {}", i));
+    }
+    for i in present..present + absent {
+        assert_eq!(server.get(&format!("key{}", i)), "Key not found");
+    }
+}
+
+#[test]
+fn data_survives_a_crash_from_snapshot_and_log() {
+    let dir = TempDir::new().unwrap();
+    let server = start(dir.path(), &["--snapshot-every", "5"]);
+    for i in 0..12 {
+        assert_eq!(server.send(&format!("SET key{} {}", i, i)), "OK");
+    }
+    assert_eq!(server.send("DELETE key11"), "OK");
+    assert_eq!(server.send("SET key0 changed"), "OK");
+    drop(server);
+
+    // Two snapshots were taken; the last four writes are only in the log.
+    assert!(dir.path().join("snapshot.json").exists());
+    let server = start(dir.path(), &[]);
+    assert_eq!(server.get("key0"), "Value: changed");
+    assert_eq!(server.get("key11"), "Key not found");
+    for i in 1..11 {
+        assert_eq!(server.get(&format!("key{}", i)), format!("Value: {}", i));
+    }
+}
+
+#[test]
+fn a_truncated_log_tail_is_discarded() {
+    let dir = TempDir::new().unwrap();
+    write_keys(dir.path(), 5);
+    let wal = dir.path().join("wal.log");
+    let file = OpenOptions::new().write(true).open(&wal).unwrap();
+    file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+    drop(file);
+
+    // The torn record is cut off, so writes after recovery are appended to
+    // intact records and survive the next restart too.
+    let server = start(dir.path(), &[]);
+    assert_keys(&server, 4, 1);
+    assert_eq!(server.send("SET after recovery"), "OK");
+    drop(server);
+    let server = start(dir.path(), &[]);
+    assert_keys(&server, 4, 1);
+    assert_eq!(server.get("after"), "Value: recovery");
+}
+
+#[test]
+fn a_corrupted_log_tail_is_discarded() {
+    let dir = TempDir::new().unwrap();
+    write_keys(dir.path(), 5);
+    let wal = dir.path().join("wal.log");
+    let mut bytes = fs::read(&wal).unwrap();
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0x20;
+    bytes.extend_from_slice(b"garbage");
+    fs::write(&wal, &bytes).unwrap();
+
+    let server = start(dir.path(), &[]);
+    assert_keys(&server, 4, 1);
+    assert_eq!(server.send("SET key4 again"), "OK");
+    drop(server);
+    let server = start(dir.path(), &["--fsync", "never"]);
+    assert_eq!(server.get("key4"), "Value: again");
+    assert_keys(&server, 4, 0);
+}
+
+#[test]
+fn lsm_backend_survives_a_crash() {
+    let dir = TempDir::new().unwrap();
+    let lsm = ["--storage", "lsm", "--memtable-size", "64"];
+    let server = start(dir.path(), &lsm);
+    for i in 0..20 {
+        assert_eq!(server.send(&format!("SET key{} {}", i, i)), "OK");
+    }
+    assert_eq!(server.send("DELETE key19"), "OK");
+    drop(server);
+
+    // Most writes were flushed to SSTables, the last few only logged.
+    let server = start(dir.path(), &lsm);
+    assert_keys(&server, 19, 1);
+    assert!(server.send("LIST").starts_with("Stored key-value pairs"));
+}